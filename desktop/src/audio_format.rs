@@ -0,0 +1,277 @@
+use crate::voice_codec;
+
+/// Sample rate and channel count of interleaved f32 audio
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AudioFormat {
+    pub sample_rate: u32,
+    pub channels: u16,
+}
+
+impl AudioFormat {
+    /// What the call pipeline encodes and decodes
+    pub const CALL: AudioFormat = AudioFormat {
+        sample_rate: voice_codec::SAMPLE_RATE,
+        channels: voice_codec::CHANNELS,
+    };
+}
+
+/// Converts a stream of audio chunks between two formats, e.g. a 44.1 kHz
+/// stereo microphone into the 48 kHz mono call format. Channels are mixed
+/// down before resampling and up after it, so only as many channels as
+/// necessary are resampled.
+pub struct FormatAdapter {
+    from: AudioFormat,
+    to: AudioFormat,
+    /// One per channel being resampled; empty when the rates match
+    resamplers: Vec<Resampler>,
+}
+
+impl FormatAdapter {
+    pub fn new(from: AudioFormat, to: AudioFormat) -> Self {
+        let channels = Self::resampled_channels(from, to);
+        let resamplers = if from.sample_rate == to.sample_rate {
+            Vec::new()
+        } else {
+            (0..channels).map(|_| Resampler::new(from.sample_rate, to.sample_rate)).collect()
+        };
+
+        Self { from, to, resamplers }
+    }
+
+    fn resampled_channels(from: AudioFormat, to: AudioFormat) -> u16 {
+        if from.channels == to.channels { from.channels } else { 1 }
+    }
+
+    /// Convert the next chunk of interleaved samples. State carries over
+    /// between chunks, so any chunk size works.
+    pub fn process(&mut self, input: &[f32]) -> Vec<f32> {
+        let channels = Self::resampled_channels(self.from, self.to) as usize;
+        let mixed = if self.from.channels == self.to.channels {
+            input.to_vec()
+        } else {
+            downmix_to_mono(input, self.from.channels)
+        };
+
+        let resampled = match self.resamplers.as_mut_slice() {
+            [] => mixed,
+            [resampler] => resampler.process(&mixed),
+            resamplers => {
+                let per_channel: Vec<Vec<f32>> = resamplers.iter_mut()
+                    .enumerate()
+                    .map(|(channel, resampler)| {
+                        let samples: Vec<f32> = mixed.iter().skip(channel).step_by(channels).copied().collect();
+                        resampler.process(&samples)
+                    })
+                    .collect();
+                interleave(&per_channel)
+            }
+        };
+
+        if channels == self.to.channels as usize {
+            resampled
+        } else {
+            upmix_from_mono(&resampled, self.to.channels)
+        }
+    }
+}
+
+/// Zero crossings of the sinc kept on each side of an output sample
+const SINC_ZERO_CROSSINGS: usize = 16;
+/// Kernel table entries per input sample
+const KERNEL_RESOLUTION: usize = 64;
+/// Passband as a fraction of the lower Nyquist rate; the filter rolls off
+/// over the rest, so nothing above the output's Nyquist rate folds back
+const CUTOFF: f64 = 0.9;
+
+/// Streaming windowed-sinc resampler for a single channel. The low-pass
+/// sits below both Nyquist rates, so downsampling does not alias and
+/// upsampling does not image.
+struct Resampler {
+    /// Input samples advanced per output sample
+    step: f64,
+    /// Input samples the kernel reaches on each side of an output sample
+    half_width: usize,
+    /// Blackman-windowed sinc from 0 to `half_width`, `KERNEL_RESOLUTION` entries per input sample
+    kernel: Vec<f32>,
+    /// Input that later output samples still reach, starting with silence
+    history: Vec<f32>,
+    /// Input position of the next output sample within `history`
+    position: f64,
+}
+
+impl Resampler {
+    fn new(from_rate: u32, to_rate: u32) -> Self {
+        let step = from_rate as f64 / to_rate as f64;
+        // Relative to the input Nyquist rate, so the kernel widens when downsampling
+        let cutoff = (1.0 / step).min(1.0) * CUTOFF;
+        let half_width = (SINC_ZERO_CROSSINGS as f64 / cutoff).ceil() as usize;
+
+        let kernel = (0..=half_width * KERNEL_RESOLUTION)
+            .map(|i| {
+                let x = i as f64 / KERNEL_RESOLUTION as f64;
+                (cutoff * sinc(cutoff * x) * blackman(x / half_width as f64)) as f32
+            })
+            .collect();
+
+        Self {
+            step,
+            half_width,
+            kernel,
+            history: vec![0.0; half_width],
+            position: half_width as f64,
+        }
+    }
+
+    fn weight(&self, distance: f64) -> f32 {
+        let index = distance.abs() * KERNEL_RESOLUTION as f64;
+        let whole = index as usize;
+        if whole + 1 >= self.kernel.len() {
+            return 0.0;
+        }
+        let fraction = (index - whole as f64) as f32;
+        self.kernel[whole] + (self.kernel[whole + 1] - self.kernel[whole]) * fraction
+    }
+
+    /// Output lags input by `half_width` input samples, which each output
+    /// sample needs past its position
+    fn process(&mut self, input: &[f32]) -> Vec<f32> {
+        self.history.extend_from_slice(input);
+        let mut output = Vec::with_capacity((input.len() as f64 / self.step) as usize + 1);
+
+        while self.position.floor() as usize + self.half_width < self.history.len() {
+            let center = self.position.floor() as usize;
+            let (mut sum, mut total_weight) = (0.0f32, 0.0f32);
+            for index in center + 1 - self.half_width..=center + self.half_width {
+                let weight = self.weight(self.position - index as f64);
+                sum += self.history[index] * weight;
+                total_weight += weight;
+            }
+            // Normalised so a constant signal keeps its level exactly
+            output.push(sum / total_weight);
+            self.position += self.step;
+        }
+
+        let consumed = (self.position.floor() as usize + 1).saturating_sub(self.half_width);
+        self.history.drain(..consumed);
+        self.position -= consumed as f64;
+        output
+    }
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else {
+        (std::f64::consts::PI * x).sin() / (std::f64::consts::PI * x)
+    }
+}
+
+/// Blackman window over -1..=1
+fn blackman(t: f64) -> f64 {
+    let angle = std::f64::consts::PI * t;
+    0.42 + 0.5 * angle.cos() + 0.08 * (2.0 * angle).cos()
+}
+
+pub fn downmix_to_mono(samples: &[f32], channels: u16) -> Vec<f32> {
+    let channels = channels.max(1) as usize;
+    samples.chunks(channels)
+        .map(|frame| frame.iter().sum::<f32>() / frame.len() as f32)
+        .collect()
+}
+
+pub fn upmix_from_mono(samples: &[f32], channels: u16) -> Vec<f32> {
+    samples.iter()
+        .flat_map(|&sample| std::iter::repeat(sample).take(channels.max(1) as usize))
+        .collect()
+}
+
+fn interleave(channels: &[Vec<f32>]) -> Vec<f32> {
+    let frames = channels.iter().map(Vec::len).min().unwrap_or(0);
+    (0..frames)
+        .flat_map(|frame| channels.iter().map(move |channel| channel[frame]))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::TAU;
+
+    const TONE_HZ: f32 = 1_000.0;
+
+    fn sine(format: AudioFormat, frames: usize, frequency: f32) -> Vec<f32> {
+        (0..frames)
+            .flat_map(|i| {
+                let sample = (TAU * frequency * i as f32 / format.sample_rate as f32).sin() * 0.5;
+                std::iter::repeat(sample).take(format.channels as usize)
+            })
+            .collect()
+    }
+
+    /// Output frames the resampler's zero history reaches into at the start
+    /// and holds back at the end
+    fn filter_frames(from: AudioFormat, to: AudioFormat) -> usize {
+        if from.sample_rate == to.sample_rate {
+            return 0;
+        }
+        let half_width = Resampler::new(from.sample_rate, to.sample_rate).half_width;
+        (half_width as f64 * to.sample_rate as f64 / from.sample_rate as f64).ceil() as usize + 1
+    }
+
+    /// Feed 100 ms of `frequency` in 10 ms chunks
+    fn convert(from: AudioFormat, to: AudioFormat, frequency: f32) -> Vec<f32> {
+        let mut adapter = FormatAdapter::new(from, to);
+        let chunk_frames = from.sample_rate as usize / 100;
+        let input = sine(from, chunk_frames * 10, frequency);
+        input.chunks(chunk_frames * from.channels as usize)
+            .flat_map(|chunk| adapter.process(chunk))
+            .collect()
+    }
+
+    /// Check 100 ms of tone comes out as the same tone in the target format
+    fn assert_converts_tone(from: AudioFormat, to: AudioFormat) {
+        let output = convert(from, to, TONE_HZ);
+        let expected = sine(to, to.sample_rate as usize / 10, TONE_HZ);
+        let channels = to.channels as usize;
+        let filter = filter_frames(from, to) * channels;
+
+        // The stream ends the filter's lookahead short of the last input sample
+        let slack = filter + (to.sample_rate / from.sample_rate + 1) as usize * channels;
+        assert_eq!(output.len() % channels, 0);
+        assert!(expected.len().abs_diff(output.len()) <= slack, "{} vs {}", output.len(), expected.len());
+        for (actual, expected) in output.iter().zip(&expected).skip(filter) {
+            assert!((actual - expected).abs() < 0.02, "{} vs {}", actual, expected);
+        }
+    }
+
+    /// Check a tone above the target's Nyquist rate is filtered out rather
+    /// than folded back into the audible band
+    fn assert_no_aliasing(from: AudioFormat, to: AudioFormat, frequency: f32) {
+        let output = convert(from, to, frequency);
+        let filter = filter_frames(from, to) * to.channels as usize;
+        let settled = &output[filter..];
+        let rms = (settled.iter().map(|sample| sample * sample).sum::<f32>() / settled.len() as f32).sqrt();
+        assert!(rms < 0.01, "{} Hz leaked through at RMS {}", frequency, rms);
+    }
+
+    #[test]
+    fn test_capture_and_playback_conversions() {
+        let device = AudioFormat { sample_rate: 44_100, channels: 2 };
+        assert_converts_tone(device, AudioFormat::CALL);
+        assert_converts_tone(AudioFormat::CALL, device);
+        assert_converts_tone(AudioFormat { sample_rate: 16_000, channels: 1 }, AudioFormat::CALL);
+        assert_converts_tone(AudioFormat { sample_rate: 48_000, channels: 2 }, AudioFormat::CALL);
+        assert_converts_tone(AudioFormat { sample_rate: 96_000, channels: 2 }, AudioFormat { sample_rate: 44_100, channels: 2 });
+
+        // Linear interpolation would fold these back to 18 kHz and 4.1 kHz
+        assert_no_aliasing(AudioFormat { sample_rate: 96_000, channels: 1 }, AudioFormat::CALL, 30_000.0);
+        assert_no_aliasing(AudioFormat { sample_rate: 96_000, channels: 2 }, AudioFormat { sample_rate: 44_100, channels: 2 }, 40_000.0);
+    }
+
+    #[test]
+    fn test_matching_formats_pass_through() {
+        let mut adapter = FormatAdapter::new(AudioFormat::CALL, AudioFormat::CALL);
+        let input = sine(AudioFormat::CALL, 960, TONE_HZ);
+        assert_eq!(adapter.process(&input), input);
+    }
+}
@@ -0,0 +1,197 @@
+use crate::models::*;
+use serde::Serialize;
+use std::collections::HashMap;
+
+// Awareness message types, as in `shared/protocols/awareness.js`
+pub const USER_STATUS: &str = "user_status";
+pub const TYPING_INDICATOR: &str = "typing_indicator";
+pub const LAST_SEEN: &str = "last_seen";
+pub const DELIVERY_STATUS: &str = "delivery_status";
+pub const READ_RECEIPT: &str = "read_receipt";
+pub const VOICE_CALL_STATUS: &str = "voice_call_status";
+pub const NETWORK_STATUS: &str = "network_status";
+
+const AWARENESS_TYPES: &[&str] = &[
+    USER_STATUS,
+    TYPING_INDICATOR,
+    LAST_SEEN,
+    DELIVERY_STATUS,
+    READ_RECEIPT,
+    VOICE_CALL_STATUS,
+    NETWORK_STATUS,
+];
+
+pub const AWARENESS_VERSION: &str = "1.0";
+
+/// Settings keys for the status we publish
+pub const SETTING_USER_STATUS: &str = "user_status";
+pub const SETTING_STATUS_MESSAGE: &str = "user_status_message";
+
+/// A typing indicator older than this is treated as stopped
+pub const TYPING_INDICATOR_MAX_AGE_MS: i64 = 5000;
+
+/// Awareness timestamps are milliseconds, unlike the rest of the app
+pub fn now_millis() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
+
+fn awareness_message(message_type: &str, user_id: &str, timestamp: i64) -> AwarenessMessage {
+    AwarenessMessage {
+        r#type: message_type.to_string(),
+        user_id: user_id.to_string(),
+        timestamp,
+        status: None,
+        custom_message: None,
+        is_typing: None,
+        chat_id: None,
+        message_id: None,
+        delivery_status: None,
+        is_online: None,
+        connection_quality: None,
+        last_seen: None,
+        version: AWARENESS_VERSION.to_string(),
+    }
+}
+
+pub fn create_user_status(user_id: &str, status: ContactStatus, custom_message: Option<String>, timestamp: i64) -> AwarenessMessage {
+    AwarenessMessage {
+        status: Some(status.as_str().to_string()),
+        custom_message,
+        ..awareness_message(USER_STATUS, user_id, timestamp)
+    }
+}
+
+pub fn create_typing_indicator(user_id: &str, chat_id: &str, is_typing: bool, timestamp: i64) -> AwarenessMessage {
+    AwarenessMessage {
+        chat_id: Some(chat_id.to_string()),
+        is_typing: Some(is_typing),
+        ..awareness_message(TYPING_INDICATOR, user_id, timestamp)
+    }
+}
+
+pub fn create_last_seen_update(user_id: &str, timestamp: i64) -> AwarenessMessage {
+    AwarenessMessage {
+        last_seen: Some(timestamp),
+        ..awareness_message(LAST_SEEN, user_id, timestamp)
+    }
+}
+
+pub fn validate_awareness_message(message: &AwarenessMessage) -> bool {
+    !message.version.is_empty() && AWARENESS_TYPES.contains(&message.r#type.as_str())
+}
+
+pub fn should_show_typing_indicator(message: &AwarenessMessage, now: i64, max_age: i64) -> bool {
+    message.is_typing == Some(true) && now - message.timestamp < max_age
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserPresence {
+    pub status: String,
+    pub last_seen: i64,
+    pub is_typing: bool,
+    pub is_online: bool,
+    pub connection_quality: Option<f32>,
+    pub custom_message: Option<String>,
+}
+
+impl Default for UserPresence {
+    fn default() -> Self {
+        Self {
+            status: ContactStatus::Offline.as_str().to_string(),
+            last_seen: 0,
+            is_typing: false,
+            is_online: false,
+            connection_quality: None,
+            custom_message: None,
+        }
+    }
+}
+
+/// Fold awareness messages, in order, into one presence per user
+pub fn aggregate_user_presence(messages: &[AwarenessMessage], now: i64) -> HashMap<String, UserPresence> {
+    let mut user_presence: HashMap<String, UserPresence> = HashMap::new();
+
+    for message in messages.iter().filter(|m| validate_awareness_message(m)) {
+        let presence = user_presence.entry(message.user_id.clone()).or_default();
+
+        match message.r#type.as_str() {
+            USER_STATUS => {
+                presence.status = message.status.clone().unwrap_or_default();
+                presence.custom_message = message.custom_message.clone();
+                presence.last_seen = message.timestamp;
+            }
+            LAST_SEEN => {
+                presence.last_seen = presence.last_seen.max(message.last_seen.unwrap_or(message.timestamp));
+            }
+            TYPING_INDICATOR => {
+                presence.is_typing = should_show_typing_indicator(message, now, TYPING_INDICATOR_MAX_AGE_MS);
+            }
+            NETWORK_STATUS => {
+                presence.is_online = message.is_online.unwrap_or(false);
+                presence.connection_quality = message.connection_quality;
+            }
+            _ => {}
+        }
+    }
+
+    user_presence
+}
+
+/// Latest awareness message of each type per contact, for presence snapshots
+#[derive(Debug, Default)]
+pub struct AwarenessTracker {
+    latest: HashMap<(String, String), AwarenessMessage>,
+}
+
+impl AwarenessTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep `message` unless a newer one of the same type is already known
+    pub fn record(&mut self, message: AwarenessMessage) {
+        let key = (message.user_id.clone(), message.r#type.clone());
+        match self.latest.get(&key) {
+            Some(existing) if existing.timestamp > message.timestamp => {}
+            _ => {
+                self.latest.insert(key, message);
+            }
+        }
+    }
+
+    pub fn presence(&self, now: i64) -> HashMap<String, UserPresence> {
+        let mut messages: Vec<AwarenessMessage> = self.latest.values().cloned().collect();
+        messages.sort_by_key(|m| m.timestamp);
+        aggregate_user_presence(&messages, now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_presence_aggregation_and_typing_expiry() {
+        let mut tracker = AwarenessTracker::new();
+        tracker.record(create_user_status("alice", ContactStatus::Busy, Some("In a meeting".to_string()), 1_000));
+        tracker.record(create_last_seen_update("alice", 4_000));
+        tracker.record(create_typing_indicator("alice", "chat", true, 10_000));
+        // Out-of-order delivery of an older status must not win
+        tracker.record(create_user_status("alice", ContactStatus::Online, None, 500));
+
+        let presence = tracker.presence(12_000);
+        let alice = &presence["alice"];
+        assert_eq!(alice.status, "busy");
+        assert_eq!(alice.custom_message.as_deref(), Some("In a meeting"));
+        assert_eq!(alice.last_seen, 4_000);
+        assert!(alice.is_typing);
+
+        // Typing is dropped once the indicator is older than 5 seconds
+        assert!(!tracker.presence(10_000 + TYPING_INDICATOR_MAX_AGE_MS)["alice"].is_typing);
+
+        let mut invalid = create_last_seen_update("bob", 1);
+        invalid.r#type = "unknown".to_string();
+        assert!(aggregate_user_presence(&[invalid], 0).is_empty());
+    }
+}
@@ -1,20 +1,17 @@
 use crate::{AppState, crypto::*, models::*, database::*, network::*};
 use tauri::State;
-use serde_json::Value;
 use anyhow::Result;
 
 // Crypto Commands
 #[tauri::command]
 pub async fn generate_contact_code(state: State<'_, AppState>) -> Result<Vec<String>, String> {
-    let mut crypto = state.crypto.as_ref().clone();
-    crypto.generate_8_word_contact_code()
+    state.crypto.generate_8_word_contact_code()
         .map_err(|e| e.to_string())
 }
 
 #[tauri::command]
 pub async fn generate_key_pair(state: State<'_, AppState>) -> Result<KeyPair, String> {
-    let mut crypto = state.crypto.as_ref().clone();
-    crypto.generate_rsa_key_pair()
+    state.crypto.generate_identity_key_pair()
         .map_err(|e| e.to_string())
 }
 
@@ -24,8 +21,7 @@ pub async fn encrypt_message(
     public_key: String,
     state: State<'_, AppState>
 ) -> Result<EncryptedMessage, String> {
-    let mut crypto = state.crypto.as_ref().clone();
-    crypto.encrypt_message(&message, &public_key)
+    state.crypto.encrypt_message(&message, &public_key)
         .map_err(|e| e.to_string())
 }
 
@@ -94,6 +90,217 @@ pub async fn add_contact(
 ) -> Result<(), String> {
     let db = state.database.lock().await;
     db.insert_contact(&contact).await
+        .map_err(|e| e.to_string())?;
+
+    let contacts = db.get_all_contacts().await
+        .map_err(|e| e.to_string())?;
+    let network = state.network.lock().await;
+    network.set_trusted_contacts(&contacts).await;
+    Ok(())
+}
+
+// Contact Exchange Commands
+#[tauri::command]
+pub async fn send_contact_request(
+    public_words: Vec<String>,
+    name: String,
+    verification_message: String,
+    state: State<'_, AppState>
+) -> Result<String, String> {
+    let profile = {
+        let db = state.database.lock().await;
+        db.get_user_profile().await
+            .map_err(|e| e.to_string())?
+            .ok_or("No user profile found")?
+    };
+
+    let (request, envelope) = crate::handshake::build_contact_request(
+        &state.crypto,
+        &profile,
+        &public_words,
+        &verification_message,
+    ).map_err(|e| e.to_string())?;
+
+    {
+        let network = state.network.lock().await;
+        network.send_envelope(&envelope).await
+            .map_err(|e| e.to_string())?;
+    }
+
+    let outgoing = OutgoingContactRequest {
+        id: request.id.clone(),
+        recipient_name: name,
+        recipient_words: public_words,
+        verification_message,
+        status: "pending".to_string(),
+        sent_at: request.timestamp,
+    };
+
+    let db = state.database.lock().await;
+    db.insert_outgoing_contact_request(&outgoing).await
+        .map_err(|e| e.to_string())?;
+
+    Ok(request.id)
+}
+
+#[tauri::command]
+pub async fn list_contact_requests(
+    status: Option<String>,
+    state: State<'_, AppState>
+) -> Result<Vec<ContactRequest>, String> {
+    let db = state.database.lock().await;
+    db.get_contact_requests(status.as_deref()).await
+        .map_err(|e| e.to_string())
+}
+
+async fn answer_contact_request(
+    request_id: &str,
+    accepted: bool,
+    state: &State<'_, AppState>
+) -> Result<ContactRequest, String> {
+    let (profile, request) = {
+        let db = state.database.lock().await;
+        let profile = db.get_user_profile().await
+            .map_err(|e| e.to_string())?
+            .ok_or("No user profile found")?;
+        let request = db.get_contact_request(request_id).await
+            .map_err(|e| e.to_string())?
+            .ok_or("Contact request not found")?;
+        (profile, request)
+    };
+
+    if request.status != "pending" {
+        return Err(format!("Contact request already {}", request.status));
+    }
+
+    let envelope = crate::handshake::build_contact_response(&state.crypto, &profile, &request, accepted)
+        .map_err(|e| e.to_string())?;
+
+    let network = state.network.lock().await;
+    network.send_envelope(&envelope).await
+        .map_err(|e| e.to_string())?;
+
+    Ok(request)
+}
+
+#[tauri::command]
+pub async fn accept_contact_request(
+    request_id: String,
+    state: State<'_, AppState>
+) -> Result<Contact, String> {
+    let request = answer_contact_request(&request_id, true, &state).await?;
+    let contact = crate::handshake::contact_from_request(&request);
+
+    let db = state.database.lock().await;
+    db.insert_contact(&contact).await
+        .map_err(|e| e.to_string())?;
+    db.update_contact_request_status(&request_id, "accepted").await
+        .map_err(|e| e.to_string())?;
+
+    let contacts = db.get_all_contacts().await
+        .map_err(|e| e.to_string())?;
+    let network = state.network.lock().await;
+    network.set_trusted_contacts(&contacts).await;
+
+    Ok(contact)
+}
+
+#[tauri::command]
+pub async fn reject_contact_request(
+    request_id: String,
+    state: State<'_, AppState>
+) -> Result<(), String> {
+    answer_contact_request(&request_id, false, &state).await?;
+
+    let db = state.database.lock().await;
+    db.update_contact_request_status(&request_id, "rejected").await
+        .map_err(|e| e.to_string())
+}
+
+// Contact Verification Commands
+async fn load_safety_number(contact_id: &str, state: &State<'_, AppState>) -> Result<SafetyNumber, String> {
+    let (profile, contact) = {
+        let db = state.database.lock().await;
+        let profile = db.get_user_profile().await
+            .map_err(|e| e.to_string())?
+            .ok_or("No user profile found")?;
+        let contact = db.get_contact_by_id(contact_id).await
+            .map_err(|e| e.to_string())?
+            .ok_or("Contact not found")?;
+        (profile, contact)
+    };
+
+    state.crypto.compute_safety_number(&profile.public_key, &contact.public_key)
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn get_safety_number(
+    contact_id: String,
+    state: State<'_, AppState>
+) -> Result<SafetyNumber, String> {
+    load_safety_number(&contact_id, &state).await
+}
+
+/// Verify a contact from a scanned safety-number QR payload or typed digits
+#[tauri::command]
+pub async fn verify_safety_number(
+    contact_id: String,
+    presented: String,
+    state: State<'_, AppState>
+) -> Result<bool, String> {
+    let expected = load_safety_number(&contact_id, &state).await?;
+    let matches = state.crypto.matches_safety_number(&expected, &presented);
+
+    if matches {
+        let db = state.database.lock().await;
+        db.set_contact_verified(&contact_id, true).await
+            .map_err(|e| e.to_string())?;
+        db.acknowledge_key_changes(&contact_id).await
+            .map_err(|e| e.to_string())?;
+    } else {
+        crate::utils::Logger::log_security_event(
+            "Safety number mismatch",
+            &format!("contact={}", contact_id),
+        );
+    }
+
+    Ok(matches)
+}
+
+/// Mark a contact verified after the user compared safety numbers out of band
+#[tauri::command]
+pub async fn mark_contact_verified(
+    contact_id: String,
+    verified: bool,
+    state: State<'_, AppState>
+) -> Result<(), String> {
+    let db = state.database.lock().await;
+    db.set_contact_verified(&contact_id, verified).await
+        .map_err(|e| e.to_string())?;
+
+    if verified {
+        db.acknowledge_key_changes(&contact_id).await
+            .map_err(|e| e.to_string())?;
+    }
+
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn get_key_changes(state: State<'_, AppState>) -> Result<Vec<KeyChange>, String> {
+    let db = state.database.lock().await;
+    db.get_unacknowledged_key_changes().await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn acknowledge_key_change(
+    contact_id: String,
+    state: State<'_, AppState>
+) -> Result<(), String> {
+    let db = state.database.lock().await;
+    db.acknowledge_key_changes(&contact_id).await
         .map_err(|e| e.to_string())
 }
 
@@ -111,35 +318,219 @@ pub async fn get_messages(
 pub async fn send_message(
     contact_id: String,
     content: String,
+    app: tauri::AppHandle,
     state: State<'_, AppState>
 ) -> Result<String, String> {
-    let message = Message {
-        id: uuid::Uuid::new_v4().to_string(),
-        contact_id: contact_id.clone(),
-        content: content.clone(),
-        is_from_me: true,
-        timestamp: chrono::Utc::now().timestamp(),
-        message_type: "text".to_string(),
-        delivery_status: "sending".to_string(),
-        encrypted_content: String::new(),
-        created_at: chrono::Utc::now().timestamp(),
-    };
-
-    // Save to database
-    {
+    let message = {
         let db = state.database.lock().await;
+        let contact = db.get_contact_by_id(&contact_id).await
+            .map_err(|e| e.to_string())?
+            .ok_or("Contact not found")?;
+
+        let encrypted = crate::dispatcher::seal_message(&state.crypto, &db, &contact, &content).await
+            .map_err(|e| e.to_string())?;
+
+        let message = Message {
+            id: uuid::Uuid::new_v4().to_string(),
+            contact_id: contact_id.clone(),
+            content: content.clone(),
+            is_from_me: true,
+            timestamp: chrono::Utc::now().timestamp(),
+            message_type: "text".to_string(),
+            delivery_status: "sending".to_string(),
+            encrypted_content: serde_json::to_string(&encrypted).map_err(|e| e.to_string())?,
+            created_at: chrono::Utc::now().timestamp(),
+        };
+
+        // Save to database
         db.insert_message(&message).await
             .map_err(|e| e.to_string())?;
+
+        // Queue for the outbox worker, which retries until the server accepts it
+        let entry = OutboxEntry {
+            message_id: message.id.clone(),
+            contact_id: contact.id.clone(),
+            recipient_contact_code: contact.contact_code.join(" "),
+            message_type: message.message_type.clone(),
+            encrypted_message: encrypted,
+            timestamp: message.timestamp,
+            status: "pending".to_string(),
+            attempts: 0,
+            next_attempt_at: message.created_at,
+            last_error: None,
+            created_at: message.created_at,
+        };
+        crate::outbox::enqueue(&db, &state.outbox, &entry).await
+            .map_err(|e| e.to_string())?;
+        crate::dispatcher::emit_chat_sessions_changed(&app, &db).await;
+        message
+    };
+
+    Ok(message.id)
+}
+
+/// Resend a queued or failed message now, with a fresh attempt budget
+#[tauri::command]
+pub async fn retry_message(
+    message_id: String,
+    state: State<'_, AppState>
+) -> Result<(), String> {
+    let db = state.database.lock().await;
+    let queued = db.retry_outbox_entry(&message_id, chrono::Utc::now().timestamp()).await
+        .map_err(|e| e.to_string())?;
+    if !queued {
+        return Err("Message is not in the outbox".to_string());
     }
 
-    // Send via network
-    {
-        let mut network = state.network.lock().await;
-        network.send_message(&message).await
+    db.update_message_status(&message_id, "sending").await
+        .map_err(|e| e.to_string())?;
+    state.outbox.notify_one();
+    Ok(())
+}
+
+/// Queued and failed outbound messages with their attempt counts
+#[tauri::command]
+pub async fn get_outbox(state: State<'_, AppState>) -> Result<Vec<OutboxEntry>, String> {
+    let db = state.database.lock().await;
+    db.get_outbox_entries().await
+        .map_err(|e| e.to_string())
+}
+
+async fn mark_read(
+    contact_id: &str,
+    message_ids: Option<&[String]>,
+    app: &tauri::AppHandle,
+    state: &State<'_, AppState>
+) -> Result<(), String> {
+    let db = state.database.lock().await;
+    let contact = db.get_contact_by_id(contact_id).await
+        .map_err(|e| e.to_string())?
+        .ok_or("Contact not found")?;
+
+    let unread = db.mark_messages_read(contact_id, message_ids, chrono::Utc::now().timestamp()).await
+        .map_err(|e| e.to_string())?;
+    if unread.is_empty() {
+        return Ok(());
+    }
+    crate::dispatcher::emit_chat_sessions_changed(app, &db).await;
+
+    if crate::receipts::read_receipts_enabled(&db).await.map_err(|e| e.to_string())? {
+        crate::receipts::queue_receipt(
+            &state.crypto, &db, &state.outbox, &contact, crate::receipts::RECEIPT_READ, &unread,
+        ).await
             .map_err(|e| e.to_string())?;
     }
 
-    Ok(message.id)
+    Ok(())
+}
+
+/// Mark incoming messages as read once shown, and send read receipts unless opted out
+#[tauri::command]
+pub async fn mark_messages_displayed(
+    contact_id: String,
+    message_ids: Vec<String>,
+    app: tauri::AppHandle,
+    state: State<'_, AppState>
+) -> Result<(), String> {
+    mark_read(&contact_id, Some(&message_ids), &app, &state).await
+}
+
+/// Mark everything from a contact as read, e.g. when their chat is opened
+#[tauri::command]
+pub async fn mark_chat_read(
+    contact_id: String,
+    app: tauri::AppHandle,
+    state: State<'_, AppState>
+) -> Result<(), String> {
+    mark_read(&contact_id, None, &app, &state).await
+}
+
+/// Chat list: one entry per contact with last message preview and unread count
+#[tauri::command]
+pub async fn get_chat_sessions(state: State<'_, AppState>) -> Result<Vec<ChatSession>, String> {
+    let mut sessions = {
+        let db = state.database.lock().await;
+        db.get_chat_sessions().await
+            .map_err(|e| e.to_string())?
+    };
+
+    let presence = state.awareness.lock().await.presence(crate::awareness::now_millis());
+    for session in &mut sessions {
+        session.is_typing = presence.get(&session.contact_id).map_or(false, |p| p.is_typing);
+    }
+    Ok(sessions)
+}
+
+#[tauri::command]
+pub async fn get_read_receipts_enabled(state: State<'_, AppState>) -> Result<bool, String> {
+    let db = state.database.lock().await;
+    crate::receipts::read_receipts_enabled(&db).await
+        .map_err(|e| e.to_string())
+}
+
+/// Opt out of (or back into) sending read receipts; delivery receipts are always sent
+#[tauri::command]
+pub async fn set_read_receipts_enabled(
+    enabled: bool,
+    state: State<'_, AppState>
+) -> Result<(), String> {
+    let db = state.database.lock().await;
+    db.set_setting(crate::receipts::SETTING_READ_RECEIPTS, if enabled { "true" } else { "false" }).await
+        .map_err(|e| e.to_string())
+}
+
+// Database Lock Commands
+#[tauri::command]
+pub async fn get_database_lock_state(state: State<'_, AppState>) -> Result<crate::db_encryption::DatabaseLockState, String> {
+    let db = state.database.lock().await;
+    Ok(db.get_lock_state())
+}
+
+/// Encrypt sensitive columns. Without a passphrase the key is kept in the OS keyring.
+#[tauri::command]
+pub async fn enable_database_encryption(
+    passphrase: Option<String>,
+    state: State<'_, AppState>
+) -> Result<(), String> {
+    // Argon2 is deliberately slow; wrap the key before taking the database lock
+    let cipher = crate::db_encryption::ColumnCipher::generate();
+    let (cipher, wrapped) = tokio::task::spawn_blocking(move || {
+        let wrapped = passphrase.map(|passphrase| cipher.wrap_with_passphrase(&passphrase)).transpose()?;
+        anyhow::Ok((cipher, wrapped))
+    })
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())?;
+
+    let mut db = state.database.lock().await;
+    db.enable_encryption(cipher, wrapped).await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn unlock_database(
+    passphrase: Option<String>,
+    state: State<'_, AppState>
+) -> Result<(), String> {
+    let wrapped = state.database.lock().await.wrapped_key().cloned()
+        .ok_or("Database encryption is not enabled")?;
+
+    // Argon2 is deliberately slow; unwrap the key before taking the database lock
+    let cipher = tokio::task::spawn_blocking(move || crate::db_encryption::ColumnCipher::unwrap(&wrapped, passphrase.as_deref()))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())?;
+
+    let mut db = state.database.lock().await;
+    db.unlock(cipher).await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn lock_database(state: State<'_, AppState>) -> Result<(), String> {
+    let mut db = state.database.lock().await;
+    db.lock().await
+        .map_err(|e| e.to_string())
 }
 
 #[tauri::command]
@@ -165,13 +556,39 @@ pub async fn connect_to_server(
     server_url: String,
     state: State<'_, AppState>
 ) -> Result<(), String> {
-    let mut network = state.network.lock().await;
-    network.connect(&server_url).await
-        .map_err(|e| e.to_string())
+    let server_url = crate::discovery::normalize_node_url(&server_url)
+        .map_err(|e| e.to_string())?;
+    {
+        let db = state.database.lock().await;
+        db.ensure_server_node(&server_url).await
+            .map_err(|e| e.to_string())?;
+    }
+
+    crate::supervisor::establish_session(&state.database, &state.network, &server_url).await
+        .map_err(|e| e.to_string())?;
+
+    if let Err(e) = crate::discovery::sync_nodes(&state.database, &state.network).await {
+        log::warn!("Node discovery failed: {}", e);
+    }
+
+    // From here on the supervisor keeps us connected, failing over if needed
+    state.supervisor.enable();
+    Ok(())
 }
 
 #[tauri::command]
 pub async fn disconnect_from_server(state: State<'_, AppState>) -> Result<(), String> {
+    state.supervisor.disable();
+
+    // Let contacts know we left instead of leaving them with a stale status
+    let contacts = state.database.lock().await.get_all_contacts().await
+        .map_err(|e| e.to_string())?;
+    if let Err(e) = crate::presence::PresencePublisher::new(&state)
+        .publish_to(&contacts, Some(ContactStatus::Offline)).await
+    {
+        log::warn!("Failed to announce going offline: {}", e);
+    }
+
     let mut network = state.network.lock().await;
     network.disconnect().await
         .map_err(|e| e.to_string())
@@ -184,6 +601,212 @@ pub async fn get_server_status(state: State<'_, AppState>) -> Result<ServerStatu
         .map_err(|e| e.to_string())
 }
 
+#[tauri::command]
+pub async fn list_server_nodes(state: State<'_, AppState>) -> Result<Vec<ServerNode>, String> {
+    let db = state.database.lock().await;
+    db.get_all_server_nodes().await
+        .map_err(|e| e.to_string())
+}
+
+/// Add a node by hand. With `announce`, the connected server is told about it
+/// so peers can discover it; that requires the node's public key.
+#[tauri::command]
+pub async fn add_server_node(
+    url: String,
+    public_key: Option<String>,
+    priority: Option<i32>,
+    announce: Option<bool>,
+    state: State<'_, AppState>
+) -> Result<ServerNode, String> {
+    let url = crate::discovery::normalize_node_url(&url)
+        .map_err(|e| e.to_string())?;
+
+    let node = ServerNode {
+        url: url.clone(),
+        public_key: public_key.clone().unwrap_or_default(),
+        is_active: true,
+        last_ping: 0,
+        response_time: 0,
+        priority: priority.unwrap_or(0),
+        pending_public_key: None,
+    };
+    {
+        let db = state.database.lock().await;
+        if db.get_server_node(&url).await.map_err(|e| e.to_string())?.is_some() {
+            return Err("Node already exists".to_string());
+        }
+        db.insert_server_node(&node).await
+            .map_err(|e| e.to_string())?;
+    }
+
+    if announce.unwrap_or(false) {
+        let public_key = public_key.ok_or("A public key is required to announce a node")?;
+        let network = state.network.lock().await;
+        let server_url = network.server_url().await.ok_or("Not connected to server")?;
+        network.register_node(&server_url, &url, &public_key).await
+            .map_err(|e| e.to_string())?;
+    }
+
+    Ok(node)
+}
+
+#[tauri::command]
+pub async fn set_server_node_enabled(
+    url: String,
+    enabled: bool,
+    state: State<'_, AppState>
+) -> Result<(), String> {
+    let db = state.database.lock().await;
+    let updated = db.set_server_node_active(&url, enabled).await
+        .map_err(|e| e.to_string())?;
+    if !updated {
+        return Err("Node not found".to_string());
+    }
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn set_server_node_priority(
+    url: String,
+    priority: i32,
+    state: State<'_, AppState>
+) -> Result<(), String> {
+    let db = state.database.lock().await;
+    let updated = db.set_server_node_priority(&url, priority).await
+        .map_err(|e| e.to_string())?;
+    if !updated {
+        return Err("Node not found".to_string());
+    }
+    Ok(())
+}
+
+/// Accept a node's changed key after the user has checked it out of band.
+/// Node keys are informational; trusting one only puts the node back in use.
+#[tauri::command]
+pub async fn trust_server_node_key(
+    url: String,
+    state: State<'_, AppState>
+) -> Result<(), String> {
+    let db = state.database.lock().await;
+    let node = db.get_server_node(&url).await
+        .map_err(|e| e.to_string())?
+        .ok_or("Node not found")?;
+    let pending = node.pending_public_key.ok_or("Node has no pending key change")?;
+
+    db.pin_server_node_key(&url, &pending).await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn discover_server_nodes(state: State<'_, AppState>) -> Result<Vec<crate::discovery::NodeMergeOutcome>, String> {
+    crate::discovery::sync_nodes(&state.database, &state.network).await
+        .map_err(|e| e.to_string())
+}
+
+// Presence Commands
+/// Set our status (online, away, busy, invisible, offline) and send it to verified contacts
+#[tauri::command]
+pub async fn set_user_status(
+    status: String,
+    custom_message: Option<String>,
+    state: State<'_, AppState>
+) -> Result<(), String> {
+    let status = ContactStatus::parse(&status)
+        .ok_or_else(|| format!("Invalid user status: {}", status))?;
+
+    {
+        let db = state.database.lock().await;
+        db.set_setting(crate::awareness::SETTING_USER_STATUS, status.as_str()).await
+            .map_err(|e| e.to_string())?;
+        db.set_setting(crate::awareness::SETTING_STATUS_MESSAGE, custom_message.as_deref().unwrap_or("")).await
+            .map_err(|e| e.to_string())?;
+    }
+
+    crate::presence::PresencePublisher::new(&state).publish_all().await
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// Hide our presence from one contact (they see us offline), or show it again
+#[tauri::command]
+pub async fn set_presence_visibility(
+    contact_id: String,
+    visible: bool,
+    state: State<'_, AppState>
+) -> Result<(), String> {
+    let contact = {
+        let db = state.database.lock().await;
+        let contact = db.get_contact_by_id(&contact_id).await
+            .map_err(|e| e.to_string())?
+            .ok_or("Contact not found")?;
+        db.set_presence_visibility(&contact_id, visible).await
+            .map_err(|e| e.to_string())?;
+        contact
+    };
+
+    crate::presence::PresencePublisher::new(&state).publish_to(&[contact], None).await
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn get_presence_visibility(
+    contact_id: String,
+    state: State<'_, AppState>
+) -> Result<bool, String> {
+    let db = state.database.lock().await;
+    db.get_presence_visibility(&contact_id).await
+        .map_err(|e| e.to_string())
+}
+
+/// Tell a contact we started or stopped typing. Not queued: a stale
+/// indicator is worse than none, so nothing is sent while offline.
+#[tauri::command]
+pub async fn send_typing_indicator(
+    contact_id: String,
+    is_typing: bool,
+    state: State<'_, AppState>
+) -> Result<(), String> {
+    if !state.network.lock().await.is_connected().await {
+        return Ok(());
+    }
+
+    let (contact, encrypted) = {
+        let db = state.database.lock().await;
+        let contact = db.get_contact_by_id(&contact_id).await
+            .map_err(|e| e.to_string())?
+            .ok_or("Contact not found")?;
+        if !crate::presence::may_share_activity(&db, &contact).await.map_err(|e| e.to_string())? {
+            return Ok(());
+        }
+        let profile = db.get_user_profile().await
+            .map_err(|e| e.to_string())?
+            .ok_or("No user profile found")?;
+
+        let user_id = profile.contact_code.join(" ");
+        let indicator = crate::awareness::create_typing_indicator(
+            &user_id, &user_id, is_typing, crate::awareness::now_millis(),
+        );
+        let plaintext = serde_json::to_string(&indicator).map_err(|e| e.to_string())?;
+        let encrypted = crate::dispatcher::seal_ephemeral(&state.crypto, &contact, &plaintext)
+            .map_err(|e| e.to_string())?;
+        (contact, encrypted)
+    };
+
+    let network = state.network.lock().await;
+    network.send_real_time_message(&contact.contact_code.join(" "), crate::awareness::TYPING_INDICATOR, encrypted).await
+        .map_err(|e| e.to_string())
+}
+
+/// Presence aggregated from the awareness updates received this session, by contact id
+#[tauri::command]
+pub async fn get_presence(
+    state: State<'_, AppState>
+) -> Result<std::collections::HashMap<String, crate::awareness::UserPresence>, String> {
+    let awareness = state.awareness.lock().await;
+    Ok(awareness.presence(crate::awareness::now_millis()))
+}
+
 // Voice Call Commands
 #[tauri::command]
 pub async fn initiate_voice_call(
@@ -200,7 +823,33 @@ pub async fn initiate_voice_call(
             .ok_or("Contact not found")?
     };
 
-    voice.initiate_call(&contact).await
+    let call_id = voice.initiate_call(&contact).await
+        .map_err(|e| e.to_string())?;
+
+    if let Err(e) = offer_call(&call_id, &contact, &voice, &state).await {
+        let _ = voice.end_call().await;
+        return Err(e);
+    }
+    Ok(call_id)
+}
+
+/// Send the call offer carrying the call's media key, sealed to `contact`
+async fn offer_call(
+    call_id: &str,
+    contact: &Contact,
+    voice: &crate::voice::VoiceCallManager,
+    state: &State<'_, AppState>
+) -> Result<(), String> {
+    let key = voice.call_key(call_id).await
+        .map_err(|e| e.to_string())?;
+    // The offer is dropped if the contact is offline, so the key can't go through the ratchet
+    let sealed_key = crate::dispatcher::seal_ephemeral(&state.crypto, contact, &key.to_base64())
+        .map_err(|e| e.to_string())?;
+    let sealed_key = serde_json::to_string(&sealed_key)
+        .map_err(|e| e.to_string())?;
+
+    let network = state.network.lock().await;
+    network.send_voice_call_init(call_id, &contact.contact_code.join(" "), &sealed_key).await
         .map_err(|e| e.to_string())
 }
 
@@ -210,7 +859,14 @@ pub async fn accept_voice_call(
     state: State<'_, AppState>
 ) -> Result<(), String> {
     let mut voice = state.voice.lock().await;
-    voice.accept_call(&call_id).await
+    let contact = voice.call_contact(&call_id).await
+        .map_err(|e| e.to_string())?;
+    let key_share = crate::voice_crypto::generate_key_share();
+    voice.accept_call(&call_id, &key_share).await
+        .map_err(|e| e.to_string())?;
+
+    let network = state.network.lock().await;
+    network.send_voice_call_accept(&call_id, &contact.contact_code.join(" "), &key_share).await
         .map_err(|e| e.to_string())
 }
 
@@ -220,15 +876,34 @@ pub async fn reject_voice_call(
     state: State<'_, AppState>
 ) -> Result<(), String> {
     let mut voice = state.voice.lock().await;
+    let contact = voice.call_contact(&call_id).await
+        .map_err(|e| e.to_string())?;
     voice.reject_call(&call_id).await
+        .map_err(|e| e.to_string())?;
+
+    let network = state.network.lock().await;
+    network.send_voice_call_reject(&call_id, &contact.contact_code.join(" ")).await
         .map_err(|e| e.to_string())
 }
 
 #[tauri::command]
 pub async fn end_voice_call(state: State<'_, AppState>) -> Result<(), String> {
     let mut voice = state.voice.lock().await;
+    let peer = match voice.current_call_id().await {
+        Some(call_id) => voice.call_contact(&call_id).await.ok().map(|contact| (call_id, contact)),
+        None => None,
+    };
     voice.end_call().await
-        .map_err(|e| e.to_string())
+        .map_err(|e| e.to_string())?;
+
+    // The call is over locally either way
+    if let Some((call_id, contact)) = peer {
+        let network = state.network.lock().await;
+        if let Err(e) = network.send_voice_call_end(&call_id, &contact.contact_code.join(" ")).await {
+            log::warn!("Failed to notify peer of call end: {}", e);
+        }
+    }
+    Ok(())
 }
 
 #[tauri::command]
@@ -238,6 +913,50 @@ pub async fn get_call_status(state: State<'_, AppState>) -> Result<CallStatus, S
         .map_err(|e| e.to_string())
 }
 
+#[tauri::command]
+pub async fn list_audio_devices(state: State<'_, AppState>) -> Result<AudioDevices, String> {
+    let selection = state.voice.lock().await.device_selection();
+    tokio::task::spawn_blocking(move || crate::voice::list_devices(&selection))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())
+}
+
+/// Choose and remember an input or output device; None goes back to the system default
+#[tauri::command]
+pub async fn select_audio_device(
+    direction: AudioDirection,
+    name: Option<String>,
+    state: State<'_, AppState>
+) -> Result<(), String> {
+    {
+        let mut voice = state.voice.lock().await;
+        voice.select_device(direction, name.clone()).await
+            .map_err(|e| e.to_string())?;
+    }
+
+    let db = state.database.lock().await;
+    db.set_setting(crate::voice::device_setting_key(direction), name.as_deref().unwrap_or("")).await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn get_voice_codec_config(state: State<'_, AppState>) -> Result<crate::voice_codec::CodecConfig, String> {
+    let voice = state.voice.lock().await;
+    Ok(voice.codec_config().clone())
+}
+
+/// Opus bitrate, complexity and FEC for calls started after this
+#[tauri::command]
+pub async fn set_voice_codec_config(
+    config: crate::voice_codec::CodecConfig,
+    state: State<'_, AppState>
+) -> Result<(), String> {
+    let mut voice = state.voice.lock().await;
+    voice.set_codec_config(config)
+        .map_err(|e| e.to_string())
+}
+
 // Utility Commands
 #[tauri::command]
 pub async fn export_keys(
@@ -249,19 +968,13 @@ pub async fn export_keys(
         .map_err(|e| e.to_string())?
         .ok_or("No user profile found")?;
 
-    // Encrypt and export keys
-    let export_data = serde_json::json!({
-        "version": "1.0",
-        "contact_code": profile.contact_code,
-        "secret_words": profile.secret_words,
-        "public_key": profile.public_key,
-        "private_key": profile.private_key,
-        "device_id": profile.device_id,
-        "created_at": profile.created_at
-    });
+    let payload = crate::key_export::KeyExportPayload::from_profile(&profile);
 
-    // TODO: Encrypt with password
-    Ok(export_data.to_string())
+    // Argon2 is deliberately slow; keep it off the async workers
+    tokio::task::spawn_blocking(move || crate::key_export::export_keys(&payload, &password))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())
 }
 
 #[tauri::command]
@@ -270,35 +983,16 @@ pub async fn import_keys(
     password: String,
     state: State<'_, AppState>
 ) -> Result<(), String> {
-    // TODO: Decrypt with password
-    let import_data: Value = serde_json::from_str(&encrypted_data)
-        .map_err(|e| e.to_string())?;
-
-    let profile = UserProfile {
-        id: "user_profile".to_string(),
-        contact_code: import_data["contact_code"].as_array()
-            .ok_or("Invalid contact_code")?
-            .iter()
-            .map(|v| v.as_str().unwrap_or("").to_string())
-            .collect(),
-        secret_words: import_data["secret_words"].as_array()
-            .ok_or("Invalid secret_words")?
-            .iter()
-            .map(|v| v.as_str().unwrap_or("").to_string())
-            .collect(),
-        public_key: import_data["public_key"].as_str()
-            .ok_or("Invalid public_key")?.to_string(),
-        private_key: import_data["private_key"].as_str()
-            .ok_or("Invalid private_key")?.to_string(),
-        device_id: import_data["device_id"].as_str()
-            .ok_or("Invalid device_id")?.to_string(),
-        display_name: "Me".to_string(),
-        status: "online".to_string(),
-        custom_message: String::new(),
-        created_at: import_data["created_at"].as_i64().unwrap_or(0),
-    };
+    let payload = tokio::task::spawn_blocking(move || crate::key_export::import_keys(&encrypted_data, &password))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())?;
 
     let db = state.database.lock().await;
+    let existing = db.get_user_profile().await
+        .map_err(|e| e.to_string())?;
+    let profile = payload.into_profile(existing);
+
     db.save_user_profile(&profile).await
         .map_err(|e| e.to_string())
 }
@@ -3,15 +3,32 @@ use bip39::{Mnemonic, Language, MnemonicType};
 use pbkdf2::{pbkdf2_hmac};
 use rand::{RngCore, rngs::OsRng};
 use rsa::{RsaPrivateKey, RsaPublicKey, PaddingScheme, PublicKey, PublicKeyParts};
-use sha2::Sha256;
+use sha2::{Digest, Sha256, Sha512};
 use serde::{Deserialize, Serialize};
 use anyhow::{Result, anyhow};
 use base64::{Engine as _, engine::general_purpose};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use hkdf::Hkdf;
+use x25519_dalek::{PublicKey as X25519PublicKey, StaticSecret};
 
 const RSA_KEY_SIZE: usize = 4096;
 const AES_KEY_SIZE: usize = 32;
 const PBKDF2_ITERATIONS: u32 = 100_000;
 const CONTACT_MESSAGE_LENGTH: usize = 256;
+const IDENTITY_KEY_SIZE: usize = 64;
+const X25519_HKDF_INFO: &[u8] = b"nonmessenger-x25519-aes256gcm-v1";
+const SAFETY_NUMBER_VERSION: u16 = 1;
+const SAFETY_NUMBER_ITERATIONS: usize = 5200;
+const SAFETY_NUMBER_GROUPS_PER_KEY: usize = 6;
+
+/// Legacy hybrid scheme: AES key wrapped with RSA-OAEP
+pub const ALGORITHM_RSA_OAEP: &str = "rsa-oaep-aes256gcm";
+/// Ephemeral X25519 ECDH, HKDF-SHA256 key derivation, AES-256-GCM
+pub const ALGORITHM_X25519_HKDF: &str = "x25519-hkdf-aes256gcm";
+
+fn default_algorithm() -> String {
+    ALGORITHM_RSA_OAEP.to_string()
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct KeyPair {
@@ -25,6 +42,9 @@ pub struct EncryptedMessage {
     pub encrypted_key: String,
     pub iv: String,
     pub auth_tag: String,
+    /// Envelopes written before the algorithm tag existed are RSA-OAEP
+    #[serde(default = "default_algorithm")]
+    pub algorithm: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -37,20 +57,164 @@ pub struct QRCodeData {
     pub timestamp: u64,
 }
 
-pub struct NonMessengerCrypto {
-    rng: OsRng,
+/// Human-comparable fingerprint of two parties' public keys.
+///
+/// Both sides compute the same value regardless of who is "local".
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SafetyNumber {
+    /// 12 groups of 5 digits separated by spaces
+    pub digits: String,
+    /// 12 BIP39 words derived from the same fingerprints
+    pub words: Vec<String>,
+    /// JSON payload for QR scanning
+    pub qr_payload: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SafetyNumberQRData {
+    pub version: String,
+    pub r#type: String,
+    pub safety_number: String,
+}
+
+/// Long-term identity: an Ed25519 signing key and an X25519 agreement key.
+///
+/// Both halves are encoded together as base64 of 64 bytes (signing || agreement),
+/// which keeps public keys short enough for QR codes.
+pub struct IdentityKeyPair {
+    pub signing_key: SigningKey,
+    pub agreement_key: StaticSecret,
+}
+
+pub struct IdentityPublicKey {
+    pub verifying_key: VerifyingKey,
+    pub agreement_key: X25519PublicKey,
+}
+
+impl IdentityKeyPair {
+    /// Expand a 32-byte seed into independent signing and agreement keys
+    pub fn from_seed(seed: &[u8; 32]) -> Result<Self> {
+        let hkdf = Hkdf::<Sha256>::new(Some(b"nonmessenger-identity"), seed);
+
+        let mut signing_bytes = [0u8; 32];
+        let mut agreement_bytes = [0u8; 32];
+        hkdf.expand(b"ed25519-signing", &mut signing_bytes)
+            .map_err(|_| anyhow!("Identity key derivation failed"))?;
+        hkdf.expand(b"x25519-agreement", &mut agreement_bytes)
+            .map_err(|_| anyhow!("Identity key derivation failed"))?;
+
+        Ok(Self {
+            signing_key: SigningKey::from_bytes(&signing_bytes),
+            agreement_key: StaticSecret::from(agreement_bytes),
+        })
+    }
+
+    pub fn from_private_key(private_key: &str) -> Result<Self> {
+        let bytes = decode_identity_key(private_key)?;
+        let mut signing_bytes = [0u8; 32];
+        let mut agreement_bytes = [0u8; 32];
+        signing_bytes.copy_from_slice(&bytes[..32]);
+        agreement_bytes.copy_from_slice(&bytes[32..]);
+
+        Ok(Self {
+            signing_key: SigningKey::from_bytes(&signing_bytes),
+            agreement_key: StaticSecret::from(agreement_bytes),
+        })
+    }
+
+    pub fn public_key(&self) -> IdentityPublicKey {
+        IdentityPublicKey {
+            verifying_key: self.signing_key.verifying_key(),
+            agreement_key: X25519PublicKey::from(&self.agreement_key),
+        }
+    }
+
+    /// Detached Ed25519 signature, base64 encoded
+    pub fn sign(&self, data: &[u8]) -> String {
+        general_purpose::STANDARD.encode(self.signing_key.sign(data).to_bytes())
+    }
+
+    pub fn to_key_pair(&self) -> KeyPair {
+        let mut private_bytes = Vec::with_capacity(IDENTITY_KEY_SIZE);
+        private_bytes.extend_from_slice(&self.signing_key.to_bytes());
+        private_bytes.extend_from_slice(&self.agreement_key.to_bytes());
+
+        KeyPair {
+            public_key: self.public_key().encode(),
+            private_key: general_purpose::STANDARD.encode(&private_bytes),
+        }
+    }
+}
+
+impl IdentityPublicKey {
+    pub fn from_public_key(public_key: &str) -> Result<Self> {
+        let bytes = decode_identity_key(public_key)?;
+        let mut verifying_bytes = [0u8; 32];
+        let mut agreement_bytes = [0u8; 32];
+        verifying_bytes.copy_from_slice(&bytes[..32]);
+        agreement_bytes.copy_from_slice(&bytes[32..]);
+
+        Ok(Self {
+            verifying_key: VerifyingKey::from_bytes(&verifying_bytes)
+                .map_err(|_| anyhow!("Invalid Ed25519 public key"))?,
+            agreement_key: X25519PublicKey::from(agreement_bytes),
+        })
+    }
+
+    pub fn verify(&self, data: &[u8], signature: &str) -> Result<()> {
+        let signature_bytes: [u8; 64] = general_purpose::STANDARD.decode(signature)
+            .map_err(|_| anyhow!("Invalid signature encoding"))?
+            .try_into()
+            .map_err(|_| anyhow!("Invalid signature length"))?;
+
+        self.verifying_key
+            .verify(data, &Signature::from_bytes(&signature_bytes))
+            .map_err(|_| anyhow!("Signature verification failed"))
+    }
+
+    pub fn encode(&self) -> String {
+        let mut bytes = Vec::with_capacity(IDENTITY_KEY_SIZE);
+        bytes.extend_from_slice(self.verifying_key.as_bytes());
+        bytes.extend_from_slice(self.agreement_key.as_bytes());
+        general_purpose::STANDARD.encode(&bytes)
+    }
+}
+
+/// Identity keys are compact base64; legacy RSA keys are PEM
+pub fn is_identity_key(key: &str) -> bool {
+    !key.trim_start().starts_with("-----BEGIN")
+}
+
+fn decode_identity_key(key: &str) -> Result<Vec<u8>> {
+    let bytes = general_purpose::STANDARD.decode(key.trim())
+        .map_err(|_| anyhow!("Invalid identity key encoding"))?;
+    if bytes.len() != IDENTITY_KEY_SIZE {
+        return Err(anyhow!("Identity key must be {} bytes", IDENTITY_KEY_SIZE));
+    }
+    Ok(bytes)
 }
 
+/// Stateless: every call draws fresh randomness from the OS, so one instance
+/// can be shared behind an `Arc`
+#[derive(Clone, Default)]
+pub struct NonMessengerCrypto;
+
 impl NonMessengerCrypto {
     pub fn new() -> Self {
-        Self {
-            rng: OsRng,
-        }
+        Self
+    }
+
+    /// Generate an Ed25519 + X25519 identity key pair
+    pub fn generate_identity_key_pair(&self) -> Result<KeyPair> {
+        let mut seed = [0u8; 32];
+        OsRng.fill_bytes(&mut seed);
+        let identity = IdentityKeyPair::from_seed(&seed)?;
+        Ok(identity.to_key_pair())
     }
 
-    /// Generate a 4096-bit RSA key pair for maximum security
-    pub fn generate_rsa_key_pair(&mut self) -> Result<KeyPair> {
-        let private_key = RsaPrivateKey::new(&mut self.rng, RSA_KEY_SIZE)?;
+    /// Generate a 4096-bit RSA key pair (legacy, slow; kept for existing envelopes)
+    pub fn generate_rsa_key_pair(&self) -> Result<KeyPair> {
+        let private_key = RsaPrivateKey::new(&mut OsRng, RSA_KEY_SIZE)?;
         let public_key = RsaPublicKey::from(&private_key);
 
         let private_pem = private_key.to_pkcs8_pem(rsa::pkcs8::LineEnding::LF)?;
@@ -63,7 +227,7 @@ impl NonMessengerCrypto {
     }
 
     /// Generate 8 BIP39 words for public contact sharing
-    pub fn generate_8_word_contact_code(&mut self) -> Result<Vec<String>> {
+    pub fn generate_8_word_contact_code(&self) -> Result<Vec<String>> {
         let mnemonic = Mnemonic::new(MnemonicType::Words12, Language::English);
         let words: Vec<String> = mnemonic.phrase()
             .split_whitespace()
@@ -75,7 +239,7 @@ impl NonMessengerCrypto {
     }
 
     /// Generate 8 BIP39 words for private verification
-    pub fn generate_8_word_secret_code(&mut self) -> Result<Vec<String>> {
+    pub fn generate_8_word_secret_code(&self) -> Result<Vec<String>> {
         let mnemonic = Mnemonic::new(MnemonicType::Words12, Language::English);
         let words: Vec<String> = mnemonic.phrase()
             .split_whitespace()
@@ -86,53 +250,40 @@ impl NonMessengerCrypto {
         Ok(words)
     }
 
-    /// Generate deterministic RSA key pair from 8 contact words (2048-bit for initial contact)
+    /// Generate deterministic identity key pair from 8 contact words
     pub fn generate_contact_key_pair(&self, words: &[String]) -> Result<KeyPair> {
         if words.len() != 8 {
             return Err(anyhow!("Contact code must be exactly 8 words"));
         }
 
         let seed = self.derive_key_from_words(words)?;
-        let mut rng = rand_chacha::ChaCha20Rng::from_seed(seed);
-        
-        let private_key = RsaPrivateKey::new(&mut rng, 2048)?;
-        let public_key = RsaPublicKey::from(&private_key);
-
-        let private_pem = private_key.to_pkcs8_pem(rsa::pkcs8::LineEnding::LF)?;
-        let public_pem = public_key.to_public_key_pem(rsa::pkcs8::LineEnding::LF)?;
-
-        Ok(KeyPair {
-            public_key: public_pem,
-            private_key: private_pem.to_string(),
-        })
+        let identity = IdentityKeyPair::from_seed(&seed)?;
+        Ok(identity.to_key_pair())
     }
 
-    /// Generate deterministic RSA key pair from all 16 words (4096-bit for full security)
+    /// Generate deterministic identity key pair from all 16 words
     pub fn generate_full_key_pair(&self, words: &[String]) -> Result<KeyPair> {
         if words.len() != 16 {
             return Err(anyhow!("Full key generation requires 16 words"));
         }
 
         let seed = self.derive_key_from_words(words)?;
-        let mut rng = rand_chacha::ChaCha20Rng::from_seed(seed);
-        
-        let private_key = RsaPrivateKey::new(&mut rng, RSA_KEY_SIZE)?;
-        let public_key = RsaPublicKey::from(&private_key);
-
-        let private_pem = private_key.to_pkcs8_pem(rsa::pkcs8::LineEnding::LF)?;
-        let public_pem = public_key.to_public_key_pem(rsa::pkcs8::LineEnding::LF)?;
-
-        Ok(KeyPair {
-            public_key: public_pem,
-            private_key: private_pem.to_string(),
-        })
+        let identity = IdentityKeyPair::from_seed(&seed)?;
+        Ok(identity.to_key_pair())
     }
 
-    /// Derive cryptographic key from BIP39 words
+    /// Derive cryptographic key from BIP39 words.
+    ///
+    /// 8-word codes are not valid mnemonics (no checksum), so the BIP39 seed is
+    /// computed directly, matching `mnemonicToSeedSync` on the other platforms.
     pub fn derive_key_from_words(&self, words: &[String]) -> Result<[u8; 32]> {
+        if words.iter().any(|word| word.is_empty() || !word.chars().all(|c| c.is_ascii_lowercase())) {
+            return Err(anyhow!("Contact words must be lowercase BIP39 words"));
+        }
+
         let phrase = words.join(" ");
-        let mnemonic = Mnemonic::from_phrase(&phrase, Language::English)?;
-        let seed = mnemonic.to_seed("");
+        let mut seed = [0u8; 64];
+        pbkdf2_hmac::<Sha512>(phrase.as_bytes(), b"mnemonic", 2048, &mut seed);
         
         let mut key = [0u8; 32];
         pbkdf2_hmac::<Sha256>(&seed, b"nonmessenger-salt", PBKDF2_ITERATIONS, &mut key);
@@ -140,8 +291,10 @@ impl NonMessengerCrypto {
         Ok(key)
     }
 
-    /// Encrypt message using hybrid RSA + AES-256-GCM encryption
-    pub fn encrypt_message(&mut self, message: &str, public_key_pem: &str) -> Result<EncryptedMessage> {
+    /// Encrypt message for a recipient key.
+    ///
+    /// Identity keys use X25519 + HKDF; PEM keys fall back to RSA-OAEP.
+    pub fn encrypt_message(&self, message: &str, public_key: &str) -> Result<EncryptedMessage> {
         // CONTENT POLICY: Limit message size to 2048 bytes (2KB) for text-only communication
         // This prevents file sharing, image distribution, and other binary content
         let message_bytes = message.as_bytes();
@@ -153,55 +306,219 @@ impl NonMessengerCrypto {
             ));
         }
 
-        // Generate random AES key and nonce
+        if is_identity_key(public_key) {
+            let recipient = IdentityPublicKey::from_public_key(public_key)?;
+            self.encrypt_message_x25519(message, &recipient)
+        } else {
+            self.encrypt_message_rsa(message, public_key)
+        }
+    }
+
+    /// Encrypt message using ephemeral X25519 ECDH + HKDF-SHA256 + AES-256-GCM
+    pub fn encrypt_message_x25519(&self, message: &str, recipient: &IdentityPublicKey) -> Result<EncryptedMessage> {
+        let mut ephemeral_bytes = [0u8; 32];
+        OsRng.fill_bytes(&mut ephemeral_bytes);
+        let ephemeral_secret = StaticSecret::from(ephemeral_bytes);
+        let ephemeral_public = X25519PublicKey::from(&ephemeral_secret);
+
+        let shared_secret = ephemeral_secret.diffie_hellman(&recipient.agreement_key);
+        if !shared_secret.was_contributory() {
+            return Err(anyhow!("Invalid recipient agreement key"));
+        }
+
+        let aes_key = Self::derive_x25519_message_key(
+            shared_secret.as_bytes(),
+            ephemeral_public.as_bytes(),
+            recipient.agreement_key.as_bytes(),
+        )?;
+
+        let (ciphertext, nonce_bytes, auth_tag) = self.seal_aes_gcm(&aes_key, message.as_bytes())?;
+
+        Ok(EncryptedMessage {
+            encrypted_message: general_purpose::STANDARD.encode(&ciphertext),
+            encrypted_key: general_purpose::STANDARD.encode(ephemeral_public.as_bytes()),
+            iv: general_purpose::STANDARD.encode(&nonce_bytes),
+            auth_tag: general_purpose::STANDARD.encode(&auth_tag),
+            algorithm: ALGORITHM_X25519_HKDF.to_string(),
+        })
+    }
+
+    /// Encrypt message using hybrid RSA + AES-256-GCM encryption
+    pub fn encrypt_message_rsa(&self, message: &str, public_key_pem: &str) -> Result<EncryptedMessage> {
+        // Generate random AES key
         let mut aes_key = [0u8; AES_KEY_SIZE];
-        let mut nonce_bytes = [0u8; 12]; // GCM standard nonce size
-        self.rng.fill_bytes(&mut aes_key);
-        self.rng.fill_bytes(&mut nonce_bytes);
+        OsRng.fill_bytes(&mut aes_key);
 
-        // Encrypt message with AES-256-GCM
-        let key = Key::from_slice(&aes_key);
-        let cipher = Aes256Gcm::new(key);
-        let nonce = Nonce::from_slice(&nonce_bytes);
-        
-        let ciphertext = cipher.encrypt(nonce, message.as_bytes())
-            .map_err(|e| anyhow!("AES encryption failed: {}", e))?;
+        let (ciphertext, nonce_bytes, auth_tag) = self.seal_aes_gcm(&aes_key, message.as_bytes())?;
 
         // Encrypt AES key with RSA
         let public_key = RsaPublicKey::from_public_key_pem(public_key_pem)?;
         let padding = PaddingScheme::new_oaep::<Sha256>();
-        let encrypted_aes_key = public_key.encrypt(&mut self.rng, padding, &aes_key)?;
+        let encrypted_aes_key = public_key.encrypt(&mut OsRng, padding, &aes_key)?;
 
         Ok(EncryptedMessage {
-            encrypted_message: general_purpose::STANDARD.encode(&ciphertext[..ciphertext.len()-16]),
+            encrypted_message: general_purpose::STANDARD.encode(&ciphertext),
             encrypted_key: general_purpose::STANDARD.encode(&encrypted_aes_key),
             iv: general_purpose::STANDARD.encode(&nonce_bytes),
-            auth_tag: general_purpose::STANDARD.encode(&ciphertext[ciphertext.len()-16..]),
+            auth_tag: general_purpose::STANDARD.encode(&auth_tag),
+            algorithm: ALGORITHM_RSA_OAEP.to_string(),
         })
     }
 
-    /// Decrypt message using hybrid RSA + AES-256-GCM decryption
-    pub fn decrypt_message(&self, encrypted_data: &EncryptedMessage, private_key_pem: &str) -> Result<String> {
-        // Decrypt AES key with RSA
-        let private_key = RsaPrivateKey::from_pkcs8_pem(private_key_pem)?;
-        let padding = PaddingScheme::new_oaep::<Sha256>();
-        let encrypted_aes_key = general_purpose::STANDARD.decode(&encrypted_data.encrypted_key)?;
-        let aes_key = private_key.decrypt(padding, &encrypted_aes_key)?;
+    /// Decrypt message, dispatching on the envelope's algorithm tag
+    pub fn decrypt_message(&self, encrypted_data: &EncryptedMessage, private_key: &str) -> Result<String> {
+        let aes_key = match encrypted_data.algorithm.as_str() {
+            ALGORITHM_X25519_HKDF => {
+                let identity = IdentityKeyPair::from_private_key(private_key)?;
+                let ephemeral_bytes: [u8; 32] = general_purpose::STANDARD
+                    .decode(&encrypted_data.encrypted_key)?
+                    .try_into()
+                    .map_err(|_| anyhow!("Invalid ephemeral key length"))?;
+                let ephemeral_public = X25519PublicKey::from(ephemeral_bytes);
+                let recipient_public = X25519PublicKey::from(&identity.agreement_key);
+
+                let shared_secret = identity.agreement_key.diffie_hellman(&ephemeral_public);
+                if !shared_secret.was_contributory() {
+                    return Err(anyhow!("Invalid ephemeral agreement key"));
+                }
+
+                Self::derive_x25519_message_key(
+                    shared_secret.as_bytes(),
+                    ephemeral_public.as_bytes(),
+                    recipient_public.as_bytes(),
+                )?.to_vec()
+            }
+            ALGORITHM_RSA_OAEP => {
+                // Decrypt AES key with RSA
+                let private_key = RsaPrivateKey::from_pkcs8_pem(private_key)?;
+                let padding = PaddingScheme::new_oaep::<Sha256>();
+                let encrypted_aes_key = general_purpose::STANDARD.decode(&encrypted_data.encrypted_key)?;
+                private_key.decrypt(padding, &encrypted_aes_key)?
+            }
+            other => return Err(anyhow!("Unsupported encryption algorithm: {}", other)),
+        };
+
+        let plaintext = self.open_aes_gcm(
+            &aes_key,
+            &encrypted_data.encrypted_message,
+            &encrypted_data.iv,
+            &encrypted_data.auth_tag,
+        )?;
+
+        Ok(String::from_utf8(plaintext)?)
+    }
+
+    fn derive_x25519_message_key(shared_secret: &[u8], ephemeral_public: &[u8], recipient_public: &[u8]) -> Result<[u8; AES_KEY_SIZE]> {
+        let mut salt = Vec::with_capacity(64);
+        salt.extend_from_slice(ephemeral_public);
+        salt.extend_from_slice(recipient_public);
+
+        let hkdf = Hkdf::<Sha256>::new(Some(&salt), shared_secret);
+        let mut key = [0u8; AES_KEY_SIZE];
+        hkdf.expand(X25519_HKDF_INFO, &mut key)
+            .map_err(|_| anyhow!("HKDF expansion failed"))?;
+        Ok(key)
+    }
 
-        // Decrypt message with AES-256-GCM
-        let key = Key::from_slice(&aes_key);
+    /// AES-256-GCM seal returning (ciphertext, nonce, auth tag) as the wire format splits them
+    fn seal_aes_gcm(&self, aes_key: &[u8], plaintext: &[u8]) -> Result<(Vec<u8>, [u8; 12], Vec<u8>)> {
+        let mut nonce_bytes = [0u8; 12]; // GCM standard nonce size
+        OsRng.fill_bytes(&mut nonce_bytes);
+
+        let key = Key::from_slice(aes_key);
         let cipher = Aes256Gcm::new(key);
-        let nonce_bytes = general_purpose::STANDARD.decode(&encrypted_data.iv)?;
         let nonce = Nonce::from_slice(&nonce_bytes);
-        
-        let mut ciphertext = general_purpose::STANDARD.decode(&encrypted_data.encrypted_message)?;
-        let auth_tag = general_purpose::STANDARD.decode(&encrypted_data.auth_tag)?;
+
+        let mut ciphertext = cipher.encrypt(nonce, plaintext)
+            .map_err(|e| anyhow!("AES encryption failed: {}", e))?;
+        let auth_tag = ciphertext.split_off(ciphertext.len() - 16);
+
+        Ok((ciphertext, nonce_bytes, auth_tag))
+    }
+
+    fn open_aes_gcm(&self, aes_key: &[u8], ciphertext_b64: &str, iv_b64: &str, auth_tag_b64: &str) -> Result<Vec<u8>> {
+        let key = Key::from_slice(aes_key);
+        let cipher = Aes256Gcm::new(key);
+        let nonce_bytes = general_purpose::STANDARD.decode(iv_b64)?;
+        let nonce = Nonce::from_slice(&nonce_bytes);
+
+        let mut ciphertext = general_purpose::STANDARD.decode(ciphertext_b64)?;
+        let auth_tag = general_purpose::STANDARD.decode(auth_tag_b64)?;
         ciphertext.extend_from_slice(&auth_tag);
 
-        let plaintext = cipher.decrypt(nonce, ciphertext.as_ref())
-            .map_err(|e| anyhow!("AES decryption failed: {}", e))?;
+        cipher.decrypt(nonce, ciphertext.as_ref())
+            .map_err(|e| anyhow!("AES decryption failed: {}", e))
+    }
 
-        Ok(String::from_utf8(plaintext)?)
+    /// Derive the safety number shared by two public keys
+    pub fn compute_safety_number(&self, our_public_key: &str, their_public_key: &str) -> Result<SafetyNumber> {
+        let our_fingerprint = Self::key_fingerprint(our_public_key);
+        let their_fingerprint = Self::key_fingerprint(their_public_key);
+
+        // Sort so both parties display the same number
+        let (first, second) = if our_fingerprint <= their_fingerprint {
+            (our_fingerprint, their_fingerprint)
+        } else {
+            (their_fingerprint, our_fingerprint)
+        };
+
+        let mut groups = Vec::with_capacity(SAFETY_NUMBER_GROUPS_PER_KEY * 2);
+        for fingerprint in [&first, &second] {
+            for chunk in fingerprint.chunks(5).take(SAFETY_NUMBER_GROUPS_PER_KEY) {
+                let value = chunk.iter().fold(0u64, |acc, &b| (acc << 8) | b as u64);
+                groups.push(format!("{:05}", value % 100_000));
+            }
+        }
+        let digits = groups.join(" ");
+
+        let mut hasher = Sha256::new();
+        hasher.update(&first);
+        hasher.update(&second);
+        let word_entropy = hasher.finalize();
+        let mnemonic = Mnemonic::from_entropy(&word_entropy[..16], Language::English)?;
+        let words = mnemonic.phrase()
+            .split_whitespace()
+            .map(|s| s.to_string())
+            .collect();
+
+        let qr_payload = serde_json::to_string(&SafetyNumberQRData {
+            version: "1.0".to_string(),
+            r#type: "nonmessenger_safety_number".to_string(),
+            safety_number: groups.concat(),
+        })?;
+
+        Ok(SafetyNumber { digits, words, qr_payload })
+    }
+
+    /// Compare a scanned QR payload or typed digits against the expected safety number
+    pub fn matches_safety_number(&self, expected: &SafetyNumber, presented: &str) -> bool {
+        let presented_digits = match serde_json::from_str::<SafetyNumberQRData>(presented) {
+            Ok(qr) if qr.r#type == "nonmessenger_safety_number" => qr.safety_number,
+            Ok(_) => return false,
+            Err(_) => presented.chars().filter(|c| c.is_ascii_digit()).collect(),
+        };
+        let expected_digits: String = expected.digits.chars().filter(|c| c.is_ascii_digit()).collect();
+
+        crate::utils::Security::secure_compare(presented_digits.as_bytes(), expected_digits.as_bytes())
+    }
+
+    /// Iterated SHA-512 over the key, in the style of Signal's numeric fingerprints
+    fn key_fingerprint(public_key: &str) -> Vec<u8> {
+        let key_bytes = public_key.trim().as_bytes();
+
+        let mut hash = Vec::with_capacity(2 + key_bytes.len());
+        hash.extend_from_slice(&SAFETY_NUMBER_VERSION.to_be_bytes());
+        hash.extend_from_slice(key_bytes);
+
+        for _ in 0..SAFETY_NUMBER_ITERATIONS {
+            let mut hasher = Sha512::new();
+            hasher.update(&hash);
+            hasher.update(key_bytes);
+            hash = hasher.finalize().to_vec();
+        }
+
+        hash.truncate(SAFETY_NUMBER_GROUPS_PER_KEY * 5);
+        hash
     }
 
     /// Generate QR code data for contact sharing
@@ -238,24 +555,24 @@ impl NonMessengerCrypto {
     }
 
     /// Generate unique device ID
-    pub fn generate_device_id(&mut self) -> String {
+    pub fn generate_device_id(&self) -> String {
         let mut bytes = [0u8; 16];
-        self.rng.fill_bytes(&mut bytes);
+        OsRng.fill_bytes(&mut bytes);
         hex::encode(bytes)
     }
 
     /// Generate random AES key for voice calls
-    pub fn generate_aes_key(&mut self) -> [u8; 32] {
+    pub fn generate_aes_key(&self) -> [u8; 32] {
         let mut key = [0u8; 32];
-        self.rng.fill_bytes(&mut key);
+        OsRng.fill_bytes(&mut key);
         key
     }
 
     /// Encrypt AES key with RSA for voice call key exchange
-    pub fn encrypt_aes_key(&mut self, aes_key: &[u8], public_key_pem: &str) -> Result<String> {
+    pub fn encrypt_aes_key(&self, aes_key: &[u8], public_key_pem: &str) -> Result<String> {
         let public_key = RsaPublicKey::from_public_key_pem(public_key_pem)?;
         let padding = PaddingScheme::new_oaep::<Sha256>();
-        let encrypted = public_key.encrypt(&mut self.rng, padding, aes_key)?;
+        let encrypted = public_key.encrypt(&mut OsRng, padding, aes_key)?;
         Ok(general_purpose::STANDARD.encode(&encrypted))
     }
 
@@ -1,26 +1,60 @@
 use crate::models::*;
+use crate::db_encryption::{self, ColumnCipher, DatabaseLockState, WrappedDatabaseKey};
+use crate::keystore::{self, KeyStore};
+use crate::migrations;
+use crate::ratchet::RatchetSession;
 use anyhow::{Result, anyhow};
-use rusqlite::{Connection, params, Row};
-use std::path::PathBuf;
+use rusqlite::{Connection, OptionalExtension, params, Row};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use dirs::data_dir;
 
+/// Row id key store secrets are sealed under, so they can't be pasted into a column
+const KEY_STORE_ROW: &str = "keystore";
+
 pub struct Database {
     conn: Connection,
+    /// Present while unlocked; sensitive columns are sealed with it
+    cipher: Option<ColumnCipher>,
+    wrapped_key: Option<WrappedDatabaseKey>,
+    /// Holds the identity private key and secret words, sealed with `cipher`
+    /// when encryption is enabled; the profile row only references them
+    key_store: Arc<dyn KeyStore>,
 }
 
 impl Database {
     pub async fn new() -> Result<Self> {
         let db_path = Self::get_database_path()?;
-        
+        Self::open(&db_path, keystore::default_key_store()?).await
+    }
+
+    pub async fn open(db_path: &Path, key_store: Arc<dyn KeyStore>) -> Result<Self> {
         // Ensure directory exists
         if let Some(parent) = db_path.parent() {
             std::fs::create_dir_all(parent)?;
         }
 
-        let conn = Connection::open(&db_path)?;
-        let mut db = Self { conn };
+        let conn = Connection::open(db_path)?;
+        let mut db = Self { conn, cipher: None, wrapped_key: None, key_store };
         
-        db.initialize_tables().await?;
+        db.initialize_tables(db_path).await?;
+        db.wrapped_key = db.load_wrapped_key()?;
+
+        // Keyring-protected databases unlock without user interaction
+        if let Some(wrapped) = &db.wrapped_key {
+            if wrapped.protection == db_encryption::PROTECTION_KEYRING {
+                match ColumnCipher::unwrap(wrapped, None) {
+                    Ok(cipher) => db.cipher = Some(cipher),
+                    Err(e) => log::warn!("Could not unlock database from keyring: {}", e),
+                }
+            }
+        }
+
+        if !db.is_locked() {
+            db.move_profile_secrets_to_key_store().await?;
+            db.seal_key_store_secrets()?;
+        }
+
         Ok(db)
     }
 
@@ -32,104 +66,138 @@ impl Database {
         Ok(path)
     }
 
-    async fn initialize_tables(&mut self) -> Result<()> {
-        // Contacts table
-        self.conn.execute(
-            "CREATE TABLE IF NOT EXISTS contacts (
-                id TEXT PRIMARY KEY,
-                name TEXT NOT NULL,
-                contact_code TEXT NOT NULL,
-                public_key TEXT NOT NULL,
-                status TEXT NOT NULL DEFAULT 'offline',
-                last_seen INTEGER NOT NULL DEFAULT 0,
-                is_verified BOOLEAN NOT NULL DEFAULT 0,
-                device_id TEXT NOT NULL,
-                created_at INTEGER NOT NULL
-            )",
-            [],
-        )?;
+    async fn initialize_tables(&mut self, db_path: &Path) -> Result<()> {
+        let version = migrations::run(&mut self.conn, Some(db_path))?;
+        log::debug!("Database schema at version {}", version);
+        Ok(())
+    }
 
-        // Messages table
-        self.conn.execute(
-            "CREATE TABLE IF NOT EXISTS messages (
-                id TEXT PRIMARY KEY,
-                contact_id TEXT NOT NULL,
-                content TEXT NOT NULL,
-                is_from_me BOOLEAN NOT NULL,
-                timestamp INTEGER NOT NULL,
-                message_type TEXT NOT NULL DEFAULT 'text',
-                delivery_status TEXT NOT NULL DEFAULT 'sent',
-                encrypted_content TEXT NOT NULL DEFAULT '',
-                created_at INTEGER NOT NULL,
-                FOREIGN KEY (contact_id) REFERENCES contacts (id)
-            )",
-            [],
+    // Encryption lifecycle
+    fn load_wrapped_key(&self) -> Result<Option<WrappedDatabaseKey>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT wrapped_key FROM database_keys WHERE id = 'primary'"
         )?;
 
-        // Contact requests table
-        self.conn.execute(
-            "CREATE TABLE IF NOT EXISTS contact_requests (
-                id TEXT PRIMARY KEY,
-                sender_id TEXT NOT NULL,
-                sender_name TEXT NOT NULL,
-                public_words TEXT NOT NULL,
-                verification_message TEXT NOT NULL,
-                sender_public_key TEXT NOT NULL,
-                status TEXT NOT NULL DEFAULT 'pending',
-                received_at INTEGER NOT NULL
-            )",
-            [],
-        )?;
+        let mut key_iter = stmt.query_map([], |row| row.get::<_, String>(0))?;
 
-        // User profile table
-        self.conn.execute(
-            "CREATE TABLE IF NOT EXISTS user_profile (
-                id TEXT PRIMARY KEY,
-                contact_code TEXT NOT NULL,
-                secret_words TEXT NOT NULL,
-                public_key TEXT NOT NULL,
-                private_key TEXT NOT NULL,
-                device_id TEXT NOT NULL,
-                display_name TEXT NOT NULL DEFAULT 'Me',
-                status TEXT NOT NULL DEFAULT 'online',
-                custom_message TEXT NOT NULL DEFAULT '',
-                created_at INTEGER NOT NULL
-            )",
-            [],
-        )?;
+        match key_iter.next() {
+            Some(wrapped) => Ok(Some(serde_json::from_str(&wrapped?)?)),
+            None => Ok(None),
+        }
+    }
 
-        // Server nodes table
-        self.conn.execute(
-            "CREATE TABLE IF NOT EXISTS server_nodes (
-                url TEXT PRIMARY KEY,
-                public_key TEXT NOT NULL,
-                is_active BOOLEAN NOT NULL DEFAULT 1,
-                last_ping INTEGER NOT NULL DEFAULT 0,
-                response_time INTEGER NOT NULL DEFAULT 0,
-                priority INTEGER NOT NULL DEFAULT 0
-            )",
-            [],
-        )?;
+    pub fn get_lock_state(&self) -> DatabaseLockState {
+        DatabaseLockState {
+            encrypted: self.wrapped_key.is_some(),
+            locked: self.is_locked(),
+            protection: self.wrapped_key.as_ref().map(|w| w.protection.clone()),
+        }
+    }
 
-        // Create indexes for better performance
-        self.conn.execute(
-            "CREATE INDEX IF NOT EXISTS idx_messages_contact_id ON messages (contact_id)",
-            [],
-        )?;
+    pub fn is_locked(&self) -> bool {
+        self.wrapped_key.is_some() && self.cipher.is_none()
+    }
 
-        self.conn.execute(
-            "CREATE INDEX IF NOT EXISTS idx_messages_timestamp ON messages (timestamp)",
-            [],
-        )?;
+    pub fn wrapped_key(&self) -> Option<&WrappedDatabaseKey> {
+        self.wrapped_key.as_ref()
+    }
 
-        self.conn.execute(
-            "CREATE INDEX IF NOT EXISTS idx_contacts_status ON contacts (status)",
-            [],
+    /// Start sealing sensitive values with `cipher`, including every existing one.
+    /// `wrapped` is the key already wrapped with a passphrase, which is slow
+    /// enough to be done before taking the database lock; `None` keeps the key
+    /// in the OS keyring instead.
+    pub async fn enable_encryption(&mut self, cipher: ColumnCipher, wrapped: Option<WrappedDatabaseKey>) -> Result<()> {
+        if self.wrapped_key.is_some() {
+            return Err(anyhow!("Database encryption is already enabled"));
+        }
+
+        let wrapped = match wrapped {
+            Some(wrapped) => wrapped,
+            None => cipher.wrap_with_keyring()?,
+        };
+
+        let tx = self.conn.unchecked_transaction()?;
+        Self::reseal_column(&tx, &cipher, "messages", "id", "content", db_encryption::MESSAGES_CONTENT)?;
+        Self::reseal_column(&tx, &cipher, "user_profile", "id", "private_key", db_encryption::PROFILE_PRIVATE_KEY)?;
+        Self::reseal_column(&tx, &cipher, "user_profile", "id", "secret_words", db_encryption::PROFILE_SECRET_WORDS)?;
+        Self::reseal_column(&tx, &cipher, "sessions", "contact_id", "state", db_encryption::SESSIONS_STATE)?;
+        tx.execute(
+            "INSERT INTO database_keys (id, wrapped_key, created_at) VALUES ('primary', ?1, ?2)",
+            params![serde_json::to_string(&wrapped)?, chrono::Utc::now().timestamp()],
         )?;
+        tx.commit()?;
+
+        self.wrapped_key = Some(wrapped);
+        self.cipher = Some(cipher);
+        self.seal_key_store_secrets()?;
+        log::info!("Database column encryption enabled");
+        Ok(())
+    }
 
+    /// Unlock with the key unwrapped from `wrapped_key`
+    pub async fn unlock(&mut self, cipher: ColumnCipher) -> Result<()> {
+        if self.wrapped_key.is_none() {
+            return Err(anyhow!("Database encryption is not enabled"));
+        }
+        self.cipher = Some(cipher);
+        self.move_profile_secrets_to_key_store().await?;
+        self.seal_key_store_secrets()?;
         Ok(())
     }
 
+    /// Drop the in-memory key; sensitive columns become unreadable until unlocked
+    pub async fn lock(&mut self) -> Result<()> {
+        if self.wrapped_key.is_none() {
+            return Err(anyhow!("Database encryption is not enabled"));
+        }
+        self.cipher = None;
+        Ok(())
+    }
+
+    fn reseal_column(
+        conn: &Connection,
+        cipher: &ColumnCipher,
+        table: &str,
+        id_column: &str,
+        column: &str,
+        column_label: &str,
+    ) -> Result<()> {
+        let rows: Vec<(String, String)> = {
+            let mut stmt = conn.prepare(&format!("SELECT {}, {} FROM {}", id_column, column, table))?;
+            let row_iter = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+            row_iter.collect::<rusqlite::Result<_>>()?
+        };
+
+        for (id, value) in rows {
+            if ColumnCipher::is_encrypted(&value) {
+                continue;
+            }
+            conn.execute(
+                &format!("UPDATE {} SET {} = ?1 WHERE {} = ?2", table, column, id_column),
+                params![cipher.encrypt(column_label, &id, &value)?, id],
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn seal_column(&self, column: &str, row_id: &str, value: &str) -> Result<String> {
+        match (&self.cipher, &self.wrapped_key) {
+            (Some(cipher), _) => cipher.encrypt(column, row_id, value),
+            (None, Some(_)) => Err(anyhow!("Database is locked")),
+            (None, None) => Ok(value.to_string()),
+        }
+    }
+
+    fn open_column(&self, column: &str, row_id: &str, value: &str) -> Result<String> {
+        if !ColumnCipher::is_encrypted(value) {
+            return Ok(value.to_string());
+        }
+        self.cipher.as_ref()
+            .ok_or_else(|| anyhow!("Database is locked"))?
+            .decrypt(column, row_id, value)
+    }
+
     // Contact operations
     pub async fn get_all_contacts(&self) -> Result<Vec<Contact>> {
         let mut stmt = self.conn.prepare(
@@ -185,7 +253,43 @@ impl Database {
         }
     }
 
+    pub async fn get_contact_by_public_key(&self, public_key: &str) -> Result<Option<Contact>> {
+        let contact_id: Option<String> = self.conn.query_row(
+            "SELECT id FROM contacts WHERE public_key = ?1",
+            [public_key],
+            |row| row.get(0),
+        ).optional()?;
+
+        match contact_id {
+            Some(contact_id) => self.get_contact_by_id(&contact_id).await,
+            None => Ok(None),
+        }
+    }
+
+    /// Insert or replace a contact. A changed public key clears verification
+    /// and records a key change for the UI to flag.
     pub async fn insert_contact(&self, contact: &Contact) -> Result<()> {
+        let mut is_verified = contact.is_verified;
+
+        if let Some(existing) = self.get_contact_by_id(&contact.id).await? {
+            if existing.public_key != contact.public_key {
+                is_verified = false;
+                self.conn.execute(
+                    "INSERT INTO key_changes (contact_id, old_public_key, new_public_key, changed_at)
+                     VALUES (?1, ?2, ?3, ?4)",
+                    params![
+                        contact.id,
+                        existing.public_key,
+                        contact.public_key,
+                        chrono::Utc::now().timestamp()
+                    ],
+                )?;
+                // The ratchet session was bound to the old identity
+                self.delete_session(&contact.id).await?;
+                log::warn!("Public key changed for contact {}", contact.id);
+            }
+        }
+
         self.conn.execute(
             "INSERT OR REPLACE INTO contacts 
              (id, name, contact_code, public_key, status, last_seen, is_verified, device_id, created_at)
@@ -197,7 +301,7 @@ impl Database {
                 contact.public_key,
                 contact.status,
                 contact.last_seen,
-                contact.is_verified,
+                is_verified,
                 contact.device_id,
                 contact.created_at
             ],
@@ -215,6 +319,68 @@ impl Database {
         Ok(())
     }
 
+    /// Whether the contact may see our presence; visible unless hidden explicitly
+    pub async fn get_presence_visibility(&self, contact_id: &str) -> Result<bool> {
+        let mut stmt = self.conn.prepare("SELECT visible FROM presence_visibility WHERE contact_id = ?1")?;
+        let mut visible_iter = stmt.query_map([contact_id], |row| row.get::<_, bool>(0))?;
+
+        match visible_iter.next() {
+            Some(visible) => Ok(visible?),
+            None => Ok(true),
+        }
+    }
+
+    pub async fn set_presence_visibility(&self, contact_id: &str, visible: bool) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO presence_visibility (contact_id, visible) VALUES (?1, ?2)",
+            params![contact_id, visible],
+        )?;
+
+        Ok(())
+    }
+
+    pub async fn set_contact_verified(&self, contact_id: &str, verified: bool) -> Result<()> {
+        self.conn.execute(
+            "UPDATE contacts SET is_verified = ?1 WHERE id = ?2",
+            params![verified, contact_id],
+        )?;
+
+        Ok(())
+    }
+
+    pub async fn get_unacknowledged_key_changes(&self) -> Result<Vec<KeyChange>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT contact_id, old_public_key, new_public_key, changed_at, acknowledged
+             FROM key_changes WHERE acknowledged = 0 ORDER BY changed_at DESC"
+        )?;
+
+        let change_iter = stmt.query_map([], |row| {
+            Ok(KeyChange {
+                contact_id: row.get(0)?,
+                old_public_key: row.get(1)?,
+                new_public_key: row.get(2)?,
+                changed_at: row.get(3)?,
+                acknowledged: row.get(4)?,
+            })
+        })?;
+
+        let mut changes = Vec::new();
+        for change in change_iter {
+            changes.push(change?);
+        }
+
+        Ok(changes)
+    }
+
+    pub async fn acknowledge_key_changes(&self, contact_id: &str) -> Result<()> {
+        self.conn.execute(
+            "UPDATE key_changes SET acknowledged = 1 WHERE contact_id = ?1",
+            params![contact_id],
+        )?;
+
+        Ok(())
+    }
+
     // Message operations
     pub async fn get_messages_for_contact(&self, contact_id: &str) -> Result<Vec<Message>> {
         let mut stmt = self.conn.prepare(
@@ -238,12 +404,111 @@ impl Database {
 
         let mut messages = Vec::new();
         for message in message_iter {
-            messages.push(message?);
+            let mut message = message?;
+            message.content = self.open_column(db_encryption::MESSAGES_CONTENT, &message.id, &message.content)?;
+            messages.push(message);
         }
 
         Ok(messages)
     }
 
+    /// One row per contact with the latest message and the number of unread
+    /// incoming messages, most recent conversation first. `is_typing` is left
+    /// for the caller, which knows live awareness state.
+    pub async fn get_chat_sessions(&self) -> Result<Vec<ChatSession>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT c.id, c.name, c.status, m.id, m.content, m.timestamp,
+                    (SELECT COUNT(*) FROM messages u
+                     WHERE u.contact_id = c.id AND u.is_from_me = 0 AND u.read_at IS NULL)
+             FROM contacts c
+             LEFT JOIN messages m ON m.id = (
+                 SELECT id FROM messages WHERE contact_id = c.id
+                 ORDER BY timestamp DESC, created_at DESC LIMIT 1
+             )
+             ORDER BY COALESCE(m.timestamp, 0) DESC, c.name ASC"
+        )?;
+
+        let session_iter = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, Option<String>>(3)?,
+                row.get::<_, Option<String>>(4)?,
+                row.get::<_, Option<i64>>(5)?,
+                row.get::<_, i32>(6)?,
+            ))
+        })?;
+
+        let mut sessions = Vec::new();
+        for session in session_iter {
+            let (contact_id, contact_name, status, last_message_id, last_message, last_message_time, unread_count) = session?;
+            let last_message = match (last_message_id, last_message) {
+                (Some(id), Some(content)) => self.open_column(db_encryption::MESSAGES_CONTENT, &id, &content)?,
+                _ => String::new(),
+            };
+            sessions.push(ChatSession {
+                contact_id,
+                contact_name,
+                last_message,
+                last_message_time: last_message_time.unwrap_or(0),
+                unread_count,
+                is_typing: false,
+                is_online: status == "online",
+            });
+        }
+
+        Ok(sessions)
+    }
+
+    pub async fn get_unread_total(&self) -> Result<i64> {
+        Ok(self.conn.query_row(
+            "SELECT COUNT(*) FROM messages WHERE is_from_me = 0 AND read_at IS NULL",
+            [],
+            |row| row.get(0),
+        )?)
+    }
+
+    /// Mark unread incoming messages from `contact_id` as read, all of them or
+    /// only `message_ids`. Returns the ids that were unread.
+    pub async fn mark_messages_read(&self, contact_id: &str, message_ids: Option<&[String]>, read_at: i64) -> Result<Vec<String>> {
+        let unread = {
+            let mut stmt = self.conn.prepare(
+                "SELECT id FROM messages
+                 WHERE contact_id = ?1 AND is_from_me = 0 AND read_at IS NULL
+                 ORDER BY timestamp ASC"
+            )?;
+            let id_iter = stmt.query_map([contact_id], |row| row.get::<_, String>(0))?;
+
+            let mut unread = Vec::new();
+            for id in id_iter {
+                let id = id?;
+                if message_ids.map_or(true, |ids| ids.contains(&id)) {
+                    unread.push(id);
+                }
+            }
+            unread
+        };
+
+        for id in &unread {
+            self.conn.execute(
+                "UPDATE messages SET read_at = ?1 WHERE id = ?2",
+                params![read_at, id],
+            )?;
+        }
+
+        Ok(unread)
+    }
+
+    pub async fn message_exists(&self, message_id: &str) -> Result<bool> {
+        let count: i64 = self.conn.query_row(
+            "SELECT COUNT(*) FROM messages WHERE id = ?1",
+            [message_id],
+            |row| row.get(0),
+        )?;
+        Ok(count > 0)
+    }
+
     pub async fn insert_message(&self, message: &Message) -> Result<()> {
         self.conn.execute(
             "INSERT OR REPLACE INTO messages 
@@ -252,7 +517,7 @@ impl Database {
             params![
                 message.id,
                 message.contact_id,
-                message.content,
+                self.seal_column(db_encryption::MESSAGES_CONTENT, &message.id, &message.content)?,
                 message.is_from_me,
                 message.timestamp,
                 message.message_type,
@@ -274,6 +539,175 @@ impl Database {
         Ok(())
     }
 
+    /// Move one of our messages to `contact_id` forward to `status`; never backwards.
+    /// Returns whether the stored status changed.
+    pub async fn advance_message_status(&self, contact_id: &str, message_id: &str, status: DeliveryStatus) -> Result<bool> {
+        let current = {
+            let mut stmt = self.conn.prepare(
+                "SELECT delivery_status FROM messages WHERE id = ?1 AND contact_id = ?2 AND is_from_me = 1"
+            )?;
+            let mut status_iter = stmt.query_map(params![message_id, contact_id], |row| row.get::<_, String>(0))?;
+            status_iter.next().transpose()?
+        };
+
+        let advances = match current {
+            Some(current) => DeliveryStatus::parse(&current).map_or(true, |current| current.rank() < status.rank()),
+            None => false,
+        };
+        if advances {
+            self.update_message_status(message_id, status.as_str()).await?;
+        }
+
+        Ok(advances)
+    }
+
+    // Seen envelope operations, for envelopes that leave no message row behind
+    pub async fn has_seen_envelope(&self, envelope_id: &str) -> Result<bool> {
+        let count: i64 = self.conn.query_row(
+            "SELECT COUNT(*) FROM seen_envelopes WHERE id = ?1",
+            [envelope_id],
+            |row| row.get(0),
+        )?;
+        Ok(count > 0)
+    }
+
+    /// Remember `envelope_id`, forgetting envelopes seen before `forget_before`
+    pub async fn mark_envelope_seen(&self, envelope_id: &str, seen_at: i64, forget_before: i64) -> Result<()> {
+        self.conn.execute(
+            "DELETE FROM seen_envelopes WHERE seen_at < ?1",
+            params![forget_before],
+        )?;
+        self.conn.execute(
+            "INSERT OR IGNORE INTO seen_envelopes (id, seen_at) VALUES (?1, ?2)",
+            params![envelope_id, seen_at],
+        )?;
+
+        Ok(())
+    }
+
+    // Contact request operations
+    pub async fn insert_contact_request(&self, request: &ContactRequest) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR IGNORE INTO contact_requests 
+             (id, sender_id, sender_name, public_words, verification_message, sender_public_key, status, received_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                request.id,
+                request.sender_id,
+                request.sender_name,
+                serde_json::to_string(&request.public_words)?,
+                request.verification_message,
+                request.sender_public_key,
+                request.status,
+                request.received_at
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    pub async fn get_contact_requests(&self, status: Option<&str>) -> Result<Vec<ContactRequest>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, sender_id, sender_name, public_words, verification_message, sender_public_key, status, received_at
+             FROM contact_requests WHERE ?1 IS NULL OR status = ?1 ORDER BY received_at DESC"
+        )?;
+
+        let request_iter = stmt.query_map(params![status], Self::row_to_contact_request)?;
+
+        let mut requests = Vec::new();
+        for request in request_iter {
+            requests.push(request?);
+        }
+
+        Ok(requests)
+    }
+
+    pub async fn get_contact_request(&self, request_id: &str) -> Result<Option<ContactRequest>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, sender_id, sender_name, public_words, verification_message, sender_public_key, status, received_at
+             FROM contact_requests WHERE id = ?1"
+        )?;
+
+        let mut request_iter = stmt.query_map([request_id], Self::row_to_contact_request)?;
+
+        match request_iter.next() {
+            Some(request) => Ok(Some(request?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn update_contact_request_status(&self, request_id: &str, status: &str) -> Result<()> {
+        self.conn.execute(
+            "UPDATE contact_requests SET status = ?1 WHERE id = ?2",
+            params![status, request_id],
+        )?;
+
+        Ok(())
+    }
+
+    fn row_to_contact_request(row: &Row) -> rusqlite::Result<ContactRequest> {
+        Ok(ContactRequest {
+            id: row.get(0)?,
+            sender_id: row.get(1)?,
+            sender_name: row.get(2)?,
+            public_words: serde_json::from_str(&row.get::<_, String>(3)?).unwrap_or_default(),
+            verification_message: row.get(4)?,
+            sender_public_key: row.get(5)?,
+            status: row.get(6)?,
+            received_at: row.get(7)?,
+        })
+    }
+
+    pub async fn insert_outgoing_contact_request(&self, request: &OutgoingContactRequest) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO outgoing_contact_requests 
+             (id, recipient_name, recipient_words, verification_message, status, sent_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                request.id,
+                request.recipient_name,
+                serde_json::to_string(&request.recipient_words)?,
+                request.verification_message,
+                request.status,
+                request.sent_at
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    pub async fn get_outgoing_contact_request(&self, request_id: &str) -> Result<Option<OutgoingContactRequest>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, recipient_name, recipient_words, verification_message, status, sent_at
+             FROM outgoing_contact_requests WHERE id = ?1"
+        )?;
+
+        let mut request_iter = stmt.query_map([request_id], |row| {
+            Ok(OutgoingContactRequest {
+                id: row.get(0)?,
+                recipient_name: row.get(1)?,
+                recipient_words: serde_json::from_str(&row.get::<_, String>(2)?).unwrap_or_default(),
+                verification_message: row.get(3)?,
+                status: row.get(4)?,
+                sent_at: row.get(5)?,
+            })
+        })?;
+
+        match request_iter.next() {
+            Some(request) => Ok(Some(request?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn update_outgoing_contact_request_status(&self, request_id: &str, status: &str) -> Result<()> {
+        self.conn.execute(
+            "UPDATE outgoing_contact_requests SET status = ?1 WHERE id = ?2",
+            params![status, request_id],
+        )?;
+
+        Ok(())
+    }
+
     // User profile operations
     pub async fn get_user_profile(&self) -> Result<Option<UserProfile>> {
         let mut stmt = self.conn.prepare(
@@ -282,10 +716,11 @@ impl Database {
         )?;
 
         let mut profile_iter = stmt.query_map([], |row| {
-            Ok(UserProfile {
+            // secret_words is decoded after the column is opened below
+            Ok((UserProfile {
                 id: row.get(0)?,
                 contact_code: serde_json::from_str(&row.get::<_, String>(1)?).unwrap_or_default(),
-                secret_words: serde_json::from_str(&row.get::<_, String>(2)?).unwrap_or_default(),
+                secret_words: Vec::new(),
                 public_key: row.get(3)?,
                 private_key: row.get(4)?,
                 device_id: row.get(5)?,
@@ -293,16 +728,29 @@ impl Database {
                 status: row.get(7)?,
                 custom_message: row.get(8)?,
                 created_at: row.get(9)?,
-            })
+            }, row.get::<_, String>(2)?))
         })?;
 
         match profile_iter.next() {
-            Some(profile) => Ok(Some(profile?)),
+            Some(row) => {
+                let (mut profile, secret_words) = row?;
+                let secret_words = self.open_profile_secret(db_encryption::PROFILE_SECRET_WORDS, &profile.id, &secret_words)?;
+                profile.secret_words = serde_json::from_str(&secret_words).unwrap_or_default();
+                profile.private_key = self.open_profile_secret(db_encryption::PROFILE_PRIVATE_KEY, &profile.id, &profile.private_key)?;
+                Ok(Some(profile))
+            }
             None => Ok(None),
         }
     }
 
     pub async fn save_user_profile(&self, profile: &UserProfile) -> Result<()> {
+        if self.is_locked() {
+            return Err(anyhow!("Database is locked"));
+        }
+
+        self.store_secret(db_encryption::PROFILE_SECRET_WORDS, &serde_json::to_string(&profile.secret_words)?)?;
+        self.store_secret(db_encryption::PROFILE_PRIVATE_KEY, &profile.private_key)?;
+
         self.conn.execute(
             "INSERT OR REPLACE INTO user_profile 
              (id, contact_code, secret_words, public_key, private_key, device_id, display_name, status, custom_message, created_at)
@@ -310,9 +758,9 @@ impl Database {
             params![
                 profile.id,
                 serde_json::to_string(&profile.contact_code)?,
-                serde_json::to_string(&profile.secret_words)?,
+                keystore::reference_for(db_encryption::PROFILE_SECRET_WORDS),
                 profile.public_key,
-                profile.private_key,
+                keystore::reference_for(db_encryption::PROFILE_PRIVATE_KEY),
                 profile.device_id,
                 profile.display_name,
                 profile.status,
@@ -324,14 +772,97 @@ impl Database {
         Ok(())
     }
 
+    /// Resolve a profile secret column, which holds a key store reference or,
+    /// for profiles written before the key store existed, the (possibly sealed) secret
+    fn open_profile_secret(&self, column: &str, row_id: &str, value: &str) -> Result<String> {
+        if self.is_locked() {
+            return Err(anyhow!("Database is locked"));
+        }
+        let value = self.open_column(column, row_id, value)?;
+        match keystore::parse_reference(&value) {
+            Some(id) => {
+                let secret = self.key_store.load(id)?
+                    .ok_or_else(|| anyhow!("{} is missing from the {} key store", id, self.key_store.backend_name()))?;
+                self.open_column(id, KEY_STORE_ROW, &secret)
+            }
+            None => Ok(value),
+        }
+    }
+
+    /// Put a secret in the key store, sealed with the database key when
+    /// encryption is enabled so the passphrase protects it there too
+    fn store_secret(&self, id: &str, secret: &str) -> Result<()> {
+        self.key_store.store(id, &self.seal_column(id, KEY_STORE_ROW, secret)?)
+    }
+
+    /// Seal key store secrets written before encryption was enabled
+    fn seal_key_store_secrets(&self) -> Result<()> {
+        if self.cipher.is_none() {
+            return Ok(());
+        }
+        for id in [db_encryption::PROFILE_SECRET_WORDS, db_encryption::PROFILE_PRIVATE_KEY] {
+            if let Some(secret) = self.key_store.load(id)? {
+                if !ColumnCipher::is_encrypted(&secret) {
+                    self.store_secret(id, &secret)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Rewrite a legacy profile row so its secrets live in the key store
+    async fn move_profile_secrets_to_key_store(&self) -> Result<()> {
+        let stored_key: Option<String> = self.conn.query_row(
+            "SELECT private_key FROM user_profile WHERE id = 'user_profile'",
+            [],
+            |row| row.get(0),
+        ).ok();
+
+        let needs_move = match stored_key {
+            Some(value) => keystore::parse_reference(&self.open_column(db_encryption::PROFILE_PRIVATE_KEY, "user_profile", &value)?).is_none(),
+            None => false,
+        };
+
+        if needs_move {
+            if let Some(profile) = self.get_user_profile().await? {
+                self.save_user_profile(&profile).await?;
+                log::info!("Moved identity secrets to the {} key store", self.key_store.backend_name());
+            }
+        }
+
+        Ok(())
+    }
+
     // Server node operations
+    /// Enabled nodes, minus any whose key changed and has not been trusted yet
     pub async fn get_active_nodes(&self) -> Result<Vec<ServerNode>> {
-        let mut stmt = self.conn.prepare(
-            "SELECT url, public_key, is_active, last_ping, response_time, priority
-             FROM server_nodes WHERE is_active = 1 ORDER BY priority ASC"
-        )?;
+        self.query_server_nodes(
+            "SELECT url, public_key, is_active, last_ping, response_time, priority, pending_public_key
+             FROM server_nodes WHERE is_active = 1 AND pending_public_key IS NULL ORDER BY priority ASC",
+            params![],
+        )
+    }
 
-        let node_iter = stmt.query_map([], |row| {
+    pub async fn get_all_server_nodes(&self) -> Result<Vec<ServerNode>> {
+        self.query_server_nodes(
+            "SELECT url, public_key, is_active, last_ping, response_time, priority, pending_public_key
+             FROM server_nodes ORDER BY priority ASC, url ASC",
+            params![],
+        )
+    }
+
+    pub async fn get_server_node(&self, url: &str) -> Result<Option<ServerNode>> {
+        Ok(self.query_server_nodes(
+            "SELECT url, public_key, is_active, last_ping, response_time, priority, pending_public_key
+             FROM server_nodes WHERE url = ?1",
+            params![url],
+        )?.into_iter().next())
+    }
+
+    fn query_server_nodes(&self, sql: &str, query_params: &[&dyn rusqlite::ToSql]) -> Result<Vec<ServerNode>> {
+        let mut stmt = self.conn.prepare(sql)?;
+
+        let node_iter = stmt.query_map(query_params, |row| {
             Ok(ServerNode {
                 url: row.get(0)?,
                 public_key: row.get(1)?,
@@ -339,6 +870,7 @@ impl Database {
                 last_ping: row.get(3)?,
                 response_time: row.get(4)?,
                 priority: row.get(5)?,
+                pending_public_key: row.get(6)?,
             })
         })?;
 
@@ -353,21 +885,70 @@ impl Database {
     pub async fn insert_server_node(&self, node: &ServerNode) -> Result<()> {
         self.conn.execute(
             "INSERT OR REPLACE INTO server_nodes 
-             (url, public_key, is_active, last_ping, response_time, priority)
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+             (url, public_key, is_active, last_ping, response_time, priority, pending_public_key)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
             params![
                 node.url,
                 node.public_key,
                 node.is_active,
                 node.last_ping,
                 node.response_time,
-                node.priority
+                node.priority,
+                node.pending_public_key
             ],
         )?;
 
         Ok(())
     }
 
+    pub async fn set_server_node_active(&self, url: &str, is_active: bool) -> Result<bool> {
+        let updated = self.conn.execute(
+            "UPDATE server_nodes SET is_active = ?1 WHERE url = ?2",
+            params![is_active, url],
+        )?;
+
+        Ok(updated > 0)
+    }
+
+    pub async fn set_server_node_priority(&self, url: &str, priority: i32) -> Result<bool> {
+        let updated = self.conn.execute(
+            "UPDATE server_nodes SET priority = ?1 WHERE url = ?2",
+            params![priority, url],
+        )?;
+
+        Ok(updated > 0)
+    }
+
+    /// Pin `public_key` for a node and clear any pending key change
+    pub async fn pin_server_node_key(&self, url: &str, public_key: &str) -> Result<()> {
+        self.conn.execute(
+            "UPDATE server_nodes SET public_key = ?1, pending_public_key = NULL WHERE url = ?2",
+            params![public_key, url],
+        )?;
+
+        Ok(())
+    }
+
+    pub async fn set_server_node_pending_key(&self, url: &str, pending_public_key: &str) -> Result<()> {
+        self.conn.execute(
+            "UPDATE server_nodes SET pending_public_key = ?1 WHERE url = ?2",
+            params![pending_public_key, url],
+        )?;
+
+        Ok(())
+    }
+
+    /// Record a node the user connected to by hand, keeping existing settings
+    pub async fn ensure_server_node(&self, url: &str) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR IGNORE INTO server_nodes (url, public_key, is_active, last_ping, response_time, priority)
+             VALUES (?1, '', 1, 0, 0, 0)",
+            params![url],
+        )?;
+
+        Ok(())
+    }
+
     pub async fn update_node_ping(&self, url: &str, timestamp: i64, response_time: i64) -> Result<()> {
         self.conn.execute(
             "UPDATE server_nodes SET last_ping = ?1, response_time = ?2 WHERE url = ?3",
@@ -376,4 +957,337 @@ impl Database {
 
         Ok(())
     }
+
+    // Session operations
+    pub async fn get_session(&self, contact_id: &str) -> Result<Option<RatchetSession>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT state FROM sessions WHERE contact_id = ?1"
+        )?;
+
+        let mut session_iter = stmt.query_map([contact_id], |row| row.get::<_, String>(0))?;
+
+        match session_iter.next() {
+            Some(state) => {
+                let state = self.open_column(db_encryption::SESSIONS_STATE, contact_id, &state?)?;
+                Ok(Some(serde_json::from_str(&state)?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub async fn save_session(&self, session: &RatchetSession) -> Result<()> {
+        let now = chrono::Utc::now().timestamp();
+        self.conn.execute(
+            "INSERT INTO sessions (contact_id, state, created_at, updated_at)
+             VALUES (?1, ?2, ?3, ?3)
+             ON CONFLICT(contact_id) DO UPDATE SET state = excluded.state, updated_at = excluded.updated_at",
+            params![
+                session.contact_id,
+                self.seal_column(db_encryption::SESSIONS_STATE, &session.contact_id, &serde_json::to_string(session)?)?,
+                now
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    pub async fn delete_session(&self, contact_id: &str) -> Result<()> {
+        self.conn.execute(
+            "DELETE FROM sessions WHERE contact_id = ?1",
+            params![contact_id],
+        )?;
+
+        Ok(())
+    }
+
+    // Pending envelope operations
+    pub async fn save_pending_envelope(&self, envelope: &MessageEnvelope, timestamp: i64, received_at: i64) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR IGNORE INTO pending_envelopes (id, envelope, timestamp, received_at)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![envelope.id, serde_json::to_string(envelope)?, timestamp, received_at],
+        )?;
+
+        Ok(())
+    }
+
+    /// Every held envelope, oldest first
+    pub async fn get_pending_envelopes(&self) -> Result<Vec<PendingEnvelope>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT envelope, timestamp, received_at, attempts, last_error FROM pending_envelopes ORDER BY timestamp"
+        )?;
+
+        let rows = stmt.query_map([], |row| {
+            let envelope: String = row.get(0)?;
+            Ok(PendingEnvelope {
+                envelope: serde_json::from_str(&envelope).map_err(|e| {
+                    rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e))
+                })?,
+                timestamp: row.get(1)?,
+                received_at: row.get(2)?,
+                attempts: row.get(3)?,
+                last_error: row.get(4)?,
+            })
+        })?;
+
+        let mut pending = Vec::new();
+        for envelope in rows {
+            pending.push(envelope?);
+        }
+
+        Ok(pending)
+    }
+
+    pub async fn record_pending_envelope_failure(&self, envelope_id: &str, error: &str) -> Result<()> {
+        self.conn.execute(
+            "UPDATE pending_envelopes SET attempts = attempts + 1, last_error = ?1 WHERE id = ?2",
+            params![error, envelope_id],
+        )?;
+
+        Ok(())
+    }
+
+    pub async fn delete_pending_envelope(&self, envelope_id: &str) -> Result<()> {
+        self.conn.execute(
+            "DELETE FROM pending_envelopes WHERE id = ?1",
+            params![envelope_id],
+        )?;
+
+        Ok(())
+    }
+
+    /// Give up on envelopes received before `received_before`; returns how many
+    pub async fn expire_pending_envelopes(&self, received_before: i64) -> Result<usize> {
+        Ok(self.conn.execute(
+            "DELETE FROM pending_envelopes WHERE received_at < ?1",
+            params![received_before],
+        )?)
+    }
+
+    // Outbox operations
+    pub async fn enqueue_outbox(&self, entry: &OutboxEntry) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO outbox
+             (message_id, contact_id, recipient_contact_code, message_type, encrypted_message, timestamp,
+              status, attempts, next_attempt_at, last_error, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            params![
+                entry.message_id,
+                entry.contact_id,
+                entry.recipient_contact_code,
+                entry.message_type,
+                serde_json::to_string(&entry.encrypted_message)?,
+                entry.timestamp,
+                entry.status,
+                entry.attempts,
+                entry.next_attempt_at,
+                entry.last_error,
+                entry.created_at
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    pub async fn get_outbox_entries(&self) -> Result<Vec<OutboxEntry>> {
+        self.query_outbox("SELECT * FROM outbox ORDER BY created_at", params![])
+    }
+
+    /// Pending entries whose retry time has come, oldest first
+    /// Due entries, oldest first, minus any queued behind an older entry to the
+    /// same contact that is still waiting to be retried, so contacts receive
+    /// messages in the order they were written
+    pub async fn get_due_outbox_entries(&self, now: i64) -> Result<Vec<OutboxEntry>> {
+        self.query_outbox(
+            "SELECT * FROM outbox AS entry
+             WHERE status = 'pending' AND next_attempt_at <= ?1
+               AND NOT EXISTS (
+                   SELECT 1 FROM outbox AS earlier
+                   WHERE earlier.contact_id = entry.contact_id AND earlier.status = 'pending'
+                     AND earlier.next_attempt_at > ?1
+                     AND (earlier.created_at, earlier.rowid) < (entry.created_at, entry.rowid)
+               )
+             ORDER BY created_at, rowid",
+            params![now],
+        )
+    }
+
+    /// When the oldest entry to some contact is next due; entries behind it wait for it
+    pub async fn get_next_outbox_attempt(&self) -> Result<Option<i64>> {
+        Ok(self.conn.query_row(
+            "SELECT MIN(next_attempt_at) FROM outbox AS entry
+             WHERE status = 'pending'
+               AND NOT EXISTS (
+                   SELECT 1 FROM outbox AS earlier
+                   WHERE earlier.contact_id = entry.contact_id AND earlier.status = 'pending'
+                     AND (earlier.created_at, earlier.rowid) < (entry.created_at, entry.rowid)
+               )",
+            [],
+            |row| row.get(0),
+        )?)
+    }
+
+    pub async fn update_outbox_attempt(
+        &self,
+        message_id: &str,
+        status: &str,
+        attempts: i32,
+        next_attempt_at: i64,
+        last_error: Option<&str>,
+    ) -> Result<()> {
+        self.conn.execute(
+            "UPDATE outbox SET status = ?1, attempts = ?2, next_attempt_at = ?3, last_error = ?4 WHERE message_id = ?5",
+            params![status, attempts, next_attempt_at, last_error, message_id],
+        )?;
+
+        Ok(())
+    }
+
+    /// Make every pending entry due now, used when the connection comes back
+    pub async fn reschedule_pending_outbox(&self, now: i64) -> Result<()> {
+        self.conn.execute(
+            "UPDATE outbox SET next_attempt_at = ?1 WHERE status = 'pending'",
+            params![now],
+        )?;
+
+        Ok(())
+    }
+
+    /// Queue an entry for immediate resend with a fresh attempt budget
+    pub async fn retry_outbox_entry(&self, message_id: &str, now: i64) -> Result<bool> {
+        let updated = self.conn.execute(
+            "UPDATE outbox SET status = 'pending', attempts = 0, next_attempt_at = ?1, last_error = NULL
+             WHERE message_id = ?2",
+            params![now, message_id],
+        )?;
+
+        Ok(updated > 0)
+    }
+
+    pub async fn delete_outbox_entry(&self, message_id: &str) -> Result<()> {
+        self.conn.execute(
+            "DELETE FROM outbox WHERE message_id = ?1",
+            params![message_id],
+        )?;
+
+        Ok(())
+    }
+
+    fn query_outbox(&self, sql: &str, query_params: &[&dyn rusqlite::ToSql]) -> Result<Vec<OutboxEntry>> {
+        let mut stmt = self.conn.prepare(sql)?;
+
+        let rows = stmt.query_map(query_params, |row| {
+            let encrypted_message: String = row.get("encrypted_message")?;
+            Ok(OutboxEntry {
+                message_id: row.get("message_id")?,
+                contact_id: row.get("contact_id")?,
+                recipient_contact_code: row.get("recipient_contact_code")?,
+                message_type: row.get("message_type")?,
+                encrypted_message: serde_json::from_str(&encrypted_message).map_err(|e| {
+                    rusqlite::Error::FromSqlConversionFailure(4, rusqlite::types::Type::Text, Box::new(e))
+                })?,
+                timestamp: row.get("timestamp")?,
+                status: row.get("status")?,
+                attempts: row.get("attempts")?,
+                next_attempt_at: row.get("next_attempt_at")?,
+                last_error: row.get("last_error")?,
+                created_at: row.get("created_at")?,
+            })
+        })?;
+
+        let mut entries = Vec::new();
+        for entry in rows {
+            entries.push(entry?);
+        }
+
+        Ok(entries)
+    }
+
+    // Settings operations
+    pub async fn get_setting(&self, key: &str) -> Result<Option<String>> {
+        let mut stmt = self.conn.prepare("SELECT value FROM settings WHERE key = ?1")?;
+        let mut value_iter = stmt.query_map([key], |row| row.get::<_, String>(0))?;
+
+        match value_iter.next() {
+            Some(value) => Ok(Some(value?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn set_setting(&self, key: &str, value: &str) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO settings (key, value) VALUES (?1, ?2)",
+            params![key, value],
+        )?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keystore::MemoryKeyStore;
+    use crate::test_support::TempDatabase;
+
+    #[tokio::test]
+    async fn test_profile_secrets_live_in_key_store() {
+        let key_store = Arc::new(MemoryKeyStore::new());
+        let mut db = TempDatabase::with_key_store(key_store.clone()).await;
+
+        let profile = UserProfile {
+            id: "user_profile".to_string(),
+            contact_code: vec!["alpha".to_string(); 8],
+            secret_words: vec!["bravo".to_string(); 8],
+            public_key: "public".to_string(),
+            private_key: "identity-private-secret".to_string(),
+            device_id: "device".to_string(),
+            display_name: "Test".to_string(),
+            status: "online".to_string(),
+            custom_message: String::new(),
+            created_at: 0,
+        };
+        db.save_user_profile(&profile).await.unwrap();
+
+        let raw = std::fs::read(db.path()).unwrap();
+        assert!(!raw.windows(b"identity-private-secret".len()).any(|w| w == b"identity-private-secret"));
+        assert_eq!(key_store.load(db_encryption::PROFILE_PRIVATE_KEY).unwrap().as_deref(), Some("identity-private-secret"));
+
+        let loaded = db.get_user_profile().await.unwrap().unwrap();
+        assert_eq!(loaded.private_key, "identity-private-secret");
+        assert_eq!(loaded.secret_words, profile.secret_words);
+
+        // Locking the database also locks the secrets it refers to
+        let wrapped = WrappedDatabaseKey {
+            protection: db_encryption::PROTECTION_PASSPHRASE.to_string(),
+            kdf: None,
+            iv: String::new(),
+            wrapped_key: String::new(),
+        };
+        db.enable_encryption(ColumnCipher::new([7u8; 32]), Some(wrapped)).await.unwrap();
+        // The passphrase key seals what is already in the key store, not just new writes
+        let stored = key_store.load(db_encryption::PROFILE_PRIVATE_KEY).unwrap().unwrap();
+        assert!(ColumnCipher::is_encrypted(&stored));
+        assert!(!stored.contains("identity-private-secret"));
+        db.lock().await.unwrap();
+        assert!(db.get_user_profile().await.is_err());
+        assert!(db.save_user_profile(&profile).await.is_err());
+
+        db.unlock(ColumnCipher::new([7u8; 32])).await.unwrap();
+        assert_eq!(db.get_user_profile().await.unwrap().unwrap().private_key, "identity-private-secret");
+    }
+
+    #[tokio::test]
+    async fn test_seen_envelopes_expire() {
+        let db = TempDatabase::open().await;
+
+        assert!(!db.has_seen_envelope("r1").await.unwrap());
+        db.mark_envelope_seen("r1", 10, 0).await.unwrap();
+        assert!(db.has_seen_envelope("r1").await.unwrap());
+
+        // Marking a later envelope forgets those seen before the cutoff
+        db.mark_envelope_seen("r2", 20, 15).await.unwrap();
+        assert!(!db.has_seen_envelope("r1").await.unwrap());
+        assert!(db.has_seen_envelope("r2").await.unwrap());
+    }
 }
@@ -0,0 +1,194 @@
+use crate::key_export::{self, KdfParams};
+use crate::utils::Security;
+use aes_gcm::{Aes256Gcm, Key, Nonce, aead::{Aead, NewAead, Payload}};
+use anyhow::{Result, anyhow};
+use base64::{Engine as _, engine::general_purpose};
+use rand::{RngCore, rngs::OsRng};
+use serde::{Deserialize, Serialize};
+
+/// Prefix marking a column value sealed by `ColumnCipher`
+const ENCRYPTED_PREFIX: &str = "enc1:";
+const KEYRING_SERVICE: &str = "NonMessenger";
+const KEYRING_DATABASE_KEY: &str = "database-key";
+
+pub const PROTECTION_PASSPHRASE: &str = "passphrase";
+pub const PROTECTION_KEYRING: &str = "keyring";
+
+/// Sensitive columns sealed when encryption is enabled
+pub const MESSAGES_CONTENT: &str = "messages.content";
+pub const PROFILE_PRIVATE_KEY: &str = "user_profile.private_key";
+pub const PROFILE_SECRET_WORDS: &str = "user_profile.secret_words";
+pub const SESSIONS_STATE: &str = "sessions.state";
+
+/// How the database key is protected, stored in the `database_keys` table
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WrappedDatabaseKey {
+    pub protection: String,
+    pub kdf: Option<KdfParams>,
+    pub iv: String,
+    pub wrapped_key: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DatabaseLockState {
+    pub encrypted: bool,
+    pub locked: bool,
+    pub protection: Option<String>,
+}
+
+/// AES-256-GCM sealing of individual column values.
+///
+/// The column name and row id are bound as associated data so values cannot
+/// be swapped between columns or rows. Values without the `enc1:` prefix are
+/// legacy plaintext.
+pub struct ColumnCipher {
+    key: [u8; 32],
+}
+
+impl ColumnCipher {
+    pub fn new(key: [u8; 32]) -> Self {
+        Self { key }
+    }
+
+    pub fn generate() -> Self {
+        let mut key = [0u8; 32];
+        OsRng.fill_bytes(&mut key);
+        Self { key }
+    }
+
+    pub fn is_encrypted(value: &str) -> bool {
+        value.starts_with(ENCRYPTED_PREFIX)
+    }
+
+    fn associated_data(column: &str, row_id: &str) -> Vec<u8> {
+        [column.as_bytes(), b"\0", row_id.as_bytes()].concat()
+    }
+
+    pub fn encrypt(&self, column: &str, row_id: &str, plaintext: &str) -> Result<String> {
+        let mut nonce_bytes = [0u8; 12];
+        OsRng.fill_bytes(&mut nonce_bytes);
+
+        let cipher = Aes256Gcm::new(Key::from_slice(&self.key));
+        let ciphertext = cipher
+            .encrypt(Nonce::from_slice(&nonce_bytes), Payload { msg: plaintext.as_bytes(), aad: &Self::associated_data(column, row_id) })
+            .map_err(|e| anyhow!("Column encryption failed: {}", e))?;
+
+        let mut sealed = nonce_bytes.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        Ok(format!("{}{}", ENCRYPTED_PREFIX, general_purpose::STANDARD.encode(&sealed)))
+    }
+
+    pub fn decrypt(&self, column: &str, row_id: &str, value: &str) -> Result<String> {
+        let encoded = match value.strip_prefix(ENCRYPTED_PREFIX) {
+            Some(encoded) => encoded,
+            None => return Ok(value.to_string()),
+        };
+
+        let sealed = general_purpose::STANDARD.decode(encoded)?;
+        if sealed.len() < 12 + 16 {
+            return Err(anyhow!("Encrypted column value is truncated"));
+        }
+
+        let cipher = Aes256Gcm::new(Key::from_slice(&self.key));
+        let plaintext = cipher
+            .decrypt(Nonce::from_slice(&sealed[..12]), Payload { msg: &sealed[12..], aad: &Self::associated_data(column, row_id) })
+            .map_err(|_| anyhow!("Column decryption failed for {}", column))?;
+
+        Ok(String::from_utf8(plaintext)?)
+    }
+
+    /// Wrap this key with an unlock passphrase
+    pub fn wrap_with_passphrase(&self, passphrase: &str) -> Result<WrappedDatabaseKey> {
+        let mut salt = [0u8; 32];
+        let mut nonce_bytes = [0u8; 12];
+        OsRng.fill_bytes(&mut salt);
+        OsRng.fill_bytes(&mut nonce_bytes);
+
+        let kdf = KdfParams {
+            salt: general_purpose::STANDARD.encode(salt),
+            ..key_export::default_kdf_params()
+        };
+        let mut wrapping_key = key_export::derive_argon2_key(passphrase, &kdf)?;
+
+        let cipher = Aes256Gcm::new(Key::from_slice(&wrapping_key));
+        let wrapped = cipher
+            .encrypt(Nonce::from_slice(&nonce_bytes), self.key.as_ref())
+            .map_err(|e| anyhow!("Database key wrapping failed: {}", e));
+        Security::secure_zero(&mut wrapping_key);
+
+        Ok(WrappedDatabaseKey {
+            protection: PROTECTION_PASSPHRASE.to_string(),
+            kdf: Some(kdf),
+            iv: general_purpose::STANDARD.encode(nonce_bytes),
+            wrapped_key: general_purpose::STANDARD.encode(wrapped?),
+        })
+    }
+
+    /// Store this key in the OS keyring; the table only records that it lives there
+    pub fn wrap_with_keyring(&self) -> Result<WrappedDatabaseKey> {
+        let entry = keyring::Entry::new(KEYRING_SERVICE, KEYRING_DATABASE_KEY)?;
+        entry.set_password(&general_purpose::STANDARD.encode(self.key))?;
+
+        Ok(WrappedDatabaseKey {
+            protection: PROTECTION_KEYRING.to_string(),
+            kdf: None,
+            iv: String::new(),
+            wrapped_key: String::new(),
+        })
+    }
+
+    pub fn unwrap(wrapped: &WrappedDatabaseKey, passphrase: Option<&str>) -> Result<Self> {
+        let key_bytes = match wrapped.protection.as_str() {
+            PROTECTION_PASSPHRASE => {
+                let passphrase = passphrase.ok_or_else(|| anyhow!("Passphrase required to unlock database"))?;
+                let kdf = wrapped.kdf.as_ref().ok_or_else(|| anyhow!("Wrapped key is missing KDF parameters"))?;
+                let mut wrapping_key = key_export::derive_argon2_key(passphrase, kdf)?;
+
+                let nonce_bytes = general_purpose::STANDARD.decode(&wrapped.iv)?;
+                let sealed = general_purpose::STANDARD.decode(&wrapped.wrapped_key)?;
+                let cipher = Aes256Gcm::new(Key::from_slice(&wrapping_key));
+                let key_bytes = cipher
+                    .decrypt(Nonce::from_slice(&nonce_bytes), sealed.as_ref())
+                    .map_err(|_| anyhow!("Wrong database passphrase"));
+                Security::secure_zero(&mut wrapping_key);
+                key_bytes?
+            }
+            PROTECTION_KEYRING => {
+                let entry = keyring::Entry::new(KEYRING_SERVICE, KEYRING_DATABASE_KEY)?;
+                general_purpose::STANDARD.decode(entry.get_password()?)?
+            }
+            other => return Err(anyhow!("Unknown database key protection: {}", other)),
+        };
+
+        let key: [u8; 32] = key_bytes.try_into()
+            .map_err(|_| anyhow!("Database key has the wrong length"))?;
+        Ok(Self { key })
+    }
+}
+
+impl Drop for ColumnCipher {
+    fn drop(&mut self) {
+        Security::secure_zero(&mut self.key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_column_round_trip_and_binding() {
+        let cipher = ColumnCipher::generate();
+        let sealed = cipher.encrypt(MESSAGES_CONTENT, "msg-1", "secret text").unwrap();
+
+        assert!(ColumnCipher::is_encrypted(&sealed));
+        assert!(!sealed.contains("secret text"));
+        assert_eq!(cipher.decrypt(MESSAGES_CONTENT, "msg-1", &sealed).unwrap(), "secret text");
+
+        // Sealed values are bound to their column and row
+        assert!(cipher.decrypt(PROFILE_PRIVATE_KEY, "msg-1", &sealed).is_err());
+        assert!(cipher.decrypt(MESSAGES_CONTENT, "msg-2", &sealed).is_err());
+        // Legacy plaintext passes through
+        assert_eq!(cipher.decrypt(MESSAGES_CONTENT, "msg-1", "plain").unwrap(), "plain");
+    }
+}
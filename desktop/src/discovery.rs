@@ -0,0 +1,138 @@
+use crate::database::Database;
+use crate::models::*;
+use crate::network::MessagePoolClient;
+use crate::utils::Logger;
+use anyhow::{Result, anyhow};
+use serde::Serialize;
+use tokio::sync::Mutex;
+use url::Url;
+
+/// Priority given to nodes learned from peers; hand-added nodes default to 0
+pub const DISCOVERED_NODE_PRIORITY: i32 = 100;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum NodeMergeOutcome {
+    Added { url: String },
+    /// First key seen for a node that had none; trust on first use
+    KeyPinned { url: String },
+    Unchanged { url: String },
+    /// Advertised key differs from the pinned one; kept pending for the user
+    /// and the node is not used until they trust it
+    KeyChanged { url: String, pinned: String, advertised: String },
+}
+
+/// Canonical form of a node URL: http(s) only, no trailing slash
+pub fn normalize_node_url(url: &str) -> Result<String> {
+    let parsed = Url::parse(url.trim())?;
+    if !matches!(parsed.scheme(), "http" | "https") || parsed.host_str().is_none() {
+        return Err(anyhow!("Node URL must be an http(s) URL: {}", url));
+    }
+    Ok(parsed.as_str().trim_end_matches('/').to_string())
+}
+
+/// Merge a peer's node list into `server_nodes`, pinning keys on first use.
+///
+/// The pin is informational only: nodes sign none of their responses, so
+/// nothing a node sends is checked against its key. Pinning records which key
+/// each node advertised, so a change is noticed and surfaced, but it does not
+/// authenticate the node or its peers' node lists.
+///
+/// A changed key never replaces the pinned one; it is stored as pending and
+/// logged so the user can review it with `trust_server_node_key`. Until then
+/// the node is left out of `get_active_nodes`.
+pub async fn merge_nodes(db: &Database, advertised: &[AdvertisedNode]) -> Result<Vec<NodeMergeOutcome>> {
+    let mut outcomes = Vec::new();
+
+    for node in advertised {
+        let url = match normalize_node_url(&node.node_url) {
+            Ok(url) => url,
+            Err(e) => {
+                log::debug!("Ignoring advertised node: {}", e);
+                continue;
+            }
+        };
+        let advertised_key = node.public_key.clone().unwrap_or_default();
+
+        let outcome = match db.get_server_node(&url).await? {
+            None => {
+                db.insert_server_node(&ServerNode {
+                    url: url.clone(),
+                    public_key: advertised_key,
+                    is_active: true,
+                    last_ping: 0,
+                    response_time: 0,
+                    priority: DISCOVERED_NODE_PRIORITY,
+                    pending_public_key: None,
+                }).await?;
+                NodeMergeOutcome::Added { url }
+            }
+            Some(existing) if advertised_key.is_empty() || existing.public_key == advertised_key => {
+                NodeMergeOutcome::Unchanged { url }
+            }
+            Some(existing) if existing.public_key.is_empty() => {
+                db.pin_server_node_key(&url, &advertised_key).await?;
+                NodeMergeOutcome::KeyPinned { url }
+            }
+            Some(existing) => {
+                if existing.pending_public_key.as_deref() != Some(advertised_key.as_str()) {
+                    Logger::log_security_event(
+                        "Server node key changed",
+                        &format!("url={} pinned={} advertised={}", url, existing.public_key, advertised_key),
+                    );
+                    db.set_server_node_pending_key(&url, &advertised_key).await?;
+                }
+                NodeMergeOutcome::KeyChanged { url, pinned: existing.public_key, advertised: advertised_key }
+            }
+        };
+        outcomes.push(outcome);
+    }
+
+    Ok(outcomes)
+}
+
+/// Fetch the node list from the node we are connected to and merge it
+pub async fn sync_nodes(database: &Mutex<Database>, network: &Mutex<MessagePoolClient>) -> Result<Vec<NodeMergeOutcome>> {
+    let advertised = {
+        let network = network.lock().await;
+        let server_url = network.server_url().await
+            .ok_or_else(|| anyhow!("Not connected to server"))?;
+        network.fetch_nodes(&server_url).await?
+    };
+
+    let db = database.lock().await;
+    merge_nodes(&db, &advertised).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDatabase;
+
+    fn advertised(url: &str, key: &str) -> AdvertisedNode {
+        AdvertisedNode { node_url: url.to_string(), public_key: Some(key.to_string()), last_seen: 0 }
+    }
+
+    #[tokio::test]
+    async fn test_merge_pins_keys_on_first_use() {
+        let db = TempDatabase::open().await;
+
+        let outcomes = merge_nodes(&db, &[advertised("https://node.example/", "key-a")]).await.unwrap();
+        assert_eq!(outcomes, vec![NodeMergeOutcome::Added { url: "https://node.example".to_string() }]);
+
+        let outcomes = merge_nodes(&db, &[advertised("https://node.example", "key-b")]).await.unwrap();
+        assert!(matches!(outcomes[0], NodeMergeOutcome::KeyChanged { .. }));
+
+        // The pinned key survives; the new one waits for review
+        let node = db.get_server_node("https://node.example").await.unwrap().unwrap();
+        assert_eq!(node.public_key, "key-a");
+        assert_eq!(node.pending_public_key.as_deref(), Some("key-b"));
+        assert_eq!(node.priority, DISCOVERED_NODE_PRIORITY);
+        assert!(db.get_active_nodes().await.unwrap().is_empty());
+
+        db.pin_server_node_key("https://node.example", "key-b").await.unwrap();
+        assert_eq!(db.get_active_nodes().await.unwrap().len(), 1);
+
+        assert!(merge_nodes(&db, &[advertised("ftp://node.example", "key")]).await.unwrap().is_empty());
+    }
+}
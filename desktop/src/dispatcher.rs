@@ -0,0 +1,485 @@
+use crate::awareness::{self, AwarenessTracker};
+use crate::crypto::{IdentityKeyPair, IdentityPublicKey, NonMessengerCrypto};
+use crate::database::Database;
+use crate::handshake::{self, HandshakeEvent};
+use crate::models::*;
+use crate::network::MessagePoolClient;
+use crate::outbox::{EVENT_MESSAGE_STATUS_CHANGED, MessageStatusEvent};
+use crate::ratchet::{self, RatchetSession};
+use crate::receipts::{self, Receipt};
+use crate::voice::VoiceCallManager;
+use crate::voice_crypto::CallKey;
+use crate::AppState;
+use anyhow::{Result, anyhow};
+use serde::Serialize;
+use serde_json::Value;
+use std::sync::Arc;
+use tauri::{AppHandle, Manager};
+use tokio::sync::{Mutex, Notify, mpsc};
+use zeroize::Zeroizing;
+
+pub const EVENT_MESSAGE_RECEIVED: &str = "message-received";
+pub const EVENT_CALL_INCOMING: &str = "call-incoming";
+pub const EVENT_CALL_UPDATED: &str = "call-updated";
+pub const EVENT_PRESENCE_CHANGED: &str = "presence-changed";
+pub const EVENT_CONTACT_REQUEST: &str = "contact-request-received";
+pub const EVENT_CONTACTS_CHANGED: &str = "contacts-changed";
+pub const EVENT_TYPING_CHANGED: &str = "typing-changed";
+pub const EVENT_CHAT_SESSIONS_CHANGED: &str = "chat-sessions-changed";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct IncomingCallEvent {
+    pub call_id: String,
+    pub contact_id: String,
+    pub contact_name: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CallUpdatedEvent {
+    pub call_id: String,
+    pub state: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PresenceChangedEvent {
+    pub contact_id: String,
+    pub status: String,
+    pub last_seen: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChatSessionsChangedEvent {
+    pub unread_total: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TypingChangedEvent {
+    pub contact_id: String,
+    pub is_typing: bool,
+}
+
+/// Routes verified messages from the network client into storage, the
+/// voice manager and frontend events
+#[derive(Clone)]
+pub struct Dispatcher {
+    app: AppHandle,
+    crypto: Arc<NonMessengerCrypto>,
+    database: Arc<Mutex<Database>>,
+    network: Arc<Mutex<MessagePoolClient>>,
+    voice: Arc<Mutex<VoiceCallManager>>,
+    outbox: Arc<Notify>,
+    awareness: Arc<Mutex<AwarenessTracker>>,
+}
+
+impl Dispatcher {
+    pub fn new(app: AppHandle, state: &AppState) -> Self {
+        Self {
+            app,
+            crypto: Arc::clone(&state.crypto),
+            database: Arc::clone(&state.database),
+            network: Arc::clone(&state.network),
+            voice: Arc::clone(&state.voice),
+            outbox: Arc::clone(&state.outbox),
+            awareness: Arc::clone(&state.awareness),
+        }
+    }
+
+    pub async fn run(self, mut incoming: mpsc::UnboundedReceiver<Value>) {
+        while let Some(message) = incoming.recv().await {
+            let message_type = message["type"].as_str().unwrap_or("").to_ascii_lowercase();
+            if let Err(e) = self.dispatch(&message_type, &message).await {
+                log::warn!("Failed to process incoming {}: {}", message_type, e);
+            }
+        }
+    }
+
+    async fn dispatch(&self, message_type: &str, message: &Value) -> Result<()> {
+        match message_type {
+            "new_message" => {
+                let envelope: MessageEnvelope = serde_json::from_value(message["message"].clone())?;
+                let timestamp = message["timestamp"].as_i64().unwrap_or(envelope.timestamp);
+                let held = envelope.clone();
+                let result = self.handle_envelope(envelope).await;
+                if result.is_err() {
+                    // The server dropped its copy on delivery; the mailbox sync retries ours
+                    let now = chrono::Utc::now().timestamp();
+                    self.database.lock().await.save_pending_envelope(&held, timestamp, now).await?;
+                }
+                result
+            }
+            "voice_data" => {
+                let data: VoiceDataMessage = serde_json::from_value(message.clone())?;
+                self.voice.lock().await.receive_voice_data(&data).await
+            }
+            "voice_call_init" | "voice_call_accept" | "voice_call_reject" | "voice_call_end" => {
+                let call_message: VoiceCallMessage = serde_json::from_value(message.clone())?;
+                self.handle_call_message(message_type, call_message).await
+            }
+            "real_time_message" => {
+                let envelope: MessageEnvelope = serde_json::from_value(message["message"].clone())?;
+                self.handle_real_time(envelope).await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Process one verified envelope; duplicates of stored messages are ignored
+    pub async fn handle_envelope(&self, envelope: MessageEnvelope) -> Result<()> {
+        let db = self.database.lock().await;
+
+        match handshake::handle_envelope(&self.crypto, &db, &envelope).await? {
+            Some(HandshakeEvent::RequestReceived(request)) => {
+                self.emit(EVENT_CONTACT_REQUEST, request);
+                return Ok(());
+            }
+            Some(HandshakeEvent::ResponseReceived { contact, .. }) => {
+                if contact.is_some() {
+                    let contacts = db.get_all_contacts().await?;
+                    self.network.lock().await.set_trusted_contacts(&contacts).await;
+                    self.emit(EVENT_CONTACTS_CHANGED, contacts);
+                }
+                return Ok(());
+            }
+            None => {}
+        }
+
+        if db.message_exists(&envelope.id).await? {
+            log::debug!("Ignoring duplicate message {}", envelope.id);
+            return Ok(());
+        }
+
+        let sender_key = envelope.sender_key.as_deref()
+            .ok_or_else(|| anyhow!("Envelope has no sender key"))?;
+        let contact = db.get_contact_by_public_key(sender_key).await?
+            .ok_or_else(|| anyhow!("Envelope from unknown sender"))?;
+
+        let now = chrono::Utc::now().timestamp();
+        let is_receipt = receipts::is_receipt_type(&envelope.message_type);
+        // Receipts have no message row to dedupe against, and a second copy
+        // must not reach the ratchet
+        if is_receipt && db.has_seen_envelope(&envelope.id).await? {
+            log::debug!("Ignoring duplicate receipt {}", envelope.id);
+            return Ok(());
+        }
+
+        let content = open_message(&self.crypto, &db, &contact, &envelope.encrypted_message).await?;
+
+        if is_receipt {
+            db.mark_envelope_seen(&envelope.id, now, now - receipts::SEEN_RECEIPT_RETENTION_SECS).await?;
+            let receipt: Receipt = serde_json::from_str(&content)?;
+            for (message_id, status) in receipts::apply_receipt(&db, &contact, &receipt).await? {
+                self.emit(EVENT_MESSAGE_STATUS_CHANGED, MessageStatusEvent {
+                    message_id,
+                    contact_id: contact.id.clone(),
+                    delivery_status: status.as_str().to_string(),
+                    attempts: None,
+                });
+            }
+            // Receipts are sent automatically, even by contacts who appear
+            // invisible, so they say nothing about presence
+            return Ok(());
+        }
+
+        let message = Message {
+            id: envelope.id.clone(),
+            contact_id: contact.id.clone(),
+            content,
+            is_from_me: false,
+            timestamp: envelope.timestamp,
+            message_type: envelope.message_type.clone(),
+            delivery_status: "delivered".to_string(),
+            encrypted_content: serde_json::to_string(&envelope.encrypted_message)?,
+            created_at: now,
+        };
+
+        db.insert_message(&message).await?;
+        if let Err(e) = receipts::queue_receipt(
+            &self.crypto, &db, &self.outbox, &contact, receipts::RECEIPT_DELIVERY_STATUS, &[message.id.clone()],
+        ).await {
+            log::warn!("Failed to queue delivery receipt for {}: {}", message.id, e);
+        }
+        self.mark_online(&db, &contact, now).await?;
+        self.emit(EVENT_MESSAGE_RECEIVED, message);
+        emit_chat_sessions_changed(&self.app, &db).await;
+        Ok(())
+    }
+
+    async fn handle_call_message(&self, message_type: &str, call_message: VoiceCallMessage) -> Result<()> {
+        let now = chrono::Utc::now().timestamp();
+        let contact = {
+            let db = self.database.lock().await;
+            let sender_key = call_message.sender_key.as_deref().unwrap_or("");
+            let contact = db.get_contact_by_public_key(sender_key).await?
+                .ok_or_else(|| anyhow!("Call signaling from unknown sender"))?;
+            reject_replayed(&db, &call_message.id, now).await?;
+
+            if message_type == "voice_call_init" {
+                // Each call is offered once; the table is pruned as a whole, so
+                // call ids are kept as long as receipt ids
+                let seen_id = format!("call:{}", call_message.call_id);
+                if db.has_seen_envelope(&seen_id).await? {
+                    return Err(anyhow!("Replayed call offer {}", call_message.call_id));
+                }
+                db.mark_envelope_seen(&seen_id, now, now - receipts::SEEN_RECEIPT_RETENTION_SECS).await?;
+            }
+
+            self.mark_online(&db, &contact, now).await?;
+            contact
+        };
+
+        let mut voice = self.voice.lock().await;
+        let call_id = call_message.call_id.as_str();
+        // Only the contact on the other end may accept, reject or end a call
+        let is_current = voice.call_contact(call_id).await
+            .map(|call_contact| call_contact.id == contact.id)
+            .unwrap_or(false);
+
+        match message_type {
+            "voice_call_init" => {
+                let encryption_key = self.open_call_key(&call_message).await?;
+                voice.receive_call(call_id, &contact, encryption_key).await?;
+                self.emit(EVENT_CALL_INCOMING, IncomingCallEvent {
+                    call_id: call_id.to_string(),
+                    contact_id: contact.id,
+                    contact_name: contact.name,
+                });
+                return Ok(());
+            }
+            "voice_call_accept" if is_current => {
+                let key_share = call_message.key_share.as_deref()
+                    .ok_or_else(|| anyhow!("Call accept carries no key share"))?;
+                voice.accept_call(call_id, key_share).await?
+            }
+            "voice_call_reject" if is_current => voice.reject_call(call_id).await?,
+            "voice_call_end" if is_current => voice.end_call().await?,
+            _ => return Err(anyhow!("Signaling for unknown call {}", call_id)),
+        }
+
+        let state = message_type.trim_start_matches("voice_call_").to_string();
+        self.emit(EVENT_CALL_UPDATED, CallUpdatedEvent { call_id: call_id.to_string(), state });
+        Ok(())
+    }
+
+    /// Unseal the media key carried by a call offer
+    async fn open_call_key(&self, call_message: &VoiceCallMessage) -> Result<CallKey> {
+        let sealed = call_message.encrypted_key.as_deref()
+            .ok_or_else(|| anyhow!("Call offer carries no media key"))?;
+        let encrypted: crate::crypto::EncryptedMessage = serde_json::from_str(sealed)?;
+
+        let db = self.database.lock().await;
+        let encoded = Zeroizing::new(open_ephemeral(&self.crypto, &db, &encrypted).await?);
+        CallKey::from_base64(&encoded)
+    }
+
+    /// Encrypted awareness updates relayed directly by the server. The sender's
+    /// claimed user id is replaced by the contact its envelope key belongs to.
+    async fn handle_real_time(&self, envelope: MessageEnvelope) -> Result<()> {
+        let db = self.database.lock().await;
+        let sender_key = envelope.sender_key.as_deref()
+            .ok_or_else(|| anyhow!("Envelope has no sender key"))?;
+        let contact = db.get_contact_by_public_key(sender_key).await?
+            .ok_or_else(|| anyhow!("Real-time message from unknown sender"))?;
+        let now = chrono::Utc::now().timestamp();
+        reject_replayed(&db, &envelope.id, now).await?;
+
+        let content = open_ephemeral(&self.crypto, &db, &envelope.encrypted_message).await?;
+        let mut message: AwarenessMessage = serde_json::from_str(&content)?;
+        if !awareness::validate_awareness_message(&message) {
+            return Err(anyhow!("Invalid awareness message {}", message.r#type));
+        }
+        message.user_id = contact.id.clone();
+        self.awareness.lock().await.record(message.clone());
+
+        match message.r#type.as_str() {
+            awareness::USER_STATUS => {
+                let status = message.status.as_deref()
+                    .and_then(ContactStatus::parse)
+                    .ok_or_else(|| anyhow!("Invalid user status {:?}", message.status))?;
+                // Invisible contacts look offline to us as well
+                let status = match status {
+                    ContactStatus::Invisible => ContactStatus::Offline,
+                    status => status,
+                };
+                db.update_contact_status(&contact.id, status.as_str(), now).await?;
+                self.emit(EVENT_PRESENCE_CHANGED, PresenceChangedEvent {
+                    contact_id: contact.id,
+                    status: status.as_str().to_string(),
+                    last_seen: now,
+                });
+            }
+            awareness::LAST_SEEN => {
+                // Peer clocks are not trusted to move last_seen into the future
+                let last_seen = (message.last_seen.unwrap_or(message.timestamp) / 1000).min(now);
+                if last_seen > contact.last_seen {
+                    db.update_contact_status(&contact.id, &contact.status, last_seen).await?;
+                    self.emit(EVENT_PRESENCE_CHANGED, PresenceChangedEvent {
+                        contact_id: contact.id,
+                        status: contact.status,
+                        last_seen,
+                    });
+                }
+            }
+            awareness::TYPING_INDICATOR => {
+                let is_typing = awareness::should_show_typing_indicator(
+                    &message,
+                    awareness::now_millis(),
+                    awareness::TYPING_INDICATOR_MAX_AGE_MS,
+                );
+                self.emit(EVENT_TYPING_CHANGED, TypingChangedEvent { contact_id: contact.id, is_typing });
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    async fn mark_online(&self, db: &Database, contact: &Contact, now: i64) -> Result<()> {
+        db.update_contact_status(&contact.id, "online", now).await?;
+        if contact.status != "online" {
+            self.emit(EVENT_PRESENCE_CHANGED, PresenceChangedEvent {
+                contact_id: contact.id.clone(),
+                status: "online".to_string(),
+                last_seen: now,
+            });
+        }
+        Ok(())
+    }
+
+    fn emit<S: Serialize + Clone>(&self, event: &str, payload: S) {
+        if let Err(e) = self.app.emit_all(event, payload) {
+            log::warn!("Failed to emit {}: {}", event, e);
+        }
+    }
+}
+
+/// Tell the frontend the chat list changed and put the unread total on the tray
+pub async fn emit_chat_sessions_changed(app: &AppHandle, db: &Database) {
+    let unread_total = match db.get_unread_total().await {
+        Ok(unread_total) => unread_total,
+        Err(e) => {
+            log::warn!("Failed to count unread messages: {}", e);
+            return;
+        }
+    };
+
+    let tooltip = match unread_total {
+        0 => "NonMessenger".to_string(),
+        n => format!("NonMessenger ({} unread)", n),
+    };
+    if let Err(e) = app.tray_handle().set_tooltip(&tooltip) {
+        log::debug!("Failed to update tray tooltip: {}", e);
+    }
+    if let Err(e) = app.emit_all(EVENT_CHAT_SESSIONS_CHANGED, ChatSessionsChangedEvent { unread_total }) {
+        log::warn!("Failed to emit {}: {}", EVENT_CHAT_SESSIONS_CHANGED, e);
+    }
+}
+
+/// Decrypt a message from `contact` with our profile key, or with the ratchet
+/// session for ratchet messages. A first ratchet message starts a new session.
+pub async fn open_message(
+    crypto: &NonMessengerCrypto,
+    db: &Database,
+    contact: &Contact,
+    encrypted: &crate::crypto::EncryptedMessage,
+) -> Result<String> {
+    let profile = db.get_user_profile().await?
+        .ok_or_else(|| anyhow!("No user profile found"))?;
+
+    if encrypted.algorithm != ratchet::ALGORITHM_DOUBLE_RATCHET {
+        return crypto.decrypt_message(encrypted, &profile.private_key);
+    }
+
+    let our_identity = IdentityKeyPair::from_private_key(&profile.private_key)?;
+    let their_identity = IdentityPublicKey::from_public_key(&contact.public_key)?;
+    let stored = db.get_session(&contact.id).await?;
+    let (plaintext, session) = RatchetSession::open(stored, &contact.id, &our_identity, &their_identity, encrypted)?;
+    if let Some(session) = session {
+        db.save_session(&session).await?;
+    }
+    Ok(plaintext)
+}
+
+/// Envelope bytes besides the ciphertext, with room to spare: ids, contact
+/// code, sender key, signature, and the ratchet header or RSA-wrapped key
+const RATCHET_ENVELOPE_OVERHEAD: usize = 896;
+const RSA_ENVELOPE_OVERHEAD: usize = 1408;
+
+/// Largest plaintext whose envelope to the holder of `public_key` the server will accept
+pub fn max_content_bytes(public_key: &str) -> usize {
+    let overhead = if crate::crypto::is_identity_key(public_key) {
+        RATCHET_ENVELOPE_OVERHEAD
+    } else {
+        RSA_ENVELOPE_OVERHEAD
+    };
+    // The ciphertext is as long as the plaintext, then base64 encoded
+    (crate::network::SERVER_MAX_ENVELOPE_BYTES - overhead) / 4 * 3
+}
+
+/// Encrypt a message for `contact`. Identity-key contacts get a ratchet session,
+/// started on first use; legacy RSA contacts fall back to the profile key scheme.
+pub async fn seal_message(
+    crypto: &NonMessengerCrypto,
+    db: &Database,
+    contact: &Contact,
+    plaintext: &str,
+) -> Result<crate::crypto::EncryptedMessage> {
+    // Checked before sealing: a message the server turns away must not use up a ratchet key
+    let limit = max_content_bytes(&contact.public_key);
+    if plaintext.len() > limit {
+        return Err(anyhow!("Message too large: {} bytes. Maximum allowed: {} bytes.", plaintext.len(), limit));
+    }
+
+    let profile = db.get_user_profile().await?
+        .ok_or_else(|| anyhow!("No user profile found"))?;
+
+    let identities = (
+        IdentityKeyPair::from_private_key(&profile.private_key),
+        IdentityPublicKey::from_public_key(&contact.public_key),
+    );
+    let (our_identity, their_identity) = match identities {
+        (Ok(ours), Ok(theirs)) => (ours, theirs),
+        _ => return crypto.encrypt_message(plaintext, &contact.public_key),
+    };
+
+    let mut session = match db.get_session(&contact.id).await? {
+        Some(session) => session,
+        None => RatchetSession::initiate(&contact.id, &our_identity, &their_identity)?,
+    };
+    let encrypted = session.encrypt(plaintext)?;
+    db.save_session(&session).await?;
+    Ok(encrypted)
+}
+
+/// Refuse a signed message whose id was already processed. Real-time and call
+/// signaling older than `network::SIGNALING_WINDOW_SECS` never gets this far,
+/// so ids only need to outlive that window.
+async fn reject_replayed(db: &Database, message_id: &str, now: i64) -> Result<()> {
+    if db.has_seen_envelope(message_id).await? {
+        return Err(anyhow!("Replayed message {}", message_id));
+    }
+    db.mark_envelope_seen(message_id, now, now - receipts::SEEN_RECEIPT_RETENTION_SECS).await
+}
+
+/// Encrypt short-lived traffic such as typing indicators for `contact` with
+/// the profile key scheme. The server drops real-time messages for offline
+/// recipients without telling us, so they must never advance the ratchet.
+pub fn seal_ephemeral(
+    crypto: &NonMessengerCrypto,
+    contact: &Contact,
+    plaintext: &str,
+) -> Result<crate::crypto::EncryptedMessage> {
+    crypto.encrypt_message(plaintext, &contact.public_key)
+}
+
+/// Decrypt traffic sealed with `seal_ephemeral`
+pub async fn open_ephemeral(
+    crypto: &NonMessengerCrypto,
+    db: &Database,
+    encrypted: &crate::crypto::EncryptedMessage,
+) -> Result<String> {
+    if encrypted.algorithm == ratchet::ALGORITHM_DOUBLE_RATCHET {
+        return Err(anyhow!("Ephemeral messages must not use the ratchet"));
+    }
+    let profile = db.get_user_profile().await?
+        .ok_or_else(|| anyhow!("No user profile found"))?;
+    crypto.decrypt_message(encrypted, &profile.private_key)
+}
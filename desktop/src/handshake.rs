@@ -0,0 +1,345 @@
+use crate::crypto::{EncryptedMessage, NonMessengerCrypto};
+use crate::database::Database;
+use crate::models::*;
+use crate::signing;
+use crate::utils::{Logger, Validator};
+use anyhow::{Result, anyhow};
+
+const CONTACT_REQUEST_TTL: i64 = 7 * 86400000; // 7 days
+
+/// Outcome of processing an incoming handshake envelope
+pub enum HandshakeEvent {
+    RequestReceived(ContactRequest),
+    ResponseReceived { request_id: String, contact: Option<Contact> },
+}
+
+/// Build a signed contact request addressed to the owner of `recipient_words`.
+///
+/// The request is encrypted to the contact key derived from the recipient's
+/// 8 public words, so only someone who knows those words can read it.
+pub fn build_contact_request(
+    crypto: &NonMessengerCrypto,
+    profile: &UserProfile,
+    recipient_words: &[String],
+    verification_message: &str,
+) -> Result<(ContactRequestMessage, MessageEnvelope)> {
+    if !Validator::validate_contact_code(recipient_words) {
+        return Err(anyhow!("Contact code must be exactly 8 words"));
+    }
+    if !crypto.validate_contact_message(verification_message) {
+        return Err(anyhow!("Verification message must be exactly 256 characters"));
+    }
+
+    let recipient_contact_key = crypto.generate_contact_key_pair(recipient_words)?;
+
+    let mut request = ContactRequestMessage {
+        r#type: "contact_request".to_string(),
+        id: uuid::Uuid::new_v4().to_string(),
+        timestamp: chrono::Utc::now().timestamp(),
+        sender_id: profile.device_id.clone(),
+        sender_name: profile.display_name.clone(),
+        public_words: profile.contact_code.clone(),
+        verification_message: verification_message.to_string(),
+        sender_public_key: profile.public_key.clone(),
+        version: "1.0".to_string(),
+        signature: None,
+    };
+    signing::sign_envelope(&mut request, &profile.private_key)?;
+
+    let encrypted = crypto.encrypt_message(
+        &serde_json::to_string(&request)?,
+        &recipient_contact_key.public_key,
+    )?;
+    let envelope = seal_envelope(
+        profile,
+        &recipient_words.join(" "),
+        "contact_request",
+        encrypted,
+    )?;
+
+    Ok((request, envelope))
+}
+
+/// Build the signed answer to a received request. Accepting shares our 8 secret
+/// words so the requester can derive our full key.
+pub fn build_contact_response(
+    crypto: &NonMessengerCrypto,
+    profile: &UserProfile,
+    request: &ContactRequest,
+    accepted: bool,
+) -> Result<MessageEnvelope> {
+    let mut response = ContactResponseMessage {
+        r#type: "contact_response".to_string(),
+        id: uuid::Uuid::new_v4().to_string(),
+        timestamp: chrono::Utc::now().timestamp(),
+        original_request_id: request.id.clone(),
+        accepted,
+        secret_words: if accepted { Some(profile.secret_words.clone()) } else { None },
+        recipient_public_key: Some(profile.public_key.clone()),
+        recipient_device_id: Some(profile.device_id.clone()),
+        version: "1.0".to_string(),
+        signature: None,
+    };
+    signing::sign_envelope(&mut response, &profile.private_key)?;
+
+    let encrypted = crypto.encrypt_message(
+        &serde_json::to_string(&response)?,
+        &request.sender_public_key,
+    )?;
+
+    seal_envelope(
+        profile,
+        &request.public_words.join(" "),
+        "contact_response",
+        encrypted,
+    )
+}
+
+/// Process an incoming `contact_request` or `contact_response` envelope.
+/// Returns `None` for any other message type.
+pub async fn handle_envelope(
+    crypto: &NonMessengerCrypto,
+    db: &Database,
+    envelope: &MessageEnvelope,
+) -> Result<Option<HandshakeEvent>> {
+    match envelope.message_type.as_str() {
+        "contact_request" => {
+            let profile = db.get_user_profile().await?
+                .ok_or_else(|| anyhow!("No user profile found"))?;
+            let request = open_contact_request(crypto, &profile, envelope)?;
+            db.insert_contact_request(&request).await?;
+
+            log::info!("Contact request received from {}", request.sender_name);
+            Ok(Some(HandshakeEvent::RequestReceived(request)))
+        }
+        "contact_response" => {
+            let profile = db.get_user_profile().await?
+                .ok_or_else(|| anyhow!("No user profile found"))?;
+            let response = open_contact_response(crypto, &profile, envelope)?;
+
+            let outgoing = db.get_outgoing_contact_request(&response.original_request_id).await?
+                .ok_or_else(|| anyhow!("Response to unknown contact request"))?;
+            if outgoing.status != "pending" {
+                return Err(anyhow!("Contact request {} already answered", outgoing.id));
+            }
+
+            if !response.accepted {
+                db.update_outgoing_contact_request_status(&outgoing.id, "rejected").await?;
+                return Ok(Some(HandshakeEvent::ResponseReceived {
+                    request_id: outgoing.id,
+                    contact: None,
+                }));
+            }
+
+            let contact = upgrade_to_full_key(crypto, &outgoing, &response)?;
+            db.insert_contact(&contact).await?;
+            db.update_outgoing_contact_request_status(&outgoing.id, "accepted").await?;
+
+            log::info!("Contact request {} accepted", outgoing.id);
+            Ok(Some(HandshakeEvent::ResponseReceived {
+                request_id: outgoing.id,
+                contact: Some(contact),
+            }))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Turn an accepted request into a stored contact on the accepting side
+pub fn contact_from_request(request: &ContactRequest) -> Contact {
+    Contact {
+        id: uuid::Uuid::new_v4().to_string(),
+        name: request.sender_name.clone(),
+        contact_code: request.public_words.clone(),
+        public_key: request.sender_public_key.clone(),
+        status: "offline".to_string(),
+        last_seen: 0,
+        is_verified: false,
+        device_id: request.sender_id.clone(),
+        created_at: chrono::Utc::now().timestamp(),
+    }
+}
+
+fn open_contact_request(
+    crypto: &NonMessengerCrypto,
+    profile: &UserProfile,
+    envelope: &MessageEnvelope,
+) -> Result<ContactRequest> {
+    // Requests are encrypted to the key derived from our public words
+    let contact_key = crypto.generate_contact_key_pair(&profile.contact_code)?;
+    let plaintext = crypto.decrypt_message(&envelope.encrypted_message, &contact_key.private_key)?;
+    let request: ContactRequestMessage = serde_json::from_str(&plaintext)?;
+
+    signing::verify_envelope(&request)?;
+    if envelope.sender_key.as_deref() != Some(request.sender_public_key.as_str()) {
+        Logger::log_security_event(
+            "Contact request key mismatch",
+            &format!("request={}", request.id),
+        );
+        return Err(anyhow!("Contact request signed by a different key than its envelope"));
+    }
+    if !crypto.validate_contact_message(&request.verification_message) {
+        return Err(anyhow!("Contact request has an invalid verification message"));
+    }
+    if !Validator::validate_contact_code(&request.public_words) {
+        return Err(anyhow!("Contact request has an invalid contact code"));
+    }
+
+    Ok(ContactRequest {
+        id: request.id,
+        sender_id: request.sender_id,
+        sender_name: request.sender_name,
+        public_words: request.public_words,
+        verification_message: request.verification_message,
+        sender_public_key: request.sender_public_key,
+        status: "pending".to_string(),
+        received_at: chrono::Utc::now().timestamp(),
+    })
+}
+
+fn open_contact_response(
+    crypto: &NonMessengerCrypto,
+    profile: &UserProfile,
+    envelope: &MessageEnvelope,
+) -> Result<ContactResponseMessage> {
+    let plaintext = crypto.decrypt_message(&envelope.encrypted_message, &profile.private_key)?;
+    let response: ContactResponseMessage = serde_json::from_str(&plaintext)?;
+
+    signing::verify_envelope(&response)?;
+    if envelope.sender_key != response.recipient_public_key {
+        Logger::log_security_event(
+            "Contact response key mismatch",
+            &format!("request={}", response.original_request_id),
+        );
+        return Err(anyhow!("Contact response signed by a different key than its envelope"));
+    }
+
+    Ok(response)
+}
+
+/// Derive the responder's full key from their 8 public + 8 secret words and
+/// check it matches the key that signed the response.
+fn upgrade_to_full_key(
+    crypto: &NonMessengerCrypto,
+    outgoing: &OutgoingContactRequest,
+    response: &ContactResponseMessage,
+) -> Result<Contact> {
+    let secret_words = response.secret_words.as_ref()
+        .ok_or_else(|| anyhow!("Accepted response is missing secret words"))?;
+    if !Validator::validate_secret_words(secret_words) {
+        return Err(anyhow!("Accepted response has invalid secret words"));
+    }
+
+    let mut full_words = outgoing.recipient_words.clone();
+    full_words.extend(secret_words.iter().cloned());
+    let full_key = crypto.generate_full_key_pair(&full_words)?;
+
+    if response.recipient_public_key.as_deref() != Some(full_key.public_key.as_str()) {
+        Logger::log_security_event(
+            "Contact response key does not match secret words",
+            &format!("request={}", outgoing.id),
+        );
+        return Err(anyhow!("Responder's key does not match their contact words"));
+    }
+
+    Ok(Contact {
+        id: uuid::Uuid::new_v4().to_string(),
+        name: outgoing.recipient_name.clone(),
+        contact_code: outgoing.recipient_words.clone(),
+        public_key: full_key.public_key,
+        status: "offline".to_string(),
+        last_seen: 0,
+        is_verified: false,
+        device_id: response.recipient_device_id.clone().unwrap_or_default(),
+        created_at: chrono::Utc::now().timestamp(),
+    })
+}
+
+fn seal_envelope(
+    profile: &UserProfile,
+    recipient_contact_code: &str,
+    message_type: &str,
+    encrypted_message: EncryptedMessage,
+) -> Result<MessageEnvelope> {
+    let mut envelope = MessageEnvelope {
+        id: uuid::Uuid::new_v4().to_string(),
+        recipient_contact_code: recipient_contact_code.to_string(),
+        encrypted_message,
+        timestamp: chrono::Utc::now().timestamp(),
+        ttl: CONTACT_REQUEST_TTL,
+        message_type: message_type.to_string(),
+        sender_key: Some(profile.public_key.clone()),
+        signature: None,
+    };
+    signing::sign_envelope(&mut envelope, &profile.private_key)?;
+    Ok(envelope)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile_from_words(crypto: &NonMessengerCrypto, name: &str) -> UserProfile {
+        let contact_code = crypto.generate_8_word_contact_code().unwrap();
+        let secret_words = crypto.generate_8_word_secret_code().unwrap();
+        let mut full_words = contact_code.clone();
+        full_words.extend(secret_words.clone());
+        let key_pair = crypto.generate_full_key_pair(&full_words).unwrap();
+
+        UserProfile {
+            id: "user_profile".to_string(),
+            contact_code,
+            secret_words,
+            public_key: key_pair.public_key,
+            private_key: key_pair.private_key,
+            device_id: crypto.generate_device_id(),
+            display_name: name.to_string(),
+            status: "online".to_string(),
+            custom_message: String::new(),
+            created_at: 0,
+        }
+    }
+
+    #[test]
+    fn test_contact_handshake_round_trip() {
+        let crypto = NonMessengerCrypto::new();
+        let alice = profile_from_words(&crypto, "Alice");
+        let bob = profile_from_words(&crypto, "Bob");
+
+        let verification = "v".repeat(256);
+        let (request_message, envelope) =
+            build_contact_request(&crypto, &alice, &bob.contact_code, &verification).unwrap();
+        assert_eq!(envelope.recipient_contact_code, bob.get_public_contact_string());
+
+        let request = open_contact_request(&crypto, &bob, &envelope).unwrap();
+        assert_eq!(request.id, request_message.id);
+        assert_eq!(request.sender_public_key, alice.public_key);
+
+        let response_envelope = build_contact_response(&crypto, &bob, &request, true).unwrap();
+        let response = open_contact_response(&crypto, &alice, &response_envelope).unwrap();
+
+        let outgoing = OutgoingContactRequest {
+            id: request.id.clone(),
+            recipient_name: "Bob".to_string(),
+            recipient_words: bob.contact_code.clone(),
+            verification_message: verification,
+            status: "pending".to_string(),
+            sent_at: 0,
+        };
+        let contact = upgrade_to_full_key(&crypto, &outgoing, &response).unwrap();
+        assert_eq!(contact.public_key, bob.public_key);
+        assert_eq!(contact.device_id, bob.device_id);
+    }
+
+    #[test]
+    fn test_contact_request_rejects_wrong_recipient() {
+        let crypto = NonMessengerCrypto::new();
+        let alice = profile_from_words(&crypto, "Alice");
+        let bob = profile_from_words(&crypto, "Bob");
+        let carol = profile_from_words(&crypto, "Carol");
+
+        let (_, envelope) =
+            build_contact_request(&crypto, &alice, &bob.contact_code, &"v".repeat(256)).unwrap();
+        assert!(open_contact_request(&crypto, &carol, &envelope).is_err());
+    }
+}
@@ -0,0 +1,223 @@
+use crate::models::CallAudioStats;
+use crate::voice_codec::FRAME_DURATION_MS;
+use std::collections::BTreeMap;
+
+/// Bounds of the adaptive playout delay, in frames
+const MIN_TARGET_FRAMES: usize = 2;
+const MAX_TARGET_FRAMES: usize = 15;
+
+/// Frames beyond the target before the oldest are dropped to catch up with
+/// a sender whose clock runs fast
+const DRIFT_MARGIN_FRAMES: usize = 3;
+
+/// Consecutive concealed frames before playout stops and rebuffers
+const MAX_CONCEALED_FRAMES: usize = 5;
+
+/// What to play for the next 20 ms
+#[derive(Debug, PartialEq)]
+pub enum PlayoutFrame {
+    Packet(Vec<u8>),
+    /// The frame was lost; rebuild it from the FEC data in the next packet
+    Recover(Vec<u8>),
+    /// The frame was lost with nothing to rebuild it from
+    Conceal,
+    /// Buffering; nothing to play yet
+    Silence,
+}
+
+/// Reorders received voice packets by sequence number and releases one per
+/// frame period, holding back enough to ride out the measured network jitter.
+#[derive(Debug, Default)]
+pub struct JitterBuffer {
+    frames: BTreeMap<i32, Vec<u8>>,
+    /// Sequence number of the next frame to play, once playout has started
+    next_sequence: Option<i32>,
+    playing: bool,
+    concealed_run: usize,
+    /// RFC 3550 interarrival jitter, in milliseconds
+    jitter_ms: f64,
+    last_transit_ms: Option<i64>,
+    first_sequence: Option<i32>,
+    highest_sequence: Option<i32>,
+    received: u64,
+    late: u64,
+    concealed: u64,
+}
+
+impl JitterBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue a packet that arrived at `arrival_ms`. Returns false when it
+    /// came too late to be played or was already queued.
+    pub fn push(&mut self, sequence_number: i32, packet: Vec<u8>, arrival_ms: i64) -> bool {
+        self.update_jitter(sequence_number, arrival_ms);
+        self.first_sequence = Some(self.first_sequence.map_or(sequence_number, |first| first.min(sequence_number)));
+        self.highest_sequence = Some(self.highest_sequence.map_or(sequence_number, |highest| highest.max(sequence_number)));
+
+        if self.next_sequence.map_or(false, |next| sequence_number < next) {
+            self.late += 1;
+            return false;
+        }
+        if self.frames.contains_key(&sequence_number) {
+            return false;
+        }
+
+        self.frames.insert(sequence_number, packet);
+        self.received += 1;
+        true
+    }
+
+    fn update_jitter(&mut self, sequence_number: i32, arrival_ms: i64) {
+        let transit = arrival_ms - sequence_number as i64 * FRAME_DURATION_MS as i64;
+        if let Some(last_transit) = self.last_transit_ms {
+            let deviation = (transit - last_transit).abs() as f64;
+            self.jitter_ms += (deviation - self.jitter_ms) / 16.0;
+        }
+        self.last_transit_ms = Some(transit);
+    }
+
+    /// Playout delay the buffer currently aims for, in frames
+    pub fn target_frames(&self) -> usize {
+        let target_ms = FRAME_DURATION_MS as f64 + 3.0 * self.jitter_ms;
+        let frames = (target_ms / FRAME_DURATION_MS as f64).ceil() as usize;
+        frames.clamp(MIN_TARGET_FRAMES, MAX_TARGET_FRAMES)
+    }
+
+    /// Take the next frame period's worth of audio
+    pub fn pop(&mut self) -> PlayoutFrame {
+        if !self.playing {
+            if self.frames.len() < self.target_frames() {
+                return PlayoutFrame::Silence;
+            }
+            self.playing = true;
+            self.concealed_run = 0;
+            self.next_sequence = self.frames.keys().next().copied();
+        }
+
+        // Skip ahead if the queue has grown past the target
+        while self.frames.len() > self.target_frames() + DRIFT_MARGIN_FRAMES {
+            if let Some((sequence, _)) = self.frames.pop_first() {
+                self.next_sequence = Some(sequence.wrapping_add(1));
+            }
+        }
+
+        let sequence = match self.next_sequence {
+            Some(sequence) => sequence,
+            None => return PlayoutFrame::Silence,
+        };
+
+        if let Some(packet) = self.frames.remove(&sequence) {
+            self.next_sequence = Some(sequence.wrapping_add(1));
+            self.concealed_run = 0;
+            return PlayoutFrame::Packet(packet);
+        }
+
+        if self.frames.is_empty() && self.concealed_run >= MAX_CONCEALED_FRAMES {
+            self.playing = false;
+            return PlayoutFrame::Silence;
+        }
+
+        self.next_sequence = Some(sequence.wrapping_add(1));
+        self.concealed_run += 1;
+        self.concealed += 1;
+        match self.frames.get(&sequence.wrapping_add(1)) {
+            Some(next_packet) => PlayoutFrame::Recover(next_packet.clone()),
+            None => PlayoutFrame::Conceal,
+        }
+    }
+
+    pub fn stats(&self) -> CallAudioStats {
+        let expected = match (self.first_sequence, self.highest_sequence) {
+            (Some(first), Some(highest)) => (highest as i64 - first as i64 + 1) as u64,
+            _ => 0,
+        };
+        // Late packets were never played, so they count as lost
+        let played_or_queued = self.received.min(expected);
+        let loss_percent = if expected == 0 {
+            0.0
+        } else {
+            (expected - played_or_queued) as f64 * 100.0 / expected as f64
+        };
+
+        CallAudioStats {
+            packets_received: self.received + self.late,
+            packets_late: self.late,
+            frames_concealed: self.concealed,
+            loss_percent,
+            jitter_ms: self.jitter_ms,
+            buffer_depth_ms: (self.frames.len() as u32 * FRAME_DURATION_MS) as i64,
+            target_delay_ms: (self.target_frames() as u32 * FRAME_DURATION_MS) as i64,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(sequence: i32) -> Vec<u8> {
+        vec![sequence as u8]
+    }
+
+    #[test]
+    fn test_reorders_recovers_and_discards_late_packets() {
+        let mut buffer = JitterBuffer::new();
+
+        // Arrives out of order, with 2 missing
+        for sequence in [1, 0, 3, 4] {
+            assert!(buffer.push(sequence, packet(sequence), 1_000 + sequence as i64 * 20));
+        }
+        assert!(!buffer.push(3, packet(3), 1_080));
+
+        assert_eq!(buffer.pop(), PlayoutFrame::Packet(packet(0)));
+        assert_eq!(buffer.pop(), PlayoutFrame::Packet(packet(1)));
+        assert_eq!(buffer.pop(), PlayoutFrame::Recover(packet(3)));
+        assert_eq!(buffer.pop(), PlayoutFrame::Packet(packet(3)));
+
+        // Frame 2 turning up now is too late to play
+        assert!(!buffer.push(2, packet(2), 1_200));
+        assert_eq!(buffer.pop(), PlayoutFrame::Packet(packet(4)));
+        assert_eq!(buffer.pop(), PlayoutFrame::Conceal);
+
+        let stats = buffer.stats();
+        assert_eq!(stats.packets_late, 1);
+        assert_eq!(stats.frames_concealed, 2);
+        assert!((stats.loss_percent - 20.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_target_delay_grows_with_jitter() {
+        let mut steady = JitterBuffer::new();
+        for sequence in 0..50 {
+            steady.push(sequence, packet(sequence), sequence as i64 * 20);
+        }
+        assert_eq!(steady.target_frames(), MIN_TARGET_FRAMES);
+
+        let mut bursty = JitterBuffer::new();
+        for sequence in 0..50 {
+            // Packets arrive in bursts of five every 100 ms
+            bursty.push(sequence, packet(sequence), (sequence as i64 / 5) * 100);
+        }
+        assert!(bursty.target_frames() > MIN_TARGET_FRAMES);
+
+        // A backlog beyond the target is skipped rather than played late
+        assert!(matches!(bursty.pop(), PlayoutFrame::Packet(_)));
+        let max_depth = (bursty.target_frames() + DRIFT_MARGIN_FRAMES) as i64 * FRAME_DURATION_MS as i64;
+        assert!(bursty.stats().buffer_depth_ms < max_depth);
+    }
+
+    #[test]
+    fn test_highest_sequence_number_does_not_overflow() {
+        let mut buffer = JitterBuffer::new();
+        for sequence in [i32::MAX - 2, i32::MAX - 1, i32::MAX] {
+            assert!(buffer.push(sequence, packet(sequence), 1_000));
+        }
+
+        for sequence in [i32::MAX - 2, i32::MAX - 1, i32::MAX] {
+            assert_eq!(buffer.pop(), PlayoutFrame::Packet(packet(sequence)));
+        }
+        assert_eq!(buffer.pop(), PlayoutFrame::Conceal);
+    }
+}
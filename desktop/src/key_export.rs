@@ -0,0 +1,426 @@
+use crate::models::UserProfile;
+use crate::utils::Security;
+use aes_gcm::{Aes256Gcm, AesGcm, Key, Nonce, aead::{Aead, NewAead, Payload}};
+use aes_gcm::aead::consts::U16;
+use aes_gcm::aes::Aes256;
+use anyhow::{Result, anyhow};
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::{Engine as _, engine::general_purpose};
+use pbkdf2::pbkdf2_hmac;
+use rand::{RngCore, rngs::OsRng};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+pub const KEY_EXPORT_FORMAT: &str = "nonmessenger-key-export";
+pub const KEY_EXPORT_VERSION: u32 = 2;
+
+const KDF_ARGON2ID: &str = "argon2id";
+const CIPHER_AES_256_GCM: &str = "aes-256-gcm";
+const SALT_LENGTH: usize = 32;
+const MIN_PASSWORD_LENGTH: usize = 8;
+
+// Argon2id defaults: 64 MiB, 3 passes, single lane
+const ARGON2_MEMORY_KIB: u32 = 64 * 1024;
+const ARGON2_ITERATIONS: u32 = 3;
+const ARGON2_PARALLELISM: u32 = 1;
+
+// Upper bounds for parameters read from an untrusted container, so a crafted
+// file cannot make an import allocate gigabytes or run for hours
+const MAX_ARGON2_MEMORY_KIB: u32 = 4 * ARGON2_MEMORY_KIB;
+const MAX_ARGON2_ITERATIONS: u32 = 10;
+const MAX_ARGON2_PARALLELISM: u32 = 8;
+
+// shared/utils/keyStorage.js container parameters
+const JS_STORAGE_VERSION: &str = "1.0";
+const JS_PBKDF2_ITERATIONS: u32 = 100_000;
+
+/// Identity material carried inside an export
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyExportPayload {
+    pub contact_code: Vec<String>,
+    pub secret_words: Vec<String>,
+    pub public_key: String,
+    pub private_key: String,
+    pub device_id: String,
+    pub created_at: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KdfParams {
+    pub algorithm: String,
+    pub salt: String,
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+/// Versioned, password-encrypted key export.
+///
+/// The header (format, version, kdf, cipher) is bound to the ciphertext as
+/// associated data, and `integrity` is the SHA-256 of the plaintext payload.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyExportContainer {
+    pub format: String,
+    pub version: u32,
+    pub kdf: KdfParams,
+    pub cipher: String,
+    pub iv: String,
+    pub ciphertext: String,
+    pub auth_tag: String,
+    pub integrity: String,
+    pub created_at: i64,
+}
+
+impl KeyExportPayload {
+    pub fn from_profile(profile: &UserProfile) -> Self {
+        Self {
+            contact_code: profile.contact_code.clone(),
+            secret_words: profile.secret_words.clone(),
+            public_key: profile.public_key.clone(),
+            private_key: profile.private_key.clone(),
+            device_id: profile.device_id.clone(),
+            created_at: profile.created_at,
+        }
+    }
+
+    /// The imported identity, keeping the name and status of `existing`
+    /// when there is one; only the key material is replaced
+    pub fn into_profile(self, existing: Option<UserProfile>) -> UserProfile {
+        let existing = existing.unwrap_or_else(|| UserProfile {
+            id: "user_profile".to_string(),
+            contact_code: Vec::new(),
+            secret_words: Vec::new(),
+            public_key: String::new(),
+            private_key: String::new(),
+            device_id: String::new(),
+            display_name: "Me".to_string(),
+            status: "online".to_string(),
+            custom_message: String::new(),
+            created_at: 0,
+        });
+
+        UserProfile {
+            contact_code: self.contact_code,
+            secret_words: self.secret_words,
+            public_key: self.public_key,
+            private_key: self.private_key,
+            device_id: self.device_id,
+            created_at: self.created_at,
+            ..existing
+        }
+    }
+}
+
+/// Argon2id parameters with an empty salt for the caller to fill in
+pub fn default_kdf_params() -> KdfParams {
+    KdfParams {
+        algorithm: KDF_ARGON2ID.to_string(),
+        salt: String::new(),
+        memory_kib: ARGON2_MEMORY_KIB,
+        iterations: ARGON2_ITERATIONS,
+        parallelism: ARGON2_PARALLELISM,
+    }
+}
+
+/// Encrypt a profile's keys into a JSON container
+pub fn export_keys(payload: &KeyExportPayload, password: &str) -> Result<String> {
+    let container = seal_container(payload, password, default_kdf_params())?;
+    Ok(serde_json::to_string_pretty(&container)?)
+}
+
+/// Decrypt a container from this app, or a `keyStorage.js` container from the
+/// Android and server tooling
+pub fn import_keys(data: &str, password: &str) -> Result<KeyExportPayload> {
+    let value: Value = serde_json::from_str(data)
+        .map_err(|_| anyhow!("Key export is not valid JSON"))?;
+
+    if value["format"].as_str() == Some(KEY_EXPORT_FORMAT) {
+        let container: KeyExportContainer = serde_json::from_value(value)?;
+        open_container(&container, password)
+    } else if value.get("encryptedData").is_some() && value.get("authTag").is_some() {
+        open_js_container(&value, password)
+    } else {
+        Err(anyhow!("Unrecognized key export format"))
+    }
+}
+
+fn seal_container(payload: &KeyExportPayload, password: &str, mut kdf: KdfParams) -> Result<KeyExportContainer> {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(anyhow!("Export password must be at least {} characters", MIN_PASSWORD_LENGTH));
+    }
+
+    let mut salt = [0u8; SALT_LENGTH];
+    let mut nonce_bytes = [0u8; 12];
+    OsRng.fill_bytes(&mut salt);
+    OsRng.fill_bytes(&mut nonce_bytes);
+    kdf.salt = general_purpose::STANDARD.encode(salt);
+
+    let mut key = derive_argon2_key(password, &kdf)?;
+    let plaintext = serde_json::to_vec(payload)?;
+    let integrity = hex::encode(Sha256::digest(&plaintext));
+
+    let mut container = KeyExportContainer {
+        format: KEY_EXPORT_FORMAT.to_string(),
+        version: KEY_EXPORT_VERSION,
+        kdf,
+        cipher: CIPHER_AES_256_GCM.to_string(),
+        iv: general_purpose::STANDARD.encode(nonce_bytes),
+        ciphertext: String::new(),
+        auth_tag: String::new(),
+        integrity,
+        created_at: chrono::Utc::now().timestamp(),
+    };
+
+    let cipher = Aes256Gcm::new(Key::from_slice(&key));
+    let aad = header_aad(&container)?;
+    let mut ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce_bytes), Payload { msg: &plaintext, aad: &aad })
+        .map_err(|e| anyhow!("Key export encryption failed: {}", e))?;
+    Security::secure_zero(&mut key);
+
+    let auth_tag = ciphertext.split_off(ciphertext.len() - 16);
+    container.ciphertext = general_purpose::STANDARD.encode(&ciphertext);
+    container.auth_tag = general_purpose::STANDARD.encode(&auth_tag);
+
+    Ok(container)
+}
+
+fn open_container(container: &KeyExportContainer, password: &str) -> Result<KeyExportPayload> {
+    if container.version != KEY_EXPORT_VERSION {
+        return Err(anyhow!("Unsupported key export version: {}", container.version));
+    }
+    if container.kdf.algorithm != KDF_ARGON2ID || container.cipher != CIPHER_AES_256_GCM {
+        return Err(anyhow!("Unsupported key export algorithms"));
+    }
+
+    let mut key = derive_argon2_key(password, &container.kdf)?;
+    let nonce_bytes = general_purpose::STANDARD.decode(&container.iv)?;
+    if nonce_bytes.len() != 12 {
+        return Err(anyhow!("Invalid key export IV"));
+    }
+
+    let mut ciphertext = general_purpose::STANDARD.decode(&container.ciphertext)?;
+    ciphertext.extend_from_slice(&general_purpose::STANDARD.decode(&container.auth_tag)?);
+
+    let cipher = Aes256Gcm::new(Key::from_slice(&key));
+    let aad = header_aad(container)?;
+    let plaintext = cipher
+        .decrypt(Nonce::from_slice(&nonce_bytes), Payload { msg: &ciphertext, aad: &aad })
+        .map_err(|_| anyhow!("Wrong password or corrupted key export"));
+    Security::secure_zero(&mut key);
+    let plaintext = plaintext?;
+
+    let integrity = hex::encode(Sha256::digest(&plaintext));
+    if !Security::secure_compare(integrity.as_bytes(), container.integrity.as_bytes()) {
+        return Err(anyhow!("Key export integrity check failed"));
+    }
+
+    Ok(serde_json::from_slice(&plaintext)?)
+}
+
+/// `SecureKeyStorage.encryptData` format: PBKDF2-SHA256, 16-byte GCM IV, hex ciphertext
+fn open_js_container(value: &Value, password: &str) -> Result<KeyExportPayload> {
+    if value["version"].as_str() != Some(JS_STORAGE_VERSION) {
+        return Err(anyhow!("Unsupported storage version"));
+    }
+
+    let field = |name: &str| -> Result<&str> {
+        value[name].as_str().ok_or_else(|| anyhow!("Key container is missing {}", name))
+    };
+    let salt = general_purpose::STANDARD.decode(field("salt")?)?;
+    let iv = general_purpose::STANDARD.decode(field("iv")?)?;
+    let auth_tag = general_purpose::STANDARD.decode(field("authTag")?)?;
+    let mut ciphertext = hex::decode(field("encryptedData")?)?;
+    if iv.len() != 16 {
+        return Err(anyhow!("Invalid key container IV"));
+    }
+
+    let mut key = [0u8; 32];
+    pbkdf2_hmac::<Sha256>(password.as_bytes(), &salt, JS_PBKDF2_ITERATIONS, &mut key);
+
+    ciphertext.extend_from_slice(&auth_tag);
+    let cipher = AesGcm::<Aes256, U16>::new(Key::from_slice(&key));
+    let plaintext = cipher
+        .decrypt(aes_gcm::aead::generic_array::GenericArray::from_slice(&iv), ciphertext.as_ref())
+        .map_err(|_| anyhow!("Wrong password or corrupted key container"));
+    Security::secure_zero(&mut key);
+    let plaintext = plaintext?;
+
+    let container: Value = serde_json::from_slice(&plaintext)?;
+    let words = |name: &str| -> Result<Vec<String>> {
+        let words: Vec<String> = container[name].as_array()
+            .ok_or_else(|| anyhow!("Key container is missing {}", name))?
+            .iter()
+            .map(|v| v.as_str().unwrap_or("").to_string())
+            .collect();
+        if words.len() != 8 {
+            return Err(anyhow!("Key container {} must have 8 words", name));
+        }
+        Ok(words)
+    };
+    let full_key_pair = &container["keyPairs"]["fullKeyPair"];
+
+    Ok(KeyExportPayload {
+        contact_code: words("contactWords")?,
+        secret_words: words("secretWords")?,
+        public_key: full_key_pair["publicKey"].as_str()
+            .ok_or_else(|| anyhow!("Key container is missing the full public key"))?.to_string(),
+        private_key: full_key_pair["privateKey"].as_str()
+            .ok_or_else(|| anyhow!("Key container is missing the full private key"))?.to_string(),
+        device_id: container["deviceId"].as_str().unwrap_or("").to_string(),
+        // keyStorage.js stores milliseconds
+        created_at: container["createdAt"].as_i64().unwrap_or(0) / 1000,
+    })
+}
+
+pub fn derive_argon2_key(password: &str, kdf: &KdfParams) -> Result<[u8; 32]> {
+    if kdf.memory_kib > MAX_ARGON2_MEMORY_KIB
+        || kdf.iterations > MAX_ARGON2_ITERATIONS
+        || kdf.parallelism > MAX_ARGON2_PARALLELISM
+    {
+        return Err(anyhow!(
+            "Argon2 parameters exceed the supported maximum ({} KiB, {} passes, {} lanes)",
+            kdf.memory_kib, kdf.iterations, kdf.parallelism
+        ));
+    }
+
+    let salt = general_purpose::STANDARD.decode(&kdf.salt)?;
+    let params = Params::new(kdf.memory_kib, kdf.iterations, kdf.parallelism, Some(32))
+        .map_err(|e| anyhow!("Invalid Argon2 parameters: {}", e))?;
+
+    let mut key = [0u8; 32];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(password.as_bytes(), &salt, &mut key)
+        .map_err(|e| anyhow!("Argon2 key derivation failed: {}", e))?;
+    Ok(key)
+}
+
+fn header_aad(container: &KeyExportContainer) -> Result<Vec<u8>> {
+    Ok(format!(
+        "{}:{}:{}:{}",
+        container.format,
+        container.version,
+        serde_json::to_string(&container.kdf)?,
+        container.cipher
+    ).into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload() -> KeyExportPayload {
+        KeyExportPayload {
+            contact_code: vec!["abandon".to_string(); 8],
+            secret_words: vec!["ability".to_string(); 8],
+            public_key: "public".to_string(),
+            private_key: "private".to_string(),
+            device_id: "0123456789abcdef0123456789abcdef".to_string(),
+            created_at: 1_700_000_000,
+        }
+    }
+
+    fn fast_kdf() -> KdfParams {
+        KdfParams {
+            algorithm: KDF_ARGON2ID.to_string(),
+            salt: String::new(),
+            memory_kib: 1024,
+            iterations: 1,
+            parallelism: 1,
+        }
+    }
+
+    #[test]
+    fn test_export_import_round_trip() {
+        let container = seal_container(&payload(), "correct horse", fast_kdf()).unwrap();
+        let json = serde_json::to_string(&container).unwrap();
+
+        let imported = import_keys(&json, "correct horse").unwrap();
+        assert_eq!(imported.private_key, "private");
+        assert_eq!(imported.contact_code, payload().contact_code);
+
+        assert!(import_keys(&json, "wrong password").is_err());
+        assert!(seal_container(&payload(), "short", fast_kdf()).is_err());
+    }
+
+    #[test]
+    fn test_export_rejects_tampered_header() {
+        let mut container = seal_container(&payload(), "correct horse", fast_kdf()).unwrap();
+        container.kdf.iterations = 2;
+        let json = serde_json::to_string(&container).unwrap();
+        assert!(import_keys(&json, "correct horse").is_err());
+    }
+
+    #[test]
+    fn test_import_rejects_excessive_kdf_params() {
+        for kdf in [
+            KdfParams { memory_kib: 16 * 1024 * 1024, ..fast_kdf() },
+            KdfParams { iterations: 1_000_000, ..fast_kdf() },
+            KdfParams { parallelism: 255, ..fast_kdf() },
+        ] {
+            let mut container = seal_container(&payload(), "correct horse", fast_kdf()).unwrap();
+            container.kdf = kdf;
+            let json = serde_json::to_string(&container).unwrap();
+            let error = import_keys(&json, "correct horse").unwrap_err();
+            assert!(error.to_string().contains("exceed"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn test_import_keeps_existing_profile_details() {
+        let existing = UserProfile {
+            display_name: "Alice".to_string(),
+            status: "away".to_string(),
+            custom_message: "on holiday".to_string(),
+            ..payload().into_profile(None)
+        };
+
+        let imported = KeyExportPayload { private_key: "new-private".to_string(), ..payload() }
+            .into_profile(Some(existing));
+        assert_eq!(imported.private_key, "new-private");
+        assert_eq!(imported.display_name, "Alice");
+        assert_eq!(imported.status, "away");
+        assert_eq!(imported.custom_message, "on holiday");
+
+        assert_eq!(payload().into_profile(None).display_name, "Me");
+    }
+
+    #[test]
+    fn test_import_key_storage_js_container() {
+        let inner = serde_json::json!({
+            "contactWords": vec!["abandon"; 8],
+            "secretWords": vec!["ability"; 8],
+            "keyPairs": {
+                "contactKeyPair": { "publicKey": "contact-public", "privateKey": "contact-private" },
+                "fullKeyPair": { "publicKey": "full-public", "privateKey": "full-private" }
+            },
+            "deviceId": "device",
+            "createdAt": 1_700_000_000_000i64,
+            "version": "1.0"
+        });
+
+        let salt = [7u8; 32];
+        let iv = [9u8; 16];
+        let mut key = [0u8; 32];
+        pbkdf2_hmac::<Sha256>(b"js password", &salt, JS_PBKDF2_ITERATIONS, &mut key);
+        let cipher = AesGcm::<Aes256, U16>::new(Key::from_slice(&key));
+        let mut sealed = cipher
+            .encrypt(aes_gcm::aead::generic_array::GenericArray::from_slice(&iv), inner.to_string().as_bytes())
+            .unwrap();
+        let auth_tag = sealed.split_off(sealed.len() - 16);
+
+        let container = serde_json::json!({
+            "version": "1.0",
+            "salt": general_purpose::STANDARD.encode(salt),
+            "iv": general_purpose::STANDARD.encode(iv),
+            "authTag": general_purpose::STANDARD.encode(auth_tag),
+            "encryptedData": hex::encode(sealed),
+        });
+
+        let imported = import_keys(&container.to_string(), "js password").unwrap();
+        assert_eq!(imported.public_key, "full-public");
+        assert_eq!(imported.private_key, "full-private");
+        assert_eq!(imported.created_at, 1_700_000_000);
+    }
+}
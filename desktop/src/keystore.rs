@@ -0,0 +1,274 @@
+use crate::db_encryption::ColumnCipher;
+use crate::utils::AppPaths;
+use anyhow::{Result, anyhow};
+use base64::{Engine as _, engine::general_purpose};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Prefix for profile columns that point into a key store instead of holding the secret
+pub const KEYSTORE_REFERENCE_PREFIX: &str = "keystore:";
+
+const KEYRING_SERVICE: &str = "NonMessenger";
+/// Master key file and cipher label of the file backend before it stopped sealing secrets itself
+const LEGACY_MASTER_KEY: &str = "keystore.key";
+const LEGACY_SECRET_LABEL: &str = "keystore.secret";
+
+/// Storage for identity secrets outside the SQLite database
+pub trait KeyStore: Send + Sync {
+    fn store(&self, id: &str, secret: &str) -> Result<()>;
+    fn load(&self, id: &str) -> Result<Option<String>>;
+    fn delete(&self, id: &str) -> Result<()>;
+    fn backend_name(&self) -> &'static str;
+}
+
+pub fn reference_for(id: &str) -> String {
+    format!("{}{}", KEYSTORE_REFERENCE_PREFIX, id)
+}
+
+pub fn parse_reference(value: &str) -> Option<&str> {
+    value.strip_prefix(KEYSTORE_REFERENCE_PREFIX)
+}
+
+/// Pick the best available store. `NONMESSENGER_KEYSTORE=file|memory` overrides
+/// the choice, which CI uses on Linux hosts without a secret service.
+pub fn default_key_store() -> Result<Arc<dyn KeyStore>> {
+    match std::env::var("NONMESSENGER_KEYSTORE").as_deref() {
+        Ok("memory") => return Ok(Arc::new(MemoryKeyStore::new())),
+        Ok("file") => return Ok(Arc::new(FileKeyStore::open(&AppPaths::get_keys_dir()?)?)),
+        _ => {}
+    }
+
+    let keyring_store = KeyringKeyStore::new(KEYRING_SERVICE);
+    if keyring_store.is_available() {
+        Ok(Arc::new(keyring_store))
+    } else {
+        log::warn!("OS secret store unavailable, falling back to key files protected by the database passphrase");
+        Ok(Arc::new(FileKeyStore::open(&AppPaths::get_keys_dir()?)?))
+    }
+}
+
+/// Keychain, Credential Manager or Secret Service via the `keyring` crate
+pub struct KeyringKeyStore {
+    service: String,
+}
+
+impl KeyringKeyStore {
+    pub fn new(service: &str) -> Self {
+        Self { service: service.to_string() }
+    }
+
+    /// Probe the platform store with a throwaway entry
+    pub fn is_available(&self) -> bool {
+        let probe = || -> Result<()> {
+            let entry = keyring::Entry::new(&self.service, "availability-probe")?;
+            entry.set_password("probe")?;
+            entry.delete_password()?;
+            Ok(())
+        };
+        probe().is_ok()
+    }
+}
+
+impl KeyStore for KeyringKeyStore {
+    fn store(&self, id: &str, secret: &str) -> Result<()> {
+        keyring::Entry::new(&self.service, id)?.set_password(secret)?;
+        Ok(())
+    }
+
+    fn load(&self, id: &str) -> Result<Option<String>> {
+        match keyring::Entry::new(&self.service, id)?.get_password() {
+            Ok(secret) => Ok(Some(secret)),
+            Err(keyring::Error::NoEntry) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn delete(&self, id: &str) -> Result<()> {
+        match keyring::Entry::new(&self.service, id)?.delete_password() {
+            Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn backend_name(&self) -> &'static str {
+        "keyring"
+    }
+}
+
+/// Owner-only files, one per secret, under the keys directory.
+///
+/// Only used when the OS secret store is unavailable, which leaves no key to
+/// seal the files with that would not sit right beside them. The database
+/// seals each secret with its passphrase-protected key before storing it here
+/// whenever encryption is enabled; until then the files are protected by their
+/// permissions alone.
+pub struct FileKeyStore {
+    dir: PathBuf,
+}
+
+impl FileKeyStore {
+    pub fn open(dir: &Path) -> Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let store = Self { dir: dir.to_path_buf() };
+        store.remove_legacy_master_key()?;
+        Ok(store)
+    }
+
+    fn path_for(&self, id: &str) -> Result<PathBuf> {
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-') {
+            return Err(anyhow!("Invalid key store id: {}", id));
+        }
+        Ok(self.dir.join(format!("{}.enc", id)))
+    }
+
+    /// Earlier builds sealed every file with a master key kept in the same
+    /// directory, which protected nothing. Unseal the files and delete it.
+    fn remove_legacy_master_key(&self) -> Result<()> {
+        let master_path = self.dir.join(LEGACY_MASTER_KEY);
+        if !master_path.exists() {
+            return Ok(());
+        }
+
+        let master_key: [u8; 32] = general_purpose::STANDARD.decode(std::fs::read_to_string(&master_path)?.trim())?
+            .try_into()
+            .map_err(|_| anyhow!("Key store master key has the wrong length"))?;
+        let cipher = ColumnCipher::new(master_key);
+
+        for entry in std::fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            let id = match path.file_name().and_then(|name| name.to_str()).and_then(|name| name.strip_suffix(".enc")) {
+                Some(id) => id.to_string(),
+                None => continue,
+            };
+            let sealed = std::fs::read_to_string(&path)?;
+            write_private_file(&path, &cipher.decrypt(LEGACY_SECRET_LABEL, &id, &sealed)?)?;
+        }
+
+        std::fs::remove_file(&master_path)?;
+        log::info!("Removed the legacy key store master key from {:?}", self.dir);
+        Ok(())
+    }
+}
+
+impl KeyStore for FileKeyStore {
+    fn store(&self, id: &str, secret: &str) -> Result<()> {
+        write_private_file(&self.path_for(id)?, secret)
+    }
+
+    fn load(&self, id: &str) -> Result<Option<String>> {
+        let path = self.path_for(id)?;
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(std::fs::read_to_string(path)?))
+    }
+
+    fn delete(&self, id: &str) -> Result<()> {
+        let path = self.path_for(id)?;
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    fn backend_name(&self) -> &'static str {
+        "file"
+    }
+}
+
+/// Process-local store for tests
+pub struct MemoryKeyStore {
+    entries: Mutex<HashMap<String, String>>,
+}
+
+impl MemoryKeyStore {
+    pub fn new() -> Self {
+        Self { entries: Mutex::new(HashMap::new()) }
+    }
+}
+
+impl KeyStore for MemoryKeyStore {
+    fn store(&self, id: &str, secret: &str) -> Result<()> {
+        self.entries.lock().unwrap().insert(id.to_string(), secret.to_string());
+        Ok(())
+    }
+
+    fn load(&self, id: &str) -> Result<Option<String>> {
+        Ok(self.entries.lock().unwrap().get(id).cloned())
+    }
+
+    fn delete(&self, id: &str) -> Result<()> {
+        self.entries.lock().unwrap().remove(id);
+        Ok(())
+    }
+
+    fn backend_name(&self) -> &'static str {
+        "memory"
+    }
+}
+
+fn write_private_file(path: &Path, contents: &str) -> Result<()> {
+    std::fs::write(path, contents)?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_key_store_round_trip() {
+        let dir = std::env::temp_dir().join(format!("nonmessenger-keystore-{}", uuid::Uuid::new_v4()));
+        let store = FileKeyStore::open(&dir).unwrap();
+
+        store.store("user_profile.private_key", "enc1:sealed").unwrap();
+        // No key is kept next to the secrets
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        let reopened = FileKeyStore::open(&dir).unwrap();
+        assert_eq!(reopened.load("user_profile.private_key").unwrap().as_deref(), Some("enc1:sealed"));
+
+        reopened.delete("user_profile.private_key").unwrap();
+        assert!(reopened.load("user_profile.private_key").unwrap().is_none());
+        assert!(reopened.store("../escape", "x").is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_file_key_store_drops_legacy_master_key() {
+        let dir = std::env::temp_dir().join(format!("nonmessenger-keystore-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let legacy = ColumnCipher::new([9u8; 32]);
+        let key_path = dir.join(LEGACY_MASTER_KEY);
+        write_private_file(&key_path, &general_purpose::STANDARD.encode([9u8; 32])).unwrap();
+        write_private_file(
+            &dir.join("user_profile.private_key.enc"),
+            &legacy.encrypt(LEGACY_SECRET_LABEL, "user_profile.private_key", "secret").unwrap(),
+        ).unwrap();
+
+        let store = FileKeyStore::open(&dir).unwrap();
+        assert!(!key_path.exists());
+        assert_eq!(store.load("user_profile.private_key").unwrap().as_deref(), Some("secret"));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_memory_key_store_round_trip() {
+        let store = MemoryKeyStore::new();
+        assert!(store.load("missing").unwrap().is_none());
+
+        store.store("id", "value").unwrap();
+        assert_eq!(store.load("id").unwrap().as_deref(), Some("value"));
+        assert_eq!(parse_reference(&reference_for("id")), Some("id"));
+    }
+}
@@ -0,0 +1,252 @@
+use crate::database::Database;
+use crate::dispatcher::Dispatcher;
+use crate::models::*;
+use crate::network::{MailboxReader, MessagePoolClient};
+use crate::signing;
+use crate::utils::Logger;
+use crate::AppState;
+use anyhow::{Result, anyhow};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+/// Default interval between mailbox polls while connected
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(120);
+
+/// How long a received envelope that keeps failing is retried
+pub const PENDING_RETENTION_SECS: i64 = 7 * 24 * 60 * 60;
+
+/// Pulls messages pooled while we were offline from every active node.
+///
+/// Nodes delete messages as they return them, so each one is stored locally
+/// before it is processed. Copies of the same message on several nodes are
+/// stored once and acknowledged on each node that returned them. Envelopes
+/// with a bad signature are dropped; others that fail are retried on every
+/// sync until they expire.
+pub struct MailboxSync {
+    dispatcher: Dispatcher,
+    database: Arc<Mutex<Database>>,
+    network: Arc<Mutex<MessagePoolClient>>,
+    interval: Duration,
+}
+
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct MailboxSyncReport {
+    pub fetched: usize,
+    pub processed: usize,
+    pub failed: usize,
+}
+
+impl MailboxSync {
+    pub fn new(dispatcher: Dispatcher, state: &AppState, interval: Duration) -> Self {
+        Self {
+            dispatcher,
+            database: Arc::clone(&state.database),
+            network: Arc::clone(&state.network),
+            interval,
+        }
+    }
+
+    pub async fn run(self) {
+        let mut connection = self.network.lock().await.subscribe_connection();
+
+        loop {
+            // Sync right after every (re)connect, then on the interval
+            if *connection.borrow_and_update() {
+                match self.sync().await {
+                    Ok(report) if report.fetched > 0 => log::info!(
+                        "Mailbox sync: {} fetched, {} processed, {} failed",
+                        report.fetched, report.processed, report.failed
+                    ),
+                    Ok(_) => {}
+                    Err(e) => log::warn!("Mailbox sync failed: {}", e),
+                }
+            }
+
+            tokio::select! {
+                _ = connection.changed() => {}
+                _ = tokio::time::sleep(self.interval) => {}
+            }
+        }
+    }
+
+    pub async fn sync(&self) -> Result<MailboxSyncReport> {
+        let (contact_code, nodes) = {
+            let db = self.database.lock().await;
+            let profile = db.get_user_profile().await?
+                .ok_or_else(|| anyhow!("No user profile found"))?;
+            (profile.contact_code.join(" "), db.get_active_nodes().await?)
+        };
+        let mailbox = self.network.lock().await.mailbox_reader();
+
+        let mut fetched = Vec::new();
+        for node in &nodes {
+            match mailbox.get_messages(&node.url, &contact_code).await {
+                Ok(messages) => fetched.push((node.url.clone(), messages)),
+                Err(e) => log::debug!("Mailbox poll of {} failed: {}", node.url, e),
+            }
+        }
+
+        let mut report = MailboxSyncReport::default();
+        let now = chrono::Utc::now().timestamp();
+        let acknowledgements = {
+            let db = self.database.lock().await;
+            store_fetched(&db, fetched, now, &mut report).await?
+        };
+
+        for (node_url, message_id) in acknowledgements {
+            if let Err(e) = mailbox.acknowledge_message(&node_url, &message_id).await {
+                log::debug!("Failed to acknowledge {} on {}: {}", message_id, node_url, e);
+            }
+        }
+
+        self.process_pending(&mailbox, &mut report).await?;
+        Ok(report)
+    }
+
+    /// Process every stored envelope, oldest first, keeping the ones that fail
+    /// for a reason that may pass, such as a sender not yet added as a contact
+    async fn process_pending(&self, mailbox: &MailboxReader, report: &mut MailboxSyncReport) -> Result<()> {
+        let pending = {
+            let db = self.database.lock().await;
+            let now = chrono::Utc::now().timestamp();
+            let expired = db.expire_pending_envelopes(now - PENDING_RETENTION_SECS).await?;
+            if expired > 0 {
+                log::warn!("Gave up on {} received messages that could not be processed", expired);
+            }
+            db.get_pending_envelopes().await?
+        };
+
+        for pending in pending {
+            let envelope_id = pending.envelope.id.clone();
+
+            // A bad signature never becomes good, so there is nothing to retry
+            if let Err(e) = signing::verify_envelope(&pending.envelope) {
+                Logger::log_security_event(
+                    "Dropped pooled message with invalid signature",
+                    &format!("id={} reason={}", envelope_id, e),
+                );
+                report.failed += 1;
+                self.database.lock().await.delete_pending_envelope(&envelope_id).await?;
+                continue;
+            }
+
+            let result = match mailbox.verify_envelope(&pending.envelope).await {
+                Ok(()) => self.dispatcher.handle_envelope(pending.envelope).await,
+                Err(e) => Err(e),
+            };
+
+            let db = self.database.lock().await;
+            match result {
+                Ok(()) => {
+                    report.processed += 1;
+                    db.delete_pending_envelope(&envelope_id).await?;
+                }
+                Err(e) => {
+                    log::warn!("Failed to process received message {} (attempt {}): {}", envelope_id, pending.attempts + 1, e);
+                    report.failed += 1;
+                    db.record_pending_envelope_failure(&envelope_id, &e.to_string()).await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Store each fetched message once, however many nodes returned a copy.
+/// Returns the (node, message id) pairs that are safe to acknowledge: every
+/// node holding a copy of a stored message.
+async fn store_fetched(
+    db: &Database,
+    fetched: Vec<(String, Vec<PooledMessage>)>,
+    now: i64,
+    report: &mut MailboxSyncReport,
+) -> Result<Vec<(String, String)>> {
+    // message id -> (message, nodes holding a copy)
+    let mut pooled: HashMap<String, (PooledMessage, Vec<String>)> = HashMap::new();
+    for (node_url, messages) in fetched {
+        for message in messages {
+            pooled.entry(message.id.clone())
+                .or_insert_with(|| (message, Vec::new()))
+                .1
+                .push(node_url.clone());
+        }
+    }
+    report.fetched = pooled.len();
+
+    let mut acknowledgements = Vec::new();
+    for (message, node_urls) in pooled.into_values() {
+        if message.encrypted_message.id != message.id {
+            log::warn!("Pooled message {} carries envelope {}", message.id, message.encrypted_message.id);
+            report.failed += 1;
+            continue;
+        }
+
+        db.save_pending_envelope(&message.encrypted_message, message.timestamp, now).await?;
+        acknowledgements.extend(node_urls.into_iter().map(|node_url| (node_url, message.id.clone())));
+    }
+
+    Ok(acknowledgements)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::EncryptedMessage;
+    use crate::test_support::TempDatabase;
+
+    fn pooled(id: &str, envelope_id: &str) -> PooledMessage {
+        PooledMessage {
+            id: id.to_string(),
+            encrypted_message: MessageEnvelope {
+                id: envelope_id.to_string(),
+                recipient_contact_code: "code".to_string(),
+                encrypted_message: EncryptedMessage {
+                    encrypted_message: "ciphertext".to_string(),
+                    encrypted_key: String::new(),
+                    iv: String::new(),
+                    auth_tag: String::new(),
+                    algorithm: String::new(),
+                },
+                timestamp: 1,
+                ttl: 86400000,
+                message_type: "text".to_string(),
+                sender_key: None,
+                signature: None,
+            },
+            timestamp: 1,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_copies_on_several_nodes_are_stored_once_and_acknowledged_on_each() {
+        let db = TempDatabase::open().await;
+        let mut report = MailboxSyncReport::default();
+
+        let fetched = vec![
+            ("https://a.example".to_string(), vec![pooled("m1", "m1"), pooled("m2", "other")]),
+            ("https://b.example".to_string(), vec![pooled("m1", "m1")]),
+        ];
+        let mut acknowledgements = store_fetched(&db, fetched, 10, &mut report).await.unwrap();
+        acknowledgements.sort();
+
+        assert_eq!(acknowledgements, vec![
+            ("https://a.example".to_string(), "m1".to_string()),
+            ("https://b.example".to_string(), "m1".to_string()),
+        ]);
+        assert_eq!(report.fetched, 2);
+        // An entry whose envelope id differs is neither stored nor acknowledged
+        assert_eq!(report.failed, 1);
+
+        let pending = db.get_pending_envelopes().await.unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].envelope.id, "m1");
+
+        // A node returning it again on the next sync adds no second copy
+        let again = vec![("https://b.example".to_string(), vec![pooled("m1", "m1")])];
+        store_fetched(&db, again, 20, &mut MailboxSyncReport::default()).await.unwrap();
+        assert_eq!(db.get_pending_envelopes().await.unwrap().len(), 1);
+    }
+}
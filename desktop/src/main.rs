@@ -6,15 +6,36 @@ use tauri::{
     WindowBuilder, WindowUrl,
 };
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, Notify};
 
+mod audio_format;
+mod awareness;
 mod crypto;
 mod database;
+mod db_encryption;
+mod discovery;
+mod dispatcher;
+mod handshake;
+mod jitter_buffer;
+mod key_export;
+mod mailbox;
+mod keystore;
+mod migrations;
 mod network;
+mod outbox;
+mod presence;
+mod ratchet;
+mod receipts;
+mod signing;
+mod supervisor;
 mod voice;
+mod voice_codec;
+mod voice_crypto;
 mod commands;
 mod models;
 mod utils;
+#[cfg(test)]
+mod test_support;
 
 use crypto::NonMessengerCrypto;
 use database::Database;
@@ -26,6 +47,11 @@ pub struct AppState {
     pub database: Arc<Mutex<Database>>,
     pub network: Arc<Mutex<MessagePoolClient>>,
     pub voice: Arc<Mutex<VoiceCallManager>>,
+    /// Wakes the outbox worker when a message is queued or retried
+    pub outbox: Arc<Notify>,
+    pub supervisor: Arc<supervisor::SupervisorControl>,
+    /// Latest awareness updates received from contacts
+    pub awareness: Arc<Mutex<awareness::AwarenessTracker>>,
 }
 
 #[tokio::main]
@@ -35,14 +61,24 @@ async fn main() {
     // Initialize application state
     let crypto = Arc::new(NonMessengerCrypto::new());
     let database = Arc::new(Mutex::new(Database::new().await.expect("Failed to initialize database")));
-    let network = Arc::new(Mutex::new(MessagePoolClient::new()));
-    let voice = Arc::new(Mutex::new(VoiceCallManager::new()));
+    let mut network_client = MessagePoolClient::new();
+    let incoming = network_client.subscribe_incoming();
+    let websocket_sender = network_client.websocket_sender();
+    let network = Arc::new(Mutex::new(network_client));
+    let mut voice_manager = VoiceCallManager::new();
+    let voice_packets = voice_manager.subscribe_packets();
+    let device_loss = voice_manager.subscribe_device_loss();
+    let voice = Arc::new(Mutex::new(voice_manager));
     
     let app_state = AppState {
         crypto,
         database,
         network,
         voice,
+        outbox: Arc::new(Notify::new()),
+        // Reconnect on startup to whatever nodes were used before
+        supervisor: Arc::new(supervisor::SupervisorControl::new(true)),
+        awareness: Arc::new(Mutex::new(awareness::AwarenessTracker::new())),
     };
 
     // Create system tray
@@ -98,27 +134,88 @@ async fn main() {
             commands::decrypt_message,
             commands::get_contacts,
             commands::add_contact,
+            commands::send_contact_request,
+            commands::list_contact_requests,
+            commands::accept_contact_request,
+            commands::reject_contact_request,
+            commands::get_safety_number,
+            commands::verify_safety_number,
+            commands::mark_contact_verified,
+            commands::get_key_changes,
+            commands::acknowledge_key_change,
             commands::send_message,
+            commands::retry_message,
+            commands::get_outbox,
             commands::get_messages,
+            commands::mark_messages_displayed,
+            commands::mark_chat_read,
+            commands::get_chat_sessions,
+            commands::get_read_receipts_enabled,
+            commands::set_read_receipts_enabled,
             commands::connect_to_server,
             commands::disconnect_from_server,
             commands::get_server_status,
+            commands::set_user_status,
+            commands::send_typing_indicator,
+            commands::get_presence,
+            commands::set_presence_visibility,
+            commands::get_presence_visibility,
+            commands::list_server_nodes,
+            commands::add_server_node,
+            commands::set_server_node_enabled,
+            commands::set_server_node_priority,
+            commands::trust_server_node_key,
+            commands::discover_server_nodes,
             commands::initiate_voice_call,
             commands::accept_voice_call,
             commands::reject_voice_call,
             commands::end_voice_call,
             commands::get_call_status,
+            commands::list_audio_devices,
+            commands::select_audio_device,
+            commands::get_voice_codec_config,
+            commands::set_voice_codec_config,
             commands::generate_qr_code,
             commands::parse_qr_code,
             commands::export_keys,
             commands::import_keys,
+            commands::get_database_lock_state,
+            commands::enable_database_encryption,
+            commands::unlock_database,
+            commands::lock_database,
             commands::get_user_profile,
             commands::update_user_profile,
             commands::validate_contact_message,
             commands::get_device_info,
             commands::check_for_updates,
         ])
-        .setup(|app| {
+        .setup(move |app| {
+            let dispatcher = dispatcher::Dispatcher::new(app.handle(), &app.state::<AppState>());
+            let mailbox_sync = mailbox::MailboxSync::new(dispatcher.clone(), &app.state::<AppState>(), mailbox::DEFAULT_POLL_INTERVAL);
+            tokio::spawn(dispatcher.run(incoming));
+            tokio::spawn(voice::send_voice_packets(websocket_sender, voice_packets));
+            tokio::spawn(mailbox_sync.run());
+
+            let outbox_worker = outbox::OutboxWorker::new(app.handle(), &app.state::<AppState>(), outbox::OutboxConfig::default());
+            tokio::spawn(outbox_worker.run());
+
+            let presence_publisher = presence::PresencePublisher::new(&app.state::<AppState>());
+            tokio::spawn(presence_publisher.run());
+
+            let device_watcher = voice::DeviceWatcher::new(app.handle(), &app.state::<AppState>(), device_loss);
+            tokio::spawn(device_watcher.run());
+
+            // Put the unread count from last session on the tray right away
+            let app_handle = app.handle();
+            let database = Arc::clone(&app.state::<AppState>().database);
+            tokio::spawn(async move {
+                let db = database.lock().await;
+                dispatcher::emit_chat_sessions_changed(&app_handle, &db).await;
+            });
+
+            let connection_supervisor = supervisor::ConnectionSupervisor::new(&app.state::<AppState>(), supervisor::SupervisorConfig::default());
+            tokio::spawn(connection_supervisor.run());
+
             // Create main window
             let _window = WindowBuilder::new(
                 app,
@@ -149,8 +246,45 @@ mod tests {
 
     #[tokio::test]
     async fn test_database_initialization() {
-        let db = Database::new().await;
-        assert!(db.is_ok());
+        let db = test_support::TempDatabase::open().await;
+        assert!(!db.is_locked());
+        assert!(db.get_user_profile().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_chat_sessions_count_unread_messages() {
+        let db = test_support::TempDatabase::open().await;
+
+        for id in ["alice", "bob"] {
+            db.insert_contact(&test_support::test_contact(id)).await.unwrap();
+        }
+        for (id, is_from_me, timestamp) in [("m1", false, 1), ("m2", false, 2), ("m3", true, 3)] {
+            db.insert_message(&models::Message {
+                id: id.to_string(),
+                contact_id: "alice".to_string(),
+                content: format!("message {}", id),
+                is_from_me,
+                timestamp,
+                message_type: "text".to_string(),
+                delivery_status: "delivered".to_string(),
+                encrypted_content: String::new(),
+                created_at: timestamp,
+            }).await.unwrap();
+        }
+
+        let sessions = db.get_chat_sessions().await.unwrap();
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(sessions[0].contact_id, "alice");
+        assert_eq!(sessions[0].last_message, "message m3");
+        assert_eq!(sessions[0].unread_count, 2);
+        assert_eq!(sessions[1].contact_id, "bob");
+        assert_eq!(sessions[1].unread_count, 0);
+        assert_eq!(db.get_unread_total().await.unwrap(), 2);
+
+        let ids = vec!["m1".to_string(), "m3".to_string()];
+        assert_eq!(db.mark_messages_read("alice", Some(&ids), 10).await.unwrap(), vec!["m1".to_string()]);
+        assert_eq!(db.mark_messages_read("alice", None, 10).await.unwrap(), vec!["m2".to_string()]);
+        assert_eq!(db.get_unread_total().await.unwrap(), 0);
     }
 
     #[test]
@@ -165,6 +299,93 @@ mod tests {
         assert_eq!(message, decrypted);
     }
 
+    #[test]
+    fn test_identity_key_encryption() {
+        let crypto = NonMessengerCrypto::new();
+        let key_pair = crypto.generate_identity_key_pair().unwrap();
+
+        let message = "Test message for X25519 encryption";
+        let encrypted = crypto.encrypt_message(message, &key_pair.public_key).unwrap();
+        assert_eq!(encrypted.algorithm, crypto::ALGORITHM_X25519_HKDF);
+
+        let decrypted = crypto.decrypt_message(&encrypted, &key_pair.private_key).unwrap();
+        assert_eq!(message, decrypted);
+
+        let other = crypto.generate_identity_key_pair().unwrap();
+        assert!(crypto.decrypt_message(&encrypted, &other.private_key).is_err());
+    }
+
+    #[test]
+    fn test_legacy_envelope_without_algorithm_tag() {
+        let crypto = NonMessengerCrypto::new();
+        let key_pair = crypto.generate_rsa_key_pair().unwrap();
+
+        let encrypted = crypto.encrypt_message("legacy", &key_pair.public_key).unwrap();
+        let mut json = serde_json::to_value(&encrypted).unwrap();
+        json.as_object_mut().unwrap().remove("algorithm");
+
+        let legacy: crypto::EncryptedMessage = serde_json::from_value(json).unwrap();
+        assert_eq!(legacy.algorithm, crypto::ALGORITHM_RSA_OAEP);
+        assert_eq!(crypto.decrypt_message(&legacy, &key_pair.private_key).unwrap(), "legacy");
+    }
+
+    #[test]
+    fn test_largest_message_fits_server_limits() {
+        let crypto = NonMessengerCrypto::new();
+        let ours = crypto.generate_identity_key_pair().unwrap();
+        let theirs = crypto.generate_identity_key_pair().unwrap();
+        let legacy = crypto.generate_rsa_key_pair().unwrap();
+
+        let our_identity = crypto::IdentityKeyPair::from_private_key(&ours.private_key).unwrap();
+        let their_identity = crypto::IdentityPublicKey::from_public_key(&theirs.public_key).unwrap();
+        let mut session = ratchet::RatchetSession::initiate("contact", &our_identity, &their_identity).unwrap();
+
+        let ratchet_limit = dispatcher::max_content_bytes(&theirs.public_key);
+        let legacy_limit = dispatcher::max_content_bytes(&legacy.public_key);
+        for encrypted in [
+            session.encrypt(&"x".repeat(ratchet_limit)).unwrap(),
+            crypto.encrypt_message(&"x".repeat(legacy_limit), &legacy.public_key).unwrap(),
+        ] {
+            let mut envelope = models::MessageEnvelope {
+                id: uuid::Uuid::new_v4().to_string(),
+                // Eight of the longest BIP39 words
+                recipient_contact_code: vec!["abstract"; 8].join(" "),
+                encrypted_message: encrypted,
+                timestamp: chrono::Utc::now().timestamp(),
+                ttl: 86400000,
+                message_type: receipts::RECEIPT_DELIVERY_STATUS.to_string(),
+                sender_key: Some(ours.public_key.clone()),
+                signature: None,
+            };
+            signing::sign_envelope(&mut envelope, &ours.private_key).unwrap();
+
+            let size = serde_json::to_string(&envelope).unwrap().len();
+            assert!(size <= network::SERVER_MAX_ENVELOPE_BYTES, "{} byte envelope", size);
+        }
+    }
+
+    #[test]
+    fn test_safety_number_is_symmetric() {
+        let crypto = NonMessengerCrypto::new();
+        let alice = crypto.generate_identity_key_pair().unwrap();
+        let bob = crypto.generate_identity_key_pair().unwrap();
+        let mallory = crypto.generate_identity_key_pair().unwrap();
+
+        let alice_view = crypto.compute_safety_number(&alice.public_key, &bob.public_key).unwrap();
+        let bob_view = crypto.compute_safety_number(&bob.public_key, &alice.public_key).unwrap();
+        assert_eq!(alice_view.digits, bob_view.digits);
+        assert_eq!(alice_view.words, bob_view.words);
+        assert_eq!(alice_view.digits.split(' ').count(), 12);
+        assert_eq!(alice_view.words.len(), 12);
+
+        assert!(crypto.matches_safety_number(&alice_view, &bob_view.qr_payload));
+        assert!(crypto.matches_safety_number(&alice_view, &bob_view.digits));
+
+        let mitm_view = crypto.compute_safety_number(&alice.public_key, &mallory.public_key).unwrap();
+        assert_ne!(alice_view.digits, mitm_view.digits);
+        assert!(!crypto.matches_safety_number(&alice_view, &mitm_view.qr_payload));
+    }
+
     #[test]
     fn test_contact_code_generation() {
         let crypto = NonMessengerCrypto::new();
@@ -0,0 +1,433 @@
+use anyhow::{Result, anyhow};
+use rusqlite::Connection;
+use std::path::{Path, PathBuf};
+
+/// One schema step. `version` is stored in `PRAGMA user_version` once applied.
+pub struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+    pub sql: &'static str,
+}
+
+/// Ordered schema history. Append new migrations; never edit applied ones.
+///
+/// Version 1 is the schema that builds before the migration framework created
+/// with `CREATE TABLE IF NOT EXISTS`, so it is written to be re-runnable over
+/// those databases, which report `user_version` 0.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "initial schema",
+        sql: "
+            CREATE TABLE IF NOT EXISTS contacts (
+                id TEXT PRIMARY KEY,
+                name TEXT NOT NULL,
+                contact_code TEXT NOT NULL,
+                public_key TEXT NOT NULL,
+                status TEXT NOT NULL DEFAULT 'offline',
+                last_seen INTEGER NOT NULL DEFAULT 0,
+                is_verified BOOLEAN NOT NULL DEFAULT 0,
+                device_id TEXT NOT NULL,
+                created_at INTEGER NOT NULL
+            );
+
+            CREATE TABLE IF NOT EXISTS messages (
+                id TEXT PRIMARY KEY,
+                contact_id TEXT NOT NULL,
+                content TEXT NOT NULL,
+                is_from_me BOOLEAN NOT NULL,
+                timestamp INTEGER NOT NULL,
+                message_type TEXT NOT NULL DEFAULT 'text',
+                delivery_status TEXT NOT NULL DEFAULT 'sent',
+                encrypted_content TEXT NOT NULL DEFAULT '',
+                created_at INTEGER NOT NULL,
+                FOREIGN KEY (contact_id) REFERENCES contacts (id)
+            );
+
+            CREATE TABLE IF NOT EXISTS contact_requests (
+                id TEXT PRIMARY KEY,
+                sender_id TEXT NOT NULL,
+                sender_name TEXT NOT NULL,
+                public_words TEXT NOT NULL,
+                verification_message TEXT NOT NULL,
+                sender_public_key TEXT NOT NULL,
+                status TEXT NOT NULL DEFAULT 'pending',
+                received_at INTEGER NOT NULL
+            );
+
+            CREATE TABLE IF NOT EXISTS user_profile (
+                id TEXT PRIMARY KEY,
+                contact_code TEXT NOT NULL,
+                secret_words TEXT NOT NULL,
+                public_key TEXT NOT NULL,
+                private_key TEXT NOT NULL,
+                device_id TEXT NOT NULL,
+                display_name TEXT NOT NULL DEFAULT 'Me',
+                status TEXT NOT NULL DEFAULT 'online',
+                custom_message TEXT NOT NULL DEFAULT '',
+                created_at INTEGER NOT NULL
+            );
+
+            CREATE TABLE IF NOT EXISTS server_nodes (
+                url TEXT PRIMARY KEY,
+                public_key TEXT NOT NULL,
+                is_active BOOLEAN NOT NULL DEFAULT 1,
+                last_ping INTEGER NOT NULL DEFAULT 0,
+                response_time INTEGER NOT NULL DEFAULT 0,
+                priority INTEGER NOT NULL DEFAULT 0
+            );
+
+            CREATE INDEX IF NOT EXISTS idx_messages_contact_id ON messages (contact_id);
+            CREATE INDEX IF NOT EXISTS idx_messages_timestamp ON messages (timestamp);
+            CREATE INDEX IF NOT EXISTS idx_contacts_status ON contacts (status);
+        ",
+    },
+    Migration {
+        version: 2,
+        description: "ratchet sessions",
+        sql: "
+            CREATE TABLE IF NOT EXISTS sessions (
+                contact_id TEXT PRIMARY KEY,
+                state TEXT NOT NULL,
+                created_at INTEGER NOT NULL,
+                updated_at INTEGER NOT NULL,
+                FOREIGN KEY (contact_id) REFERENCES contacts (id)
+            );
+        ",
+    },
+    Migration {
+        version: 3,
+        description: "contact key change history",
+        sql: "
+            CREATE TABLE IF NOT EXISTS key_changes (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                contact_id TEXT NOT NULL,
+                old_public_key TEXT NOT NULL,
+                new_public_key TEXT NOT NULL,
+                changed_at INTEGER NOT NULL,
+                acknowledged BOOLEAN NOT NULL DEFAULT 0,
+                FOREIGN KEY (contact_id) REFERENCES contacts (id)
+            );
+        ",
+    },
+    Migration {
+        version: 4,
+        description: "outgoing contact requests",
+        sql: "
+            CREATE TABLE IF NOT EXISTS outgoing_contact_requests (
+                id TEXT PRIMARY KEY,
+                recipient_name TEXT NOT NULL,
+                recipient_words TEXT NOT NULL,
+                verification_message TEXT NOT NULL,
+                status TEXT NOT NULL DEFAULT 'pending',
+                sent_at INTEGER NOT NULL
+            );
+        ",
+    },
+    Migration {
+        version: 5,
+        description: "wrapped database key",
+        sql: "
+            CREATE TABLE IF NOT EXISTS database_keys (
+                id TEXT PRIMARY KEY,
+                wrapped_key TEXT NOT NULL,
+                created_at INTEGER NOT NULL
+            );
+        ",
+    },
+    Migration {
+        version: 6,
+        description: "outbound message queue",
+        sql: "
+            CREATE TABLE outbox (
+                message_id TEXT PRIMARY KEY,
+                contact_id TEXT NOT NULL,
+                recipient_contact_code TEXT NOT NULL,
+                message_type TEXT NOT NULL,
+                encrypted_message TEXT NOT NULL,
+                timestamp INTEGER NOT NULL,
+                status TEXT NOT NULL DEFAULT 'pending',
+                attempts INTEGER NOT NULL DEFAULT 0,
+                next_attempt_at INTEGER NOT NULL,
+                last_error TEXT,
+                created_at INTEGER NOT NULL,
+                FOREIGN KEY (message_id) REFERENCES messages (id)
+            );
+
+            CREATE INDEX idx_outbox_next_attempt ON outbox (status, next_attempt_at);
+        ",
+    },
+    Migration {
+        version: 7,
+        description: "pinned server node keys",
+        sql: "
+            ALTER TABLE server_nodes ADD COLUMN pending_public_key TEXT;
+        ",
+    },
+    Migration {
+        version: 8,
+        description: "user settings",
+        sql: "
+            CREATE TABLE settings (
+                key TEXT PRIMARY KEY,
+                value TEXT NOT NULL
+            );
+        ",
+    },
+    Migration {
+        version: 9,
+        description: "per-contact presence visibility",
+        sql: "
+            CREATE TABLE presence_visibility (
+                contact_id TEXT PRIMARY KEY,
+                visible BOOLEAN NOT NULL,
+                FOREIGN KEY (contact_id) REFERENCES contacts (id)
+            );
+        ",
+    },
+    Migration {
+        version: 10,
+        description: "message read markers",
+        sql: "
+            ALTER TABLE messages ADD COLUMN read_at INTEGER;
+            -- History predates read tracking; don't turn it all into unread messages
+            UPDATE messages SET read_at = created_at WHERE is_from_me = 0;
+            CREATE INDEX idx_messages_contact_timestamp ON messages (contact_id, timestamp);
+            CREATE INDEX idx_messages_unread ON messages (contact_id) WHERE is_from_me = 0 AND read_at IS NULL;
+        ",
+    },
+    Migration {
+        version: 11,
+        description: "seen receipt envelopes",
+        sql: "
+            CREATE TABLE seen_envelopes (
+                id TEXT PRIMARY KEY,
+                seen_at INTEGER NOT NULL
+            );
+
+            CREATE INDEX idx_seen_envelopes_seen_at ON seen_envelopes (seen_at);
+        ",
+    },
+    Migration {
+        version: 12,
+        description: "received envelopes awaiting processing",
+        sql: "
+            CREATE TABLE pending_envelopes (
+                id TEXT PRIMARY KEY,
+                envelope TEXT NOT NULL,
+                timestamp INTEGER NOT NULL,
+                received_at INTEGER NOT NULL,
+                attempts INTEGER NOT NULL DEFAULT 0,
+                last_error TEXT
+            );
+        ",
+    },
+    Migration {
+        version: 13,
+        description: "outbox entries without a message row",
+        // Receipts are queued in the outbox but never stored as messages
+        sql: "
+            CREATE TABLE outbox_new (
+                message_id TEXT PRIMARY KEY,
+                contact_id TEXT NOT NULL,
+                recipient_contact_code TEXT NOT NULL,
+                message_type TEXT NOT NULL,
+                encrypted_message TEXT NOT NULL,
+                timestamp INTEGER NOT NULL,
+                status TEXT NOT NULL DEFAULT 'pending',
+                attempts INTEGER NOT NULL DEFAULT 0,
+                next_attempt_at INTEGER NOT NULL,
+                last_error TEXT,
+                created_at INTEGER NOT NULL
+            );
+
+            INSERT INTO outbox_new SELECT * FROM outbox;
+            DROP TABLE outbox;
+            ALTER TABLE outbox_new RENAME TO outbox;
+
+            CREATE INDEX idx_outbox_next_attempt ON outbox (status, next_attempt_at);
+        ",
+    },
+];
+
+pub fn latest_version() -> u32 {
+    MIGRATIONS.last().map(|m| m.version).unwrap_or(0)
+}
+
+pub fn current_version(conn: &Connection) -> Result<u32> {
+    Ok(conn.query_row("PRAGMA user_version", [], |row| row.get(0))?)
+}
+
+/// Bring the schema up to date, one transaction per migration.
+///
+/// When `db_path` is given and the database already holds data, a copy is
+/// written next to it before the first pending migration runs. The copy holds
+/// the same secrets as the database, unencrypted columns included, so it is
+/// only kept when a migration fails and is deleted once they all commit.
+pub fn run(conn: &mut Connection, db_path: Option<&Path>) -> Result<u32> {
+    let current = current_version(conn)?;
+    let latest = latest_version();
+
+    if current > latest {
+        return Err(anyhow!(
+            "Database schema version {} is newer than this build supports ({})",
+            current, latest
+        ));
+    }
+    if current == latest {
+        return Ok(current);
+    }
+
+    let mut backup = None;
+    if let Some(path) = db_path {
+        if has_user_tables(conn)? {
+            let path = backup_path(path, current);
+            if path.exists() {
+                std::fs::remove_file(&path)?;
+            }
+            conn.execute("VACUUM INTO ?1", [path.to_string_lossy()])?;
+            log::info!("Backed up database to {:?} before migrating", path);
+            backup = Some(path);
+        }
+    }
+
+    for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration.sql)
+            .map_err(|e| anyhow!("Migration {} ({}) failed: {}", migration.version, migration.description, e))?;
+        tx.pragma_update(None, "user_version", migration.version)?;
+        tx.commit()?;
+        log::info!("Applied database migration {}: {}", migration.version, migration.description);
+    }
+
+    if let Some(path) = backup {
+        std::fs::remove_file(&path)?;
+        log::info!("Removed pre-migration backup {:?}", path);
+    }
+
+    Ok(latest)
+}
+
+pub fn backup_path(db_path: &Path, version: u32) -> PathBuf {
+    let mut name = db_path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".v{}.bak", version));
+    db_path.with_file_name(name)
+}
+
+fn has_user_tables(conn: &Connection) -> Result<bool> {
+    let count: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name NOT LIKE 'sqlite_%'",
+        [],
+        |row| row.get(0),
+    )?;
+    Ok(count > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_db_path() -> PathBuf {
+        std::env::temp_dir().join(format!("nonmessenger-migrate-{}.db", uuid::Uuid::new_v4()))
+    }
+
+    /// Build a database as a release at `version` would have left it
+    fn fixture_at(path: &Path, version: u32) -> Connection {
+        let conn = Connection::open(path).unwrap();
+        // Version 0 is a pre-framework install: the initial tables without a user_version
+        let applied = version.max(1);
+        for migration in MIGRATIONS.iter().filter(|m| m.version <= applied) {
+            conn.execute_batch(migration.sql).unwrap();
+        }
+        conn.pragma_update(None, "user_version", version).unwrap();
+
+        conn.execute(
+            "INSERT INTO contacts (id, name, contact_code, public_key, device_id, created_at)
+             VALUES ('alice', 'Alice', '[]', 'key', 'device', 1)",
+            [],
+        ).unwrap();
+        conn.execute(
+            "INSERT INTO messages (id, contact_id, content, is_from_me, timestamp, created_at)
+             VALUES ('m1', 'alice', 'hello', 1, 1, 1)",
+            [],
+        ).unwrap();
+        conn
+    }
+
+    #[test]
+    fn test_upgrade_from_every_historical_version() {
+        for version in 0..latest_version() {
+            let path = temp_db_path();
+            let mut conn = fixture_at(&path, version);
+
+            assert_eq!(run(&mut conn, Some(&path)).unwrap(), latest_version());
+            assert_eq!(current_version(&conn).unwrap(), latest_version());
+
+            for table in ["sessions", "key_changes", "outgoing_contact_requests", "database_keys", "outbox", "settings", "presence_visibility", "seen_envelopes", "pending_envelopes"] {
+                let exists: i64 = conn.query_row(
+                    "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?1",
+                    [table],
+                    |row| row.get(0),
+                ).unwrap();
+                assert_eq!(exists, 1, "{} missing after upgrade from v{}", table, version);
+            }
+
+            let content: String = conn
+                .query_row("SELECT content FROM messages WHERE id = 'm1'", [], |row| row.get(0))
+                .unwrap();
+            assert_eq!(content, "hello");
+
+            // Receipts are queued without a message row
+            conn.pragma_update(None, "foreign_keys", true).unwrap();
+            conn.execute(
+                "INSERT INTO outbox (message_id, contact_id, recipient_contact_code, message_type, encrypted_message, timestamp, next_attempt_at, created_at)
+                 VALUES ('receipt-1', 'alice', 'code', 'read_receipt', '{}', 1, 1, 1)",
+                [],
+            ).unwrap();
+
+            // The backup holds plaintext secrets, so it is gone once the upgrade commits
+            assert!(!backup_path(&path, version).exists());
+
+            drop(conn);
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_backup_kept_when_a_migration_fails() {
+        let path = temp_db_path();
+        let version = latest_version() - 1;
+        let mut conn = fixture_at(&path, version);
+        // Left behind by an interrupted rebuild, so the last migration cannot create it
+        conn.execute_batch("CREATE TABLE outbox_new (id TEXT)").unwrap();
+
+        assert!(run(&mut conn, Some(&path)).is_err());
+        assert_eq!(current_version(&conn).unwrap(), version);
+
+        let backup = backup_path(&path, version);
+        let backup_conn = Connection::open(&backup).unwrap();
+        assert_eq!(current_version(&backup_conn).unwrap(), version);
+
+        drop(backup_conn);
+        drop(conn);
+        std::fs::remove_file(&backup).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_fresh_database_and_rerun() {
+        let path = temp_db_path();
+        let mut conn = Connection::open(&path).unwrap();
+
+        assert_eq!(run(&mut conn, Some(&path)).unwrap(), latest_version());
+        assert!(!backup_path(&path, 0).exists());
+        // Running again is a no-op
+        assert_eq!(run(&mut conn, Some(&path)).unwrap(), latest_version());
+
+        conn.pragma_update(None, "user_version", latest_version() + 1).unwrap();
+        assert!(run(&mut conn, Some(&path)).is_err());
+
+        drop(conn);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -13,6 +13,44 @@ pub struct Contact {
     pub created_at: i64,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyChange {
+    pub contact_id: String,
+    pub old_public_key: String,
+    pub new_public_key: String,
+    pub changed_at: i64,
+    pub acknowledged: bool,
+}
+
+/// An encrypted outbound message waiting for the server to accept it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutboxEntry {
+    pub message_id: String,
+    pub contact_id: String,
+    pub recipient_contact_code: String,
+    pub message_type: String,
+    pub encrypted_message: crate::crypto::EncryptedMessage,
+    pub timestamp: i64,
+    /// "pending" while retries remain, "failed" once they are exhausted
+    pub status: String,
+    pub attempts: i32,
+    pub next_attempt_at: i64,
+    pub last_error: Option<String>,
+    pub created_at: i64,
+}
+
+/// A received envelope kept until it has been processed. Servers forget an
+/// envelope once they hand it out, so failures are retried from here.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingEnvelope {
+    pub envelope: MessageEnvelope,
+    /// Server pool time, which orders retries
+    pub timestamp: i64,
+    pub received_at: i64,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Message {
     pub id: String,
@@ -38,6 +76,16 @@ pub struct ContactRequest {
     pub received_at: i64,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutgoingContactRequest {
+    pub id: String,
+    pub recipient_name: String,
+    pub recipient_words: Vec<String>,
+    pub verification_message: String,
+    pub status: String,
+    pub sent_at: i64,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UserProfile {
     pub id: String,
@@ -55,11 +103,37 @@ pub struct UserProfile {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ServerNode {
     pub url: String,
+    /// Key the node advertised, pinned on first use. Informational only: no
+    /// node response is signed, so nothing is verified against it.
     pub public_key: String,
     pub is_active: bool,
     pub last_ping: i64,
     pub response_time: i64,
     pub priority: i32,
+    /// Key a node advertised that differs from the pinned `public_key`, awaiting user review
+    #[serde(default)]
+    pub pending_public_key: Option<String>,
+}
+
+/// Entry in a node's `GET /api/nodes` answer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AdvertisedNode {
+    pub node_url: String,
+    #[serde(default)]
+    pub public_key: Option<String>,
+    #[serde(default)]
+    pub last_seen: i64,
+}
+
+/// Entry in a `GET /api/messages/:contactCode` answer; the server keeps our
+/// whole signed envelope in `encryptedMessage`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PooledMessage {
+    pub id: String,
+    pub encrypted_message: MessageEnvelope,
+    pub timestamp: i64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -70,6 +144,10 @@ pub struct MessageEnvelope {
     pub timestamp: i64,
     pub ttl: i64,
     pub message_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -83,6 +161,8 @@ pub struct ContactRequestMessage {
     pub verification_message: String,
     pub sender_public_key: String,
     pub version: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -94,7 +174,12 @@ pub struct ContactResponseMessage {
     pub accepted: bool,
     pub secret_words: Option<Vec<String>>,
     pub recipient_public_key: Option<String>,
+    /// Responder's device id, covered by the signature; absent from older peers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recipient_device_id: Option<String>,
     pub version: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -106,6 +191,16 @@ pub struct VoiceCallMessage {
     pub caller_id: Option<String>,
     pub recipient_id: Option<String>,
     pub version: String,
+    /// Call offers only: the per-call media key, sealed to the recipient
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encrypted_key: Option<String>,
+    /// Call accepts only: the callee's random contribution to the media key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_share: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -119,7 +214,10 @@ pub struct VoiceDataMessage {
     pub version: String,
 }
 
+/// Awareness protocol message; field names and millisecond timestamps follow
+/// `shared/protocols/awareness.js`
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct AwarenessMessage {
     pub r#type: String,
     pub user_id: String,
@@ -132,6 +230,7 @@ pub struct AwarenessMessage {
     pub delivery_status: Option<String>,
     pub is_online: Option<bool>,
     pub connection_quality: Option<f32>,
+    pub last_seen: Option<i64>,
     pub version: String,
 }
 
@@ -164,6 +263,51 @@ pub struct CallStatus {
     pub start_time: Option<i64>,
     pub duration: Option<i64>,
     pub is_incoming: bool,
+    /// Receive-side audio statistics while call audio is running
+    pub audio: Option<CallAudioStats>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CallAudioStats {
+    pub packets_received: u64,
+    pub packets_late: u64,
+    pub frames_concealed: u64,
+    pub loss_percent: f64,
+    pub jitter_ms: f64,
+    pub buffer_depth_ms: i64,
+    pub target_delay_ms: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioDirection {
+    Input,
+    Output,
+}
+
+impl AudioDirection {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AudioDirection::Input => "input",
+            AudioDirection::Output => "output",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AudioDeviceInfo {
+    pub name: String,
+    pub is_default: bool,
+    /// The user's choice, or the default if they haven't made one
+    pub is_selected: bool,
+    /// In use by the call manager; differs from the selection after a fallback
+    pub is_active: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AudioDevices {
+    pub inputs: Vec<AudioDeviceInfo>,
+    pub outputs: Vec<AudioDeviceInfo>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -198,7 +342,7 @@ pub enum MessageType {
     VoiceData,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum DeliveryStatus {
     Sending,
     Sent,
@@ -207,7 +351,7 @@ pub enum DeliveryStatus {
     Failed,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ContactStatus {
     Online,
     Offline,
@@ -279,6 +423,63 @@ impl UserProfile {
     }
 }
 
+impl ContactStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ContactStatus::Online => "online",
+            ContactStatus::Offline => "offline",
+            ContactStatus::Away => "away",
+            ContactStatus::Busy => "busy",
+            ContactStatus::Invisible => "invisible",
+        }
+    }
+
+    pub fn parse(status: &str) -> Option<Self> {
+        match status {
+            "online" => Some(ContactStatus::Online),
+            "offline" => Some(ContactStatus::Offline),
+            "away" => Some(ContactStatus::Away),
+            "busy" => Some(ContactStatus::Busy),
+            "invisible" => Some(ContactStatus::Invisible),
+            _ => None,
+        }
+    }
+}
+
+impl DeliveryStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeliveryStatus::Sending => "sending",
+            DeliveryStatus::Sent => "sent",
+            DeliveryStatus::Delivered => "delivered",
+            DeliveryStatus::Read => "read",
+            DeliveryStatus::Failed => "failed",
+        }
+    }
+
+    pub fn parse(status: &str) -> Option<Self> {
+        match status {
+            "sending" => Some(DeliveryStatus::Sending),
+            "sent" => Some(DeliveryStatus::Sent),
+            "delivered" => Some(DeliveryStatus::Delivered),
+            "read" => Some(DeliveryStatus::Read),
+            "failed" => Some(DeliveryStatus::Failed),
+            _ => None,
+        }
+    }
+
+    /// Position in sending -> sent -> delivered -> read. Failed ranks with
+    /// sending so a late receipt still moves the message forward.
+    pub fn rank(&self) -> u8 {
+        match self {
+            DeliveryStatus::Sending | DeliveryStatus::Failed => 0,
+            DeliveryStatus::Sent => 1,
+            DeliveryStatus::Delivered => 2,
+            DeliveryStatus::Read => 3,
+        }
+    }
+}
+
 impl ServerNode {
     pub fn is_healthy(&self) -> bool {
         let now = chrono::Utc::now().timestamp();
@@ -1,30 +1,352 @@
 use crate::models::*;
+use crate::crypto::IdentityKeyPair;
+use crate::signing::{self, SignedEnvelope};
+use crate::utils::Logger;
 use anyhow::{Result, anyhow};
+use futures_util::stream::{SplitSink, SplitStream};
 use futures_util::{SinkExt, StreamExt};
 use reqwest::Client;
 use serde_json::Value;
+use std::collections::HashMap;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio::time::MissedTickBehavior;
 use tokio_tungstenite::{connect_async, tungstenite::Message as WsMessage};
 use url::Url;
 
+/// Server answer to `POST /api/message`
+#[derive(Debug, Clone, Copy)]
+pub struct SendOutcome {
+    /// Pushed to the recipient over an open WebSocket
+    pub delivered: bool,
+    /// Held in the pool until the recipient fetches it
+    pub pooled: bool,
+}
+
+impl SendOutcome {
+    pub fn delivery_status(&self) -> &'static str {
+        if self.delivered {
+            "delivered"
+        } else {
+            "sent"
+        }
+    }
+}
+
+/// Largest serialized `encryptedMessage` the server accepts. That field carries
+/// the whole signed envelope; the rest of the request body stays well within
+/// the server's 4096 byte total.
+pub const SERVER_MAX_ENVELOPE_BYTES: usize = 3072;
+
+/// How far the timestamp of signed traffic that is never pooled may be from
+/// our clock. Anything older can only be a replay.
+pub const SIGNALING_WINDOW_SECS: i64 = 60;
+
+/// Peer-to-peer message types sent inside `real_time_message`
+const RELAYED_TYPES: &[&str] = &["voice_call_init", "voice_call_accept", "voice_call_reject", "voice_call_end", "voice_data"];
+
+type WsStream = tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+/// Contact public key -> contact id. Replaced whole by `set_trusted_contacts`,
+/// so readers take a cheap snapshot instead of copying the map per frame.
+type TrustedKeys = Arc<Mutex<Arc<HashMap<String, String>>>>;
+
+/// Ping cadence and how long a silent connection is tolerated before it is dropped
+#[derive(Debug, Clone, Copy)]
+pub struct HeartbeatConfig {
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(15),
+            timeout: Duration::from_secs(45),
+        }
+    }
+}
+
+/// The live WebSocket: a writer task fed by `outgoing` and a reader task
+struct ConnectionHandle {
+    id: u64,
+    outgoing: mpsc::UnboundedSender<WsMessage>,
+    reader: JoinHandle<()>,
+}
+
+/// State the reader task needs to dispatch frames and tear the connection down
+struct ReaderContext {
+    id: u64,
+    connection: Arc<Mutex<Option<ConnectionHandle>>>,
+    is_connected: Arc<Mutex<bool>>,
+    connection_tx: Arc<watch::Sender<bool>>,
+    trusted_keys: TrustedKeys,
+    incoming_tx: Option<mpsc::UnboundedSender<Value>>,
+    outgoing: mpsc::UnboundedSender<WsMessage>,
+    heartbeat: HeartbeatConfig,
+}
+
+/// Writes to whichever WebSocket connection is current, without the client lock
+#[derive(Clone)]
+pub struct WebSocketSender {
+    connection: Arc<Mutex<Option<ConnectionHandle>>>,
+}
+
+impl WebSocketSender {
+    /// Relay one voice frame to the peer's connection
+    pub async fn send_voice_data(&self, recipient_contact_code: &str, call_id: &str, encrypted_audio_data: &str, sequence_number: i32) -> Result<()> {
+        let message = VoiceDataMessage {
+            r#type: "VOICE_DATA".to_string(),
+            id: uuid::Uuid::new_v4().to_string(),
+            timestamp: chrono::Utc::now().timestamp(),
+            call_id: call_id.to_string(),
+            encrypted_audio_data: encrypted_audio_data.to_string(),
+            sequence_number,
+            version: "1.0".to_string(),
+        };
+
+        self.send(&serde_json::json!({
+            "type": "real_time_message",
+            "recipientContactCode": recipient_contact_code,
+            "message": message,
+        })).await
+    }
+
+    pub async fn send<T: serde::Serialize>(&self, message: &T) -> Result<()> {
+        let json = serde_json::to_string(message)?;
+        let connection = self.connection.lock().await;
+
+        match connection.as_ref() {
+            Some(handle) => handle.outgoing.send(WsMessage::Text(json))
+                .map_err(|_| anyhow!("WebSocket writer has stopped")),
+            None => Err(anyhow!("WebSocket not connected")),
+        }
+    }
+}
+
+/// Signs and posts envelopes to the current server, without the client lock
+#[derive(Clone)]
+pub struct EnvelopeSender {
+    client: Client,
+    server_url: Arc<Mutex<Option<String>>>,
+    identity_key: Arc<Mutex<Option<String>>>,
+}
+
+impl EnvelopeSender {
+    async fn sign<T: SignedEnvelope>(&self, envelope: &mut T) -> Result<()> {
+        let identity_key = self.identity_key.lock().await;
+        let private_key = identity_key.as_ref()
+            .ok_or_else(|| anyhow!("No identity key set for signing"))?;
+        signing::sign_envelope(envelope, private_key)
+    }
+
+    async fn public_identity_key(&self) -> Result<String> {
+        let identity_key = self.identity_key.lock().await;
+        let private_key = identity_key.as_ref()
+            .ok_or_else(|| anyhow!("No identity key set for signing"))?;
+        Ok(IdentityKeyPair::from_private_key(private_key)?.public_key().encode())
+    }
+
+    /// Sign and post a queued, already-encrypted message to the recipient's contact code
+    pub async fn send_message(&self, entry: &OutboxEntry) -> Result<SendOutcome> {
+        let mut envelope = MessageEnvelope {
+            id: entry.message_id.clone(),
+            recipient_contact_code: entry.recipient_contact_code.clone(),
+            encrypted_message: entry.encrypted_message.clone(),
+            timestamp: entry.timestamp,
+            ttl: 86400000, // 24 hours
+            message_type: entry.message_type.clone(),
+            sender_key: Some(self.public_identity_key().await?),
+            signature: None,
+        };
+        self.sign(&mut envelope).await?;
+
+        let response = self.send_envelope(&envelope).await?;
+        if response["success"].as_bool() == Some(false) {
+            return Err(anyhow!("Server rejected message: {}", response["error"].as_str().unwrap_or("unknown error")));
+        }
+
+        Ok(SendOutcome {
+            delivered: response["delivered"].as_bool().unwrap_or(false),
+            pooled: response["pooled"].as_bool().unwrap_or(false),
+        })
+    }
+
+    /// Post a signed envelope to the message pool for store-and-forward delivery.
+    ///
+    /// The server only relays `encryptedMessage`, so the whole envelope goes there
+    /// to keep the sender signature intact end to end.
+    pub async fn send_envelope(&self, envelope: &MessageEnvelope) -> Result<Value> {
+        let server_url = {
+            let url = self.server_url.lock().await;
+            url.clone().ok_or_else(|| anyhow!("Not connected to server"))?
+        };
+
+        let body = serde_json::json!({
+            "recipientContactCode": envelope.recipient_contact_code,
+            "encryptedMessage": envelope,
+            "messageId": envelope.id,
+            "ttl": envelope.ttl,
+        });
+
+        let response = self.client
+            .post(&format!("{}/api/message", server_url))
+            .json(&body)
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(anyhow!("Failed to send envelope: {}", response.status()));
+        }
+
+        Ok(response.json().await?)
+    }
+}
+
+/// Reads and clears mailboxes on every node, without the client lock
+#[derive(Clone)]
+pub struct MailboxReader {
+    client: Client,
+    trusted_keys: TrustedKeys,
+}
+
+impl MailboxReader {
+    /// Envelopes pooled for `contact_code` on `server_url` while we were offline
+    pub async fn get_messages(&self, server_url: &str, contact_code: &str) -> Result<Vec<PooledMessage>> {
+        let mut url = Url::parse(server_url)?;
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("Invalid server URL: {}", server_url))?
+            .pop_if_empty()
+            .extend(["api", "messages", contact_code]);
+
+        let response = self.client.get(url).send().await?;
+
+        if !response.status().is_success() {
+            return Err(anyhow!("Failed to get messages: {}", response.status()));
+        }
+
+        let json: Value = response.json().await?;
+        let entries: Vec<Value> = serde_json::from_value(json["messages"].clone())?;
+
+        // One malformed entry should not hide the rest of the mailbox
+        let mut messages = Vec::new();
+        for entry in entries {
+            match serde_json::from_value::<PooledMessage>(entry) {
+                Ok(message) => messages.push(message),
+                Err(e) => log::warn!("Skipping malformed pooled message from {}: {}", server_url, e),
+            }
+        }
+
+        Ok(messages)
+    }
+
+    /// Remove a fetched message from the pool via `DELETE /api/message/:id`
+    pub async fn acknowledge_message(&self, server_url: &str, message_id: &str) -> Result<()> {
+        let mut url = Url::parse(server_url)?;
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("Invalid server URL: {}", server_url))?
+            .pop_if_empty()
+            .extend(["api", "message", message_id]);
+
+        let response = self.client.delete(url).send().await?;
+
+        if !response.status().is_success() {
+            return Err(anyhow!("Failed to acknowledge message: {}", response.status()));
+        }
+
+        Ok(())
+    }
+
+    /// Apply the WebSocket signature and sender checks to an envelope fetched over HTTP
+    pub async fn verify_envelope(&self, envelope: &MessageEnvelope) -> Result<()> {
+        let message = serde_json::json!({ "type": "new_message", "message": envelope });
+        let trusted_keys = Arc::clone(&*self.trusted_keys.lock().await);
+        MessagePoolClient::verify_incoming("new_message", &message, &trusted_keys)
+    }
+}
+
 pub struct MessagePoolClient {
     client: Client,
-    websocket: Arc<Mutex<Option<tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>>>>,
+    connection: Arc<Mutex<Option<ConnectionHandle>>>,
+    next_connection_id: AtomicU64,
+    heartbeat: HeartbeatConfig,
     server_url: Arc<Mutex<Option<String>>>,
     is_connected: Arc<Mutex<bool>>,
+    identity_key: Arc<Mutex<Option<String>>>,
+    trusted_keys: TrustedKeys,
+    incoming_tx: Option<mpsc::UnboundedSender<Value>>,
+    connection_tx: Arc<watch::Sender<bool>>,
 }
 
 impl MessagePoolClient {
     pub fn new() -> Self {
         Self {
             client: Client::new(),
-            websocket: Arc::new(Mutex::new(None)),
+            connection: Arc::new(Mutex::new(None)),
+            next_connection_id: AtomicU64::new(1),
+            heartbeat: HeartbeatConfig::default(),
             server_url: Arc::new(Mutex::new(None)),
             is_connected: Arc::new(Mutex::new(false)),
+            identity_key: Arc::new(Mutex::new(None)),
+            trusted_keys: Arc::new(Mutex::new(Arc::new(HashMap::new()))),
+            incoming_tx: None,
+            connection_tx: Arc::new(watch::channel(false).0),
         }
     }
 
+    /// Receive every incoming message that passed signature checks.
+    /// Must be called before `connect`.
+    pub fn subscribe_incoming(&mut self) -> mpsc::UnboundedReceiver<Value> {
+        let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
+        self.incoming_tx = Some(incoming_tx);
+        incoming_rx
+    }
+
+    /// Observe connection state changes, e.g. to flush queued messages on reconnect
+    pub fn subscribe_connection(&self) -> watch::Receiver<bool> {
+        self.connection_tx.subscribe()
+    }
+
+    pub async fn is_connected(&self) -> bool {
+        *self.is_connected.lock().await
+    }
+
+    /// Applies to connections opened after the call
+    pub fn set_heartbeat(&mut self, heartbeat: HeartbeatConfig) {
+        self.heartbeat = heartbeat;
+    }
+
+    pub async fn server_url(&self) -> Option<String> {
+        self.server_url.lock().await.clone()
+    }
+
+    /// Set the identity private key used to sign outbound envelopes
+    pub async fn set_identity(&self, private_key: &str) -> Result<()> {
+        IdentityKeyPair::from_private_key(private_key)?;
+        let mut identity_key = self.identity_key.lock().await;
+        *identity_key = Some(private_key.to_string());
+        Ok(())
+    }
+
+    /// Replace the set of sender keys accepted on incoming envelopes
+    pub async fn set_trusted_contacts(&self, contacts: &[Contact]) {
+        let trusted_keys = contacts.iter()
+            .map(|contact| (contact.public_key.clone(), contact.id.clone()))
+            .collect();
+        *self.trusted_keys.lock().await = Arc::new(trusted_keys);
+    }
+
+    async fn sign<T: SignedEnvelope>(&self, envelope: &mut T) -> Result<()> {
+        self.envelope_sender().sign(envelope).await
+    }
+
+    async fn public_identity_key(&self) -> Result<String> {
+        self.envelope_sender().public_identity_key().await
+    }
+
     pub async fn connect(&mut self, server_url: &str) -> Result<()> {
         // Store server URL
         {
@@ -45,36 +367,40 @@ impl MessagePoolClient {
         let url = Url::parse(&ws_url)?;
         
         let (ws_stream, _) = connect_async(url).await?;
-        
-        {
-            let mut websocket = self.websocket.lock().await;
-            *websocket = Some(ws_stream);
-        }
+
+        Self::close_connection(&self.connection, None, &self.is_connected, &self.connection_tx).await;
+
+        // Hold the slot while the tasks start so a reader that fails at once
+        // tears down this connection rather than finding nothing to close
+        let mut connection = self.connection.lock().await;
+        let id = self.next_connection_id.fetch_add(1, Ordering::SeqCst);
+        let (sink, stream) = ws_stream.split();
+        let (outgoing_tx, outgoing_rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(Self::run_writer(sink, outgoing_rx));
+        let reader = tokio::spawn(Self::run_reader(stream, ReaderContext {
+            id,
+            connection: Arc::clone(&self.connection),
+            is_connected: Arc::clone(&self.is_connected),
+            connection_tx: Arc::clone(&self.connection_tx),
+            trusted_keys: Arc::clone(&self.trusted_keys),
+            incoming_tx: self.incoming_tx.clone(),
+            outgoing: outgoing_tx.clone(),
+            heartbeat: self.heartbeat,
+        }));
 
         {
             let mut connected = self.is_connected.lock().await;
             *connected = true;
         }
-
-        // Start message listening loop
-        self.start_message_listener().await;
+        self.connection_tx.send_replace(true);
+        *connection = Some(ConnectionHandle { id, outgoing: outgoing_tx, reader });
 
         Ok(())
     }
 
     pub async fn disconnect(&mut self) -> Result<()> {
-        {
-            let mut websocket = self.websocket.lock().await;
-            if let Some(ws) = websocket.take() {
-                // Close WebSocket connection
-                drop(ws);
-            }
-        }
-
-        {
-            let mut connected = self.is_connected.lock().await;
-            *connected = false;
-        }
+        Self::close_connection(&self.connection, None, &self.is_connected, &self.connection_tx).await;
 
         {
             let mut url = self.server_url.lock().await;
@@ -84,127 +410,213 @@ impl MessagePoolClient {
         Ok(())
     }
 
-    pub async fn send_message(&self, message: &Message) -> Result<()> {
-        let server_url = {
-            let url = self.server_url.lock().await;
-            url.clone().ok_or_else(|| anyhow!("Not connected to server"))?
+    /// Close the connection `id` (or whichever is open for `None`) and mark us offline
+    async fn close_connection(
+        connection: &Mutex<Option<ConnectionHandle>>,
+        id: Option<u64>,
+        is_connected: &Mutex<bool>,
+        connection_tx: &watch::Sender<bool>,
+    ) {
+        let handle = {
+            let mut connection = connection.lock().await;
+            match connection.as_ref() {
+                Some(handle) if id.map_or(true, |id| id == handle.id) => connection.take(),
+                _ => None,
+            }
         };
 
-        let envelope = MessageEnvelope {
-            id: message.id.clone(),
-            recipient_contact_code: message.contact_id.clone(), // This should be the actual contact code
-            encrypted_message: crate::crypto::EncryptedMessage {
-                encrypted_message: message.encrypted_content.clone(),
-                encrypted_key: String::new(), // Should be filled with actual encrypted key
-                iv: String::new(),
-                auth_tag: String::new(),
-            },
-            timestamp: message.timestamp,
-            ttl: 86400000, // 24 hours
-            message_type: message.message_type.clone(),
-        };
+        if let Some(handle) = handle {
+            // The writer sends the close frame and exits once the channel drains
+            let _ = handle.outgoing.send(WsMessage::Close(None));
+            if id.is_none() {
+                handle.reader.abort();
+            }
 
+            let mut connected = is_connected.lock().await;
+            *connected = false;
+            connection_tx.send_replace(false);
+        }
+    }
+
+    /// Sign and post a queued, already-encrypted message to the recipient's contact code
+    pub async fn send_message(&self, entry: &OutboxEntry) -> Result<SendOutcome> {
+        self.envelope_sender().send_message(entry).await
+    }
+
+    pub async fn send_envelope(&self, envelope: &MessageEnvelope) -> Result<Value> {
+        self.envelope_sender().send_envelope(envelope).await
+    }
+
+    /// Peer nodes known to `server_url`, from `GET /api/nodes`
+    pub async fn fetch_nodes(&self, server_url: &str) -> Result<Vec<AdvertisedNode>> {
         let response = self.client
-            .post(&format!("{}/api/message", server_url))
-            .json(&envelope)
+            .get(&format!("{}/api/nodes", server_url))
             .send()
             .await?;
 
         if !response.status().is_success() {
-            return Err(anyhow!("Failed to send message: {}", response.status()));
+            return Err(anyhow!("Failed to fetch nodes: {}", response.status()));
         }
 
-        Ok(())
+        let json: Value = response.json().await?;
+        Ok(serde_json::from_value(json["nodes"].clone())?)
     }
 
-    pub async fn get_messages(&self, contact_code: &str) -> Result<Vec<MessageEnvelope>> {
-        let server_url = {
-            let url = self.server_url.lock().await;
-            url.clone().ok_or_else(|| anyhow!("Not connected to server"))?
-        };
-
+    /// Announce a node to `server_url` via `POST /api/register-node`
+    pub async fn register_node(&self, server_url: &str, node_url: &str, public_key: &str) -> Result<()> {
         let response = self.client
-            .get(&format!("{}/api/messages/{}", server_url, contact_code))
+            .post(&format!("{}/api/register-node", server_url))
+            .json(&serde_json::json!({
+                "nodeUrl": node_url,
+                "publicKey": public_key,
+            }))
             .send()
             .await?;
 
         if !response.status().is_success() {
-            return Err(anyhow!("Failed to get messages: {}", response.status()));
+            return Err(anyhow!("Failed to register node: {}", response.status()));
         }
 
-        let json: Value = response.json().await?;
-        let messages: Vec<MessageEnvelope> = serde_json::from_value(json["messages"].clone())?;
-
-        Ok(messages)
+        Ok(())
     }
 
+    /// Offer a call; `encrypted_key` is the call's media key sealed to the recipient
     pub async fn send_voice_call_init(&self, call_id: &str, recipient_contact_code: &str, encrypted_key: &str) -> Result<()> {
-        let message = VoiceCallMessage {
+        let mut message = VoiceCallMessage {
             r#type: "VOICE_CALL_INIT".to_string(),
             id: uuid::Uuid::new_v4().to_string(),
             timestamp: chrono::Utc::now().timestamp(),
             call_id: call_id.to_string(),
-            caller_id: Some("self".to_string()), // Should be actual user ID
+            caller_id: None,
             recipient_id: Some(recipient_contact_code.to_string()),
             version: "1.0".to_string(),
+            encrypted_key: Some(encrypted_key.to_string()),
+            key_share: None,
+            sender_key: Some(self.public_identity_key().await?),
+            signature: None,
         };
+        self.sign(&mut message).await?;
 
-        self.send_websocket_message(&message).await
+        self.send_relayed(recipient_contact_code, &message).await
     }
 
-    pub async fn send_voice_call_accept(&self, call_id: &str) -> Result<()> {
-        let message = VoiceCallMessage {
+    /// Accept a call; `key_share` is mixed into the offered media key on both sides
+    pub async fn send_voice_call_accept(&self, call_id: &str, recipient_contact_code: &str, key_share: &str) -> Result<()> {
+        let mut message = VoiceCallMessage {
             r#type: "VOICE_CALL_ACCEPT".to_string(),
             id: uuid::Uuid::new_v4().to_string(),
             timestamp: chrono::Utc::now().timestamp(),
             call_id: call_id.to_string(),
             caller_id: None,
-            recipient_id: Some("self".to_string()),
+            recipient_id: Some(recipient_contact_code.to_string()),
             version: "1.0".to_string(),
+            encrypted_key: None,
+            key_share: Some(key_share.to_string()),
+            sender_key: Some(self.public_identity_key().await?),
+            signature: None,
         };
+        self.sign(&mut message).await?;
 
-        self.send_websocket_message(&message).await
+        self.send_relayed(recipient_contact_code, &message).await
     }
 
-    pub async fn send_voice_call_reject(&self, call_id: &str) -> Result<()> {
-        let message = VoiceCallMessage {
+    pub async fn send_voice_call_reject(&self, call_id: &str, recipient_contact_code: &str) -> Result<()> {
+        let mut message = VoiceCallMessage {
             r#type: "VOICE_CALL_REJECT".to_string(),
             id: uuid::Uuid::new_v4().to_string(),
             timestamp: chrono::Utc::now().timestamp(),
             call_id: call_id.to_string(),
             caller_id: None,
-            recipient_id: Some("self".to_string()),
+            recipient_id: Some(recipient_contact_code.to_string()),
             version: "1.0".to_string(),
+            encrypted_key: None,
+            key_share: None,
+            sender_key: Some(self.public_identity_key().await?),
+            signature: None,
         };
+        self.sign(&mut message).await?;
 
-        self.send_websocket_message(&message).await
+        self.send_relayed(recipient_contact_code, &message).await
     }
 
-    pub async fn send_voice_call_end(&self, call_id: &str) -> Result<()> {
-        let message = VoiceCallMessage {
+    pub async fn send_voice_call_end(&self, call_id: &str, recipient_contact_code: &str) -> Result<()> {
+        let mut message = VoiceCallMessage {
             r#type: "VOICE_CALL_END".to_string(),
             id: uuid::Uuid::new_v4().to_string(),
             timestamp: chrono::Utc::now().timestamp(),
             call_id: call_id.to_string(),
             caller_id: None,
-            recipient_id: None,
+            recipient_id: Some(recipient_contact_code.to_string()),
             version: "1.0".to_string(),
+            encrypted_key: None,
+            key_share: None,
+            sender_key: Some(self.public_identity_key().await?),
+            signature: None,
         };
+        self.sign(&mut message).await?;
 
-        self.send_websocket_message(&message).await
+        self.send_relayed(recipient_contact_code, &message).await
     }
 
-    pub async fn send_voice_data(&self, call_id: &str, encrypted_audio_data: &str, sequence_number: i32) -> Result<()> {
-        let message = VoiceDataMessage {
-            r#type: "VOICE_DATA".to_string(),
+    pub async fn send_voice_data(&self, recipient_contact_code: &str, call_id: &str, encrypted_audio_data: &str, sequence_number: i32) -> Result<()> {
+        self.websocket_sender().send_voice_data(recipient_contact_code, call_id, encrypted_audio_data, sequence_number).await
+    }
+
+    /// Handle for high-rate traffic such as voice frames, which must not wait
+    /// behind HTTP requests made while the client itself is locked
+    pub fn websocket_sender(&self) -> WebSocketSender {
+        WebSocketSender { connection: Arc::clone(&self.connection) }
+    }
+
+    /// Handle for posting envelopes, so slow HTTP requests need not hold the client lock
+    pub fn envelope_sender(&self) -> EnvelopeSender {
+        EnvelopeSender {
+            client: self.client.clone(),
+            server_url: Arc::clone(&self.server_url),
+            identity_key: Arc::clone(&self.identity_key),
+        }
+    }
+
+    /// Handle for polling node mailboxes, which visits every node in turn
+    pub fn mailbox_reader(&self) -> MailboxReader {
+        MailboxReader {
+            client: self.client.clone(),
+            trusted_keys: Arc::clone(&self.trusted_keys),
+        }
+    }
+
+    /// Sign and relay an envelope to a connected recipient without pooling it.
+    /// Used for ephemeral traffic like awareness updates; nothing is stored if
+    /// the recipient is offline.
+    pub async fn send_real_time_message(
+        &self,
+        recipient_contact_code: &str,
+        message_type: &str,
+        encrypted_message: crate::crypto::EncryptedMessage,
+    ) -> Result<()> {
+        let mut envelope = MessageEnvelope {
             id: uuid::Uuid::new_v4().to_string(),
+            recipient_contact_code: recipient_contact_code.to_string(),
+            encrypted_message,
             timestamp: chrono::Utc::now().timestamp(),
-            call_id: call_id.to_string(),
-            encrypted_audio_data: encrypted_audio_data.to_string(),
-            sequence_number,
-            version: "1.0".to_string(),
+            ttl: 0,
+            message_type: message_type.to_string(),
+            sender_key: Some(self.public_identity_key().await?),
+            signature: None,
         };
+        self.sign(&mut envelope).await?;
+
+        self.send_relayed(recipient_contact_code, &envelope).await
+    }
 
+    /// Have the server forward `message` to the recipient's open connections.
+    /// `real_time_message` is the only type the server relays between users.
+    async fn send_relayed<T: serde::Serialize>(&self, recipient_contact_code: &str, message: &T) -> Result<()> {
+        let message = serde_json::json!({
+            "type": "real_time_message",
+            "recipientContactCode": recipient_contact_code,
+            "message": message,
+        });
         self.send_websocket_message(&message).await
     }
 
@@ -250,32 +662,42 @@ impl MessagePoolClient {
         }
     }
 
+    /// Queue a frame for the writer task; never waits on the reader
     async fn send_websocket_message<T: serde::Serialize>(&self, message: &T) -> Result<()> {
-        let mut websocket = self.websocket.lock().await;
-        
-        if let Some(ws) = websocket.as_mut() {
-            let json = serde_json::to_string(message)?;
-            ws.send(WsMessage::Text(json)).await?;
-            Ok(())
-        } else {
-            Err(anyhow!("WebSocket not connected"))
-        }
+        self.websocket_sender().send(message).await
     }
 
-    async fn start_message_listener(&self) {
-        let websocket = Arc::clone(&self.websocket);
-        let is_connected = Arc::clone(&self.is_connected);
+    async fn run_writer(mut sink: SplitSink<WsStream, WsMessage>, mut outgoing: mpsc::UnboundedReceiver<WsMessage>) {
+        while let Some(message) = outgoing.recv().await {
+            let is_close = matches!(message, WsMessage::Close(_));
+            if let Err(e) = sink.send(message).await {
+                log::error!("WebSocket write failed: {}", e);
+                break;
+            }
+            if is_close {
+                break;
+            }
+        }
+        let _ = sink.close().await;
+    }
 
-        tokio::spawn(async move {
-            loop {
-                let mut ws_guard = websocket.lock().await;
-                
-                if let Some(ws) = ws_guard.as_mut() {
-                    match ws.next().await {
+    /// Dispatch incoming frames and ping the server. Any frame counts as a sign
+    /// of life; silence longer than the heartbeat timeout drops the connection.
+    async fn run_reader(mut stream: SplitStream<WsStream>, context: ReaderContext) {
+        let mut heartbeat = tokio::time::interval(context.heartbeat.interval);
+        heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut last_activity = Instant::now();
+
+        loop {
+            tokio::select! {
+                frame = stream.next() => {
+                    last_activity = Instant::now();
+                    match frame {
                         Some(Ok(WsMessage::Text(text))) => {
                             // Handle incoming message
                             if let Ok(json) = serde_json::from_str::<Value>(&text) {
-                                Self::handle_incoming_message(json).await;
+                                let trusted_keys = Arc::clone(&*context.trusted_keys.lock().await);
+                                Self::handle_incoming_message(json, &trusted_keys, &context.incoming_tx).await;
                             }
                         }
                         Some(Ok(WsMessage::Close(_))) => {
@@ -290,54 +712,107 @@ impl MessagePoolClient {
                             log::info!("WebSocket stream ended");
                             break;
                         }
+                        // Pings are answered by tungstenite; pongs only refresh activity
                         _ => {}
                     }
-                } else {
-                    break;
+                }
+                _ = heartbeat.tick() => {
+                    if last_activity.elapsed() > context.heartbeat.timeout {
+                        log::warn!("No WebSocket traffic for {:?}, dropping connection", last_activity.elapsed());
+                        break;
+                    }
+                    if context.outgoing.send(WsMessage::Ping(Vec::new())).is_err() {
+                        break;
+                    }
                 }
             }
+        }
 
-            // Mark as disconnected
-            let mut connected = is_connected.lock().await;
-            *connected = false;
-        });
+        // Mark as disconnected
+        Self::close_connection(&context.connection, Some(context.id), &context.is_connected, &context.connection_tx).await;
     }
 
-    async fn handle_incoming_message(message: Value) {
-        let message_type = message["type"].as_str().unwrap_or("");
-        
+    /// Check the sender signature of envelope-bearing messages.
+    ///
+    /// Contact requests and responses are self-signed because the sender is not
+    /// a contact yet; everything else must come from a known contact key.
+    fn verify_incoming(message_type: &str, message: &Value, trusted_keys: &HashMap<String, String>) -> Result<()> {
         match message_type {
-            "new_message" => {
-                // Handle new message
-                log::info!("Received new message");
-            }
-            "voice_call_init" => {
-                // Handle incoming voice call
-                log::info!("Received voice call initiation");
-            }
-            "voice_call_accept" => {
-                // Handle call acceptance
-                log::info!("Voice call accepted");
-            }
-            "voice_call_reject" => {
-                // Handle call rejection
-                log::info!("Voice call rejected");
-            }
-            "voice_call_end" => {
-                // Handle call end
-                log::info!("Voice call ended");
+            "new_message" | "real_time_message" => {
+                let envelope: MessageEnvelope = serde_json::from_value(message["message"].clone())?;
+                signing::verify_envelope(&envelope)?;
+
+                let is_handshake = message_type == "new_message"
+                    && matches!(envelope.message_type.as_str(), "contact_request" | "contact_response");
+                let sender_key = envelope.sender_key.as_deref().unwrap_or("");
+                if !is_handshake && !trusted_keys.contains_key(sender_key) {
+                    return Err(anyhow!("Message signed by unknown sender"));
+                }
+                if message_type == "real_time_message" {
+                    Self::check_fresh(envelope.timestamp)?;
+                }
+                Ok(())
             }
-            "voice_data" => {
-                // Handle voice data
-                log::debug!("Received voice data packet");
+            "voice_call_init" | "voice_call_accept" | "voice_call_reject" | "voice_call_end" => {
+                let call_message: VoiceCallMessage = serde_json::from_value(message.clone())?;
+                signing::verify_envelope(&call_message)?;
+
+                if !trusted_keys.contains_key(call_message.sender_key.as_deref().unwrap_or("")) {
+                    return Err(anyhow!("Call signaling signed by unknown sender"));
+                }
+                Self::check_fresh(call_message.timestamp)
             }
-            "status_update" => {
-                // Handle status update
-                log::info!("Received status update");
+            "contact_request" => {
+                let request: ContactRequestMessage = serde_json::from_value(message.clone())?;
+                signing::verify_envelope(&request)
             }
-            _ => {
-                log::warn!("Unknown message type: {}", message_type);
+            "contact_response" => {
+                let response: ContactResponseMessage = serde_json::from_value(message.clone())?;
+                signing::verify_envelope(&response)
             }
+            _ => Ok(()),
+        }
+    }
+
+    fn check_fresh(timestamp: i64) -> Result<()> {
+        let age = chrono::Utc::now().timestamp() - timestamp;
+        if age.abs() > SIGNALING_WINDOW_SECS {
+            return Err(anyhow!("Message timestamp is {}s away from our clock", age));
+        }
+        Ok(())
+    }
+
+    /// Relayed messages that are not awareness envelopes are checked and
+    /// dispatched as the message they carry
+    fn unwrap_relayed(message: Value) -> Value {
+        let is_relayed = message["type"].as_str() == Some("real_time_message")
+            && message["message"]["type"].as_str().map_or(false, |inner| RELAYED_TYPES.iter().any(|relayed| relayed.eq_ignore_ascii_case(inner)));
+        if is_relayed {
+            message["message"].clone()
+        } else {
+            message
+        }
+    }
+
+    async fn handle_incoming_message(
+        message: Value,
+        trusted_keys: &HashMap<String, String>,
+        incoming_tx: &Option<mpsc::UnboundedSender<Value>>,
+    ) {
+        let message = Self::unwrap_relayed(message);
+        let message_type = message["type"].as_str().unwrap_or("").to_ascii_lowercase();
+        let message_type = message_type.as_str();
+
+        if let Err(e) = Self::verify_incoming(message_type, &message, trusted_keys) {
+            Logger::log_security_event(
+                "Dropped message with invalid signature",
+                &format!("type={} reason={}", message_type, e),
+            );
+            return;
+        }
+
+        if let Some(incoming_tx) = incoming_tx {
+            let _ = incoming_tx.send(message);
         }
     }
 }
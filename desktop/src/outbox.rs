@@ -0,0 +1,244 @@
+use crate::database::Database;
+use crate::models::*;
+use crate::network::MessagePoolClient;
+use crate::receipts;
+use crate::AppState;
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+use tauri::{AppHandle, Manager};
+use tokio::sync::{Mutex, Notify};
+
+pub const EVENT_MESSAGE_STATUS_CHANGED: &str = "message-status-changed";
+
+/// How long the worker sleeps when nothing is scheduled
+const IDLE_WAIT_SECS: i64 = 300;
+
+#[derive(Debug, Clone)]
+pub struct OutboxConfig {
+    /// Attempts before a message is marked `failed`
+    pub max_attempts: i32,
+    pub base_delay_secs: i64,
+    pub max_delay_secs: i64,
+}
+
+impl Default for OutboxConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 8,
+            base_delay_secs: 5,
+            max_delay_secs: 15 * 60,
+        }
+    }
+}
+
+impl OutboxConfig {
+    /// Exponential delay before the next attempt, after `attempts` failures
+    pub fn retry_delay(&self, attempts: i32) -> i64 {
+        let exponent = (attempts.max(1) - 1).min(30) as u32;
+        self.base_delay_secs.saturating_mul(1i64 << exponent).min(self.max_delay_secs)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MessageStatusEvent {
+    pub message_id: String,
+    pub contact_id: String,
+    pub delivery_status: String,
+    /// Send attempts so far; absent when the change came from a receipt
+    pub attempts: Option<i32>,
+}
+
+/// Queue an encrypted message and wake the worker
+pub async fn enqueue(db: &Database, outbox: &Notify, entry: &OutboxEntry) -> Result<()> {
+    db.enqueue_outbox(entry).await?;
+    outbox.notify_one();
+    Ok(())
+}
+
+/// Background sender for the `outbox` table.
+///
+/// Entries survive restarts because they live in the database; the worker
+/// picks up whatever is due on start and whenever the connection comes back.
+pub struct OutboxWorker {
+    app: AppHandle,
+    database: Arc<Mutex<Database>>,
+    network: Arc<Mutex<MessagePoolClient>>,
+    wake: Arc<Notify>,
+    config: OutboxConfig,
+}
+
+impl OutboxWorker {
+    pub fn new(app: AppHandle, state: &AppState, config: OutboxConfig) -> Self {
+        Self {
+            app,
+            database: Arc::clone(&state.database),
+            network: Arc::clone(&state.network),
+            wake: Arc::clone(&state.outbox),
+            config,
+        }
+    }
+
+    pub async fn run(self) {
+        let mut connection = self.network.lock().await.subscribe_connection();
+
+        loop {
+            if let Err(e) = self.process_due().await {
+                log::warn!("Outbox pass failed: {}", e);
+            }
+
+            let wait = match self.database.lock().await.get_next_outbox_attempt().await {
+                Ok(Some(next)) => (next - chrono::Utc::now().timestamp()).clamp(0, IDLE_WAIT_SECS),
+                _ => IDLE_WAIT_SECS,
+            };
+
+            tokio::select! {
+                _ = self.wake.notified() => {}
+                changed = connection.changed() => {
+                    if changed.is_ok() && *connection.borrow() {
+                        log::info!("Connection restored, flushing outbox");
+                        let db = self.database.lock().await;
+                        if let Err(e) = db.reschedule_pending_outbox(chrono::Utc::now().timestamp()).await {
+                            log::warn!("Failed to reschedule outbox: {}", e);
+                        }
+                    }
+                }
+                _ = tokio::time::sleep(Duration::from_secs(wait as u64)) => {}
+            }
+        }
+    }
+
+    async fn process_due(&self) -> Result<()> {
+        // Post without the client lock so a slow server does not stall everything else
+        let sender = {
+            let network = self.network.lock().await;
+            if !network.is_connected().await {
+                return Ok(());
+            }
+            network.envelope_sender()
+        };
+
+        let now = chrono::Utc::now().timestamp();
+        let due = self.database.lock().await.get_due_outbox_entries(now).await?;
+
+        // Keep per-contact ordering: the query holds back entries queued behind
+        // one waiting for a retry, and a failure in this pass does the same
+        let mut blocked_contacts = HashSet::new();
+        for entry in due {
+            if blocked_contacts.contains(&entry.contact_id) {
+                continue;
+            }
+
+            let outcome = sender.send_message(&entry).await;
+            let db = self.database.lock().await;
+
+            match outcome {
+                Ok(outcome) => {
+                    db.delete_outbox_entry(&entry.message_id).await?;
+                    // Receipts have no message row; for messages, a receipt may already have overtaken us
+                    let status = DeliveryStatus::parse(outcome.delivery_status()).unwrap_or(DeliveryStatus::Sent);
+                    if !receipts::is_receipt_type(&entry.message_type)
+                        && db.advance_message_status(&entry.contact_id, &entry.message_id, status).await?
+                    {
+                        self.emit_status(&entry, status.as_str(), entry.attempts + 1);
+                    }
+                }
+                Err(e) => {
+                    blocked_contacts.insert(entry.contact_id.clone());
+                    let attempts = entry.attempts + 1;
+                    let error = e.to_string();
+
+                    if attempts >= self.config.max_attempts {
+                        log::warn!("Giving up on message {} after {} attempts: {}", entry.message_id, attempts, error);
+                        db.update_outbox_attempt(&entry.message_id, "failed", attempts, now, Some(&error)).await?;
+                        if !receipts::is_receipt_type(&entry.message_type) {
+                            db.update_message_status(&entry.message_id, "failed").await?;
+                            self.emit_status(&entry, "failed", attempts);
+                        }
+                    } else {
+                        let next_attempt_at = now + self.config.retry_delay(attempts);
+                        log::debug!("Message {} attempt {} failed: {}", entry.message_id, attempts, error);
+                        db.update_outbox_attempt(&entry.message_id, "pending", attempts, next_attempt_at, Some(&error)).await?;
+                        if !receipts::is_receipt_type(&entry.message_type) {
+                            self.emit_status(&entry, "sending", attempts);
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn emit_status(&self, entry: &OutboxEntry, delivery_status: &str, attempts: i32) {
+        let event = MessageStatusEvent {
+            message_id: entry.message_id.clone(),
+            contact_id: entry.contact_id.clone(),
+            delivery_status: delivery_status.to_string(),
+            attempts: Some(attempts),
+        };
+        if let Err(e) = self.app.emit_all(EVENT_MESSAGE_STATUS_CHANGED, event) {
+            log::warn!("Failed to emit {}: {}", EVENT_MESSAGE_STATUS_CHANGED, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::EncryptedMessage;
+    use crate::test_support::TempDatabase;
+
+    fn entry(message_id: &str, contact_id: &str, created_at: i64) -> OutboxEntry {
+        OutboxEntry {
+            message_id: message_id.to_string(),
+            contact_id: contact_id.to_string(),
+            recipient_contact_code: format!("{}-code", contact_id),
+            message_type: "text".to_string(),
+            encrypted_message: EncryptedMessage {
+                encrypted_message: "ciphertext".to_string(),
+                encrypted_key: String::new(),
+                iv: String::new(),
+                auth_tag: String::new(),
+                algorithm: String::new(),
+            },
+            timestamp: created_at,
+            status: "pending".to_string(),
+            attempts: 0,
+            next_attempt_at: created_at,
+            last_error: None,
+            created_at,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_messages_wait_behind_an_older_one_being_retried() {
+        let db = TempDatabase::open().await;
+        for (message_id, contact_id, created_at) in [("a1", "alice", 1), ("a2", "alice", 1), ("b1", "bob", 2)] {
+            db.enqueue_outbox(&entry(message_id, contact_id, created_at)).await.unwrap();
+        }
+        db.update_outbox_attempt("a1", "pending", 1, 100, Some("offline")).await.unwrap();
+
+        // Across passes, not just within one, a2 never overtakes a1
+        let due: Vec<String> = db.get_due_outbox_entries(50).await.unwrap().into_iter().map(|e| e.message_id).collect();
+        assert_eq!(due, vec!["b1".to_string()]);
+
+        db.delete_outbox_entry("b1").await.unwrap();
+        assert_eq!(db.get_next_outbox_attempt().await.unwrap(), Some(100));
+
+        let due: Vec<String> = db.get_due_outbox_entries(100).await.unwrap().into_iter().map(|e| e.message_id).collect();
+        assert_eq!(due, vec!["a1".to_string(), "a2".to_string()]);
+    }
+
+    #[test]
+    fn test_retry_delay_backs_off_exponentially() {
+        let config = OutboxConfig::default();
+
+        assert_eq!(config.retry_delay(1), 5);
+        assert_eq!(config.retry_delay(2), 10);
+        assert_eq!(config.retry_delay(4), 40);
+        assert_eq!(config.retry_delay(100), config.max_delay_secs);
+    }
+}
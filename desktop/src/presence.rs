@@ -0,0 +1,136 @@
+use crate::awareness;
+use crate::crypto::NonMessengerCrypto;
+use crate::database::Database;
+use crate::dispatcher::seal_ephemeral;
+use crate::models::*;
+use crate::network::MessagePoolClient;
+use crate::AppState;
+use anyhow::{Result, anyhow};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// Status `contact` gets to see. Invisible, or hidden from this contact,
+/// looks exactly like being offline.
+pub fn visible_status(status: ContactStatus, hidden_from_contact: bool) -> ContactStatus {
+    if hidden_from_contact || status == ContactStatus::Invisible {
+        ContactStatus::Offline
+    } else {
+        status
+    }
+}
+
+/// Our chosen status and custom message; online until the user picks one
+pub async fn current_status(db: &Database) -> Result<(ContactStatus, Option<String>)> {
+    let status = db.get_setting(awareness::SETTING_USER_STATUS).await?
+        .and_then(|status| ContactStatus::parse(&status))
+        .unwrap_or(ContactStatus::Online);
+    let custom_message = db.get_setting(awareness::SETTING_STATUS_MESSAGE).await?
+        .filter(|message| !message.is_empty());
+    Ok((status, custom_message))
+}
+
+/// Whether live activity such as typing may be shared with `contact`
+pub async fn may_share_activity(db: &Database, contact: &Contact) -> Result<bool> {
+    if !contact.is_verified || !db.get_presence_visibility(&contact.id).await? {
+        return Ok(false);
+    }
+    let (status, _) = current_status(db).await?;
+    Ok(visible_status(status, false) != ContactStatus::Offline)
+}
+
+/// Sends our status to verified contacts as encrypted, addressed
+/// `real_time_message` envelopes, never through the server-wide
+/// `status_update` broadcast.
+pub struct PresencePublisher {
+    crypto: Arc<NonMessengerCrypto>,
+    database: Arc<Mutex<Database>>,
+    network: Arc<Mutex<MessagePoolClient>>,
+}
+
+impl PresencePublisher {
+    pub fn new(state: &AppState) -> Self {
+        Self {
+            crypto: Arc::clone(&state.crypto),
+            database: Arc::clone(&state.database),
+            network: Arc::clone(&state.network),
+        }
+    }
+
+    /// Announce our status after every (re)connect
+    pub async fn run(self) {
+        let mut connection = self.network.lock().await.subscribe_connection();
+
+        loop {
+            if *connection.borrow_and_update() {
+                if let Err(e) = self.publish_all().await {
+                    log::warn!("Failed to publish presence: {}", e);
+                }
+            }
+
+            if connection.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Send our current status to every verified contact
+    pub async fn publish_all(&self) -> Result<usize> {
+        let contacts = self.database.lock().await.get_all_contacts().await?;
+        self.publish_to(&contacts, None).await
+    }
+
+    /// Send our status to the verified contacts among `contacts`, or
+    /// `status_override` instead, e.g. offline right before disconnecting
+    pub async fn publish_to(&self, contacts: &[Contact], status_override: Option<ContactStatus>) -> Result<usize> {
+        // Real-time messages are never queued, so there is nothing to do offline
+        if !self.network.lock().await.is_connected().await {
+            return Ok(0);
+        }
+
+        let outgoing = {
+            let db = self.database.lock().await;
+            let profile = db.get_user_profile().await?
+                .ok_or_else(|| anyhow!("No user profile found"))?;
+            let user_id = profile.contact_code.join(" ");
+            let (status, custom_message) = match status_override {
+                Some(status) => (status, None),
+                None => current_status(&db).await?,
+            };
+
+            let mut outgoing = Vec::new();
+            for contact in contacts.iter().filter(|c| c.is_verified) {
+                let hidden = !db.get_presence_visibility(&contact.id).await?;
+                let shown = visible_status(status, hidden);
+                let custom_message = if shown == status { custom_message.clone() } else { None };
+
+                let update = awareness::create_user_status(&user_id, shown, custom_message, awareness::now_millis());
+                // Recipients may be offline and never see this, so it stays off the ratchet
+                let encrypted = seal_ephemeral(&self.crypto, contact, &serde_json::to_string(&update)?)?;
+                outgoing.push((contact.contact_code.join(" "), encrypted));
+            }
+            outgoing
+        };
+
+        let network = self.network.lock().await;
+        let mut sent = 0;
+        for (contact_code, encrypted) in outgoing {
+            match network.send_real_time_message(&contact_code, awareness::USER_STATUS, encrypted).await {
+                Ok(()) => sent += 1,
+                Err(e) => log::debug!("Failed to send presence to {}: {}", contact_code, e),
+            }
+        }
+        Ok(sent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_invisible_and_hidden_look_offline() {
+        assert_eq!(visible_status(ContactStatus::Busy, false), ContactStatus::Busy);
+        assert_eq!(visible_status(ContactStatus::Busy, true), ContactStatus::Offline);
+        assert_eq!(visible_status(ContactStatus::Invisible, false), ContactStatus::Offline);
+    }
+}
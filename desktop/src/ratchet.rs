@@ -0,0 +1,512 @@
+use crate::crypto::{EncryptedMessage, IdentityKeyPair, IdentityPublicKey};
+use aes_gcm::{Aes256Gcm, Key, Nonce, aead::{Aead, NewAead}};
+use anyhow::{Result, anyhow};
+use base64::{Engine as _, engine::general_purpose};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::{RngCore, rngs::OsRng};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashMap;
+use x25519_dalek::{PublicKey as X25519PublicKey, StaticSecret};
+
+/// Algorithm tag for `EncryptedMessage` produced by a ratchet session
+pub const ALGORITHM_DOUBLE_RATCHET: &str = "double-ratchet-v1";
+
+const MAX_SKIP: u32 = 1000;
+const MAX_SKIPPED_KEYS: usize = 2000;
+/// Initiator ephemerals remembered per contact; each one is a peer reinstall or reset
+const MAX_USED_X3DH_EPHEMERALS: usize = 64;
+const X3DH_INFO: &[u8] = b"nonmessenger-x3dh-v1";
+const ROOT_KDF_INFO: &[u8] = b"nonmessenger-ratchet-root";
+const MESSAGE_KDF_INFO: &[u8] = b"nonmessenger-ratchet-message";
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Per-message header, authenticated as associated data
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RatchetHeader {
+    pub dh: String,
+    pub pn: u32,
+    pub n: u32,
+    /// Initiator's X3DH ephemeral key, repeated until the first reply arrives
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub x3dh_ephemeral: Option<String>,
+}
+
+/// Double Ratchet state for one contact, persisted in the `sessions` table
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RatchetSession {
+    pub contact_id: String,
+    root_key: [u8; 32],
+    dh_self: [u8; 32],
+    dh_remote: Option<[u8; 32]>,
+    sending_chain: Option<[u8; 32]>,
+    receiving_chain: Option<[u8; 32]>,
+    send_count: u32,
+    recv_count: u32,
+    previous_send_count: u32,
+    /// Message keys for messages not yet received, keyed by "<dh>:<n>"
+    skipped_keys: HashMap<String, [u8; 32]>,
+    pending_x3dh_ephemeral: Option<[u8; 32]>,
+    /// X3DH ephemerals of first messages already answered, carried over when
+    /// a new session replaces this one so they can never start another
+    #[serde(default)]
+    used_x3dh_ephemerals: Vec<[u8; 32]>,
+}
+
+impl RatchetSession {
+    /// Start a session as the initiator (the side that sends first)
+    pub fn initiate(contact_id: &str, our_identity: &IdentityKeyPair, their_identity: &IdentityPublicKey) -> Result<Self> {
+        let ephemeral = random_secret();
+        let ephemeral_public = X25519PublicKey::from(&ephemeral);
+
+        let dh1 = our_identity.agreement_key.diffie_hellman(&their_identity.agreement_key);
+        let dh2 = ephemeral.diffie_hellman(&their_identity.agreement_key);
+        let shared_key = x3dh_shared_key(dh1.as_bytes(), dh2.as_bytes())?;
+
+        // The responder's identity agreement key acts as its initial ratchet key
+        let dh_self = random_secret();
+        let dh_out = dh_self.diffie_hellman(&their_identity.agreement_key);
+        let (root_key, sending_chain) = kdf_root(&shared_key, dh_out.as_bytes())?;
+
+        Ok(Self {
+            contact_id: contact_id.to_string(),
+            root_key,
+            dh_self: dh_self.to_bytes(),
+            dh_remote: Some(their_identity.agreement_key.to_bytes()),
+            sending_chain: Some(sending_chain),
+            receiving_chain: None,
+            send_count: 0,
+            recv_count: 0,
+            previous_send_count: 0,
+            skipped_keys: HashMap::new(),
+            pending_x3dh_ephemeral: Some(ephemeral_public.to_bytes()),
+            used_x3dh_ephemerals: Vec::new(),
+        })
+    }
+
+    /// Start a session as the responder from the initiator's first message
+    pub fn respond(contact_id: &str, our_identity: &IdentityKeyPair, their_identity: &IdentityPublicKey, first_message: &EncryptedMessage) -> Result<Self> {
+        let header = Self::read_header(first_message)?;
+        let ephemeral_public = decode_public(
+            header.x3dh_ephemeral.as_deref()
+                .ok_or_else(|| anyhow!("First ratchet message is missing the X3DH ephemeral key"))?
+        )?;
+
+        let dh1 = our_identity.agreement_key.diffie_hellman(&their_identity.agreement_key);
+        let dh2 = our_identity.agreement_key.diffie_hellman(&ephemeral_public);
+        let shared_key = x3dh_shared_key(dh1.as_bytes(), dh2.as_bytes())?;
+
+        Ok(Self {
+            contact_id: contact_id.to_string(),
+            root_key: shared_key,
+            dh_self: our_identity.agreement_key.to_bytes(),
+            dh_remote: None,
+            sending_chain: None,
+            receiving_chain: None,
+            send_count: 0,
+            recv_count: 0,
+            previous_send_count: 0,
+            skipped_keys: HashMap::new(),
+            pending_x3dh_ephemeral: None,
+            used_x3dh_ephemerals: vec![ephemeral_public.to_bytes()],
+        })
+    }
+
+    /// Decrypt an inbound message with the stored session, or start one from
+    /// an initiator's first message. Returns the session to persist, if any.
+    ///
+    /// An established session is only replaced by a first message with an
+    /// X3DH ephemeral we have never answered, which is the peer starting over
+    /// after a reinstall; any other message that fails to decrypt is a replay
+    /// or does not belong to the session. When both sides send a first message
+    /// before hearing from each other, the side with the lower identity key
+    /// stays initiator and the other adopts the session it was offered.
+    pub fn open(
+        stored: Option<RatchetSession>,
+        contact_id: &str,
+        our_identity: &IdentityKeyPair,
+        their_identity: &IdentityPublicKey,
+        message: &EncryptedMessage,
+    ) -> Result<(String, Option<RatchetSession>)> {
+        let mut session = match stored {
+            Some(session) => session,
+            None => {
+                let mut session = Self::respond(contact_id, our_identity, their_identity, message)?;
+                let plaintext = session.decrypt(message)?;
+                return Ok((plaintext, Some(session)));
+            }
+        };
+
+        let error = match session.decrypt(message) {
+            Ok(plaintext) => return Ok((plaintext, Some(session))),
+            Err(e) => e,
+        };
+        let ephemeral = match Self::read_header(message)?.x3dh_ephemeral {
+            Some(encoded) => decode_public(&encoded)?.to_bytes(),
+            None => return Err(error),
+        };
+        let awaiting_reply = session.pending_x3dh_ephemeral.is_some();
+        if !awaiting_reply && session.used_x3dh_ephemerals.contains(&ephemeral) {
+            return Err(error);
+        }
+
+        let mut offered = Self::respond(contact_id, our_identity, their_identity, message)?;
+        let plaintext = offered.decrypt(message)?;
+        session.remember_x3dh_ephemeral(ephemeral);
+
+        let we_initiate = awaiting_reply
+            && our_identity.public_key().agreement_key.as_bytes() < their_identity.agreement_key.as_bytes();
+        if we_initiate {
+            return Ok((plaintext, Some(session)));
+        }
+        offered.used_x3dh_ephemerals = session.used_x3dh_ephemerals;
+        Ok((plaintext, Some(offered)))
+    }
+
+    fn remember_x3dh_ephemeral(&mut self, ephemeral: [u8; 32]) {
+        if self.used_x3dh_ephemerals.contains(&ephemeral) {
+            return;
+        }
+        self.used_x3dh_ephemerals.push(ephemeral);
+        if self.used_x3dh_ephemerals.len() > MAX_USED_X3DH_EPHEMERALS {
+            self.used_x3dh_ephemerals.remove(0);
+        }
+    }
+
+    /// Decode the ratchet header carried in `encrypted_key`
+    pub fn read_header(message: &EncryptedMessage) -> Result<RatchetHeader> {
+        if message.algorithm != ALGORITHM_DOUBLE_RATCHET {
+            return Err(anyhow!("Not a ratchet message: {}", message.algorithm));
+        }
+        let header_bytes = general_purpose::STANDARD.decode(&message.encrypted_key)?;
+        Ok(serde_json::from_slice(&header_bytes)?)
+    }
+
+    /// Encrypt the next outbound message
+    pub fn encrypt(&mut self, plaintext: &str) -> Result<EncryptedMessage> {
+        let chain_key = self.sending_chain
+            .ok_or_else(|| anyhow!("Session has no sending chain yet"))?;
+        let (next_chain, message_key) = kdf_chain(&chain_key)?;
+
+        let dh_public = X25519PublicKey::from(&StaticSecret::from(self.dh_self));
+        let header = RatchetHeader {
+            dh: general_purpose::STANDARD.encode(dh_public.as_bytes()),
+            pn: self.previous_send_count,
+            n: self.send_count,
+            x3dh_ephemeral: self.pending_x3dh_ephemeral
+                .map(|key| general_purpose::STANDARD.encode(key)),
+        };
+        let header_bytes = serde_json::to_vec(&header)?;
+
+        let (aes_key, nonce_bytes) = message_cipher_params(&message_key)?;
+        let cipher = Aes256Gcm::new(Key::from_slice(&aes_key));
+        let mut ciphertext = cipher
+            .encrypt(Nonce::from_slice(&nonce_bytes), aes_gcm::aead::Payload {
+                msg: plaintext.as_bytes(),
+                aad: &header_bytes,
+            })
+            .map_err(|e| anyhow!("Ratchet encryption failed: {}", e))?;
+        let auth_tag = ciphertext.split_off(ciphertext.len() - 16);
+
+        self.sending_chain = Some(next_chain);
+        self.send_count += 1;
+
+        Ok(EncryptedMessage {
+            encrypted_message: general_purpose::STANDARD.encode(&ciphertext),
+            encrypted_key: general_purpose::STANDARD.encode(&header_bytes),
+            iv: general_purpose::STANDARD.encode(nonce_bytes),
+            auth_tag: general_purpose::STANDARD.encode(&auth_tag),
+            algorithm: ALGORITHM_DOUBLE_RATCHET.to_string(),
+        })
+    }
+
+    /// Decrypt an inbound message. State is only advanced if decryption succeeds.
+    pub fn decrypt(&mut self, message: &EncryptedMessage) -> Result<String> {
+        let header = Self::read_header(message)?;
+        let header_bytes = general_purpose::STANDARD.decode(&message.encrypted_key)?;
+
+        let mut next = self.clone();
+        let message_key = next.message_key_for(&header)?;
+
+        let (aes_key, nonce_bytes) = message_cipher_params(&message_key)?;
+        let cipher = Aes256Gcm::new(Key::from_slice(&aes_key));
+        let mut ciphertext = general_purpose::STANDARD.decode(&message.encrypted_message)?;
+        ciphertext.extend_from_slice(&general_purpose::STANDARD.decode(&message.auth_tag)?);
+
+        let plaintext = cipher
+            .decrypt(Nonce::from_slice(&nonce_bytes), aes_gcm::aead::Payload {
+                msg: &ciphertext,
+                aad: &header_bytes,
+            })
+            .map_err(|e| anyhow!("Ratchet decryption failed: {}", e))?;
+
+        // The peer has our session once anything decrypts, so stop sending X3DH data
+        next.pending_x3dh_ephemeral = None;
+        *self = next;
+
+        Ok(String::from_utf8(plaintext)?)
+    }
+
+    fn message_key_for(&mut self, header: &RatchetHeader) -> Result<[u8; 32]> {
+        let skipped_id = format!("{}:{}", header.dh, header.n);
+        if let Some(key) = self.skipped_keys.remove(&skipped_id) {
+            return Ok(key);
+        }
+
+        let remote = decode_public(&header.dh)?.to_bytes();
+        if self.dh_remote != Some(remote) {
+            self.skip_message_keys(header.pn)?;
+            self.dh_ratchet(remote)?;
+        }
+
+        self.skip_message_keys(header.n)?;
+
+        let chain_key = self.receiving_chain
+            .ok_or_else(|| anyhow!("Session has no receiving chain"))?;
+        let (next_chain, message_key) = kdf_chain(&chain_key)?;
+        self.receiving_chain = Some(next_chain);
+        self.recv_count += 1;
+
+        Ok(message_key)
+    }
+
+    fn skip_message_keys(&mut self, until: u32) -> Result<()> {
+        if self.recv_count + MAX_SKIP < until {
+            return Err(anyhow!("Too many skipped ratchet messages"));
+        }
+
+        if let (Some(mut chain_key), Some(remote)) = (self.receiving_chain, self.dh_remote) {
+            let remote_id = general_purpose::STANDARD.encode(remote);
+            while self.recv_count < until {
+                let (next_chain, message_key) = kdf_chain(&chain_key)?;
+                self.skipped_keys.insert(format!("{}:{}", remote_id, self.recv_count), message_key);
+                chain_key = next_chain;
+                self.recv_count += 1;
+            }
+            self.receiving_chain = Some(chain_key);
+        }
+
+        if self.skipped_keys.len() > MAX_SKIPPED_KEYS {
+            return Err(anyhow!("Skipped message key cache is full"));
+        }
+
+        Ok(())
+    }
+
+    fn dh_ratchet(&mut self, remote: [u8; 32]) -> Result<()> {
+        let remote_public = X25519PublicKey::from(remote);
+
+        self.previous_send_count = self.send_count;
+        self.send_count = 0;
+        self.recv_count = 0;
+        self.dh_remote = Some(remote);
+
+        let dh_out = StaticSecret::from(self.dh_self).diffie_hellman(&remote_public);
+        let (root_key, receiving_chain) = kdf_root(&self.root_key, dh_out.as_bytes())?;
+
+        let dh_self = random_secret();
+        let dh_out = dh_self.diffie_hellman(&remote_public);
+        let (root_key, sending_chain) = kdf_root(&root_key, dh_out.as_bytes())?;
+
+        self.root_key = root_key;
+        self.receiving_chain = Some(receiving_chain);
+        self.sending_chain = Some(sending_chain);
+        self.dh_self = dh_self.to_bytes();
+
+        Ok(())
+    }
+}
+
+fn random_secret() -> StaticSecret {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    StaticSecret::from(bytes)
+}
+
+fn decode_public(encoded: &str) -> Result<X25519PublicKey> {
+    let bytes: [u8; 32] = general_purpose::STANDARD.decode(encoded)?
+        .try_into()
+        .map_err(|_| anyhow!("Invalid X25519 public key length"))?;
+    Ok(X25519PublicKey::from(bytes))
+}
+
+fn x3dh_shared_key(dh1: &[u8], dh2: &[u8]) -> Result<[u8; 32]> {
+    let mut input = vec![0xFFu8; 32];
+    input.extend_from_slice(dh1);
+    input.extend_from_slice(dh2);
+
+    let hkdf = Hkdf::<Sha256>::new(Some(&[0u8; 32]), &input);
+    let mut key = [0u8; 32];
+    hkdf.expand(X3DH_INFO, &mut key)
+        .map_err(|_| anyhow!("X3DH key derivation failed"))?;
+    Ok(key)
+}
+
+fn kdf_root(root_key: &[u8; 32], dh_out: &[u8]) -> Result<([u8; 32], [u8; 32])> {
+    let hkdf = Hkdf::<Sha256>::new(Some(root_key), dh_out);
+    let mut output = [0u8; 64];
+    hkdf.expand(ROOT_KDF_INFO, &mut output)
+        .map_err(|_| anyhow!("Root key derivation failed"))?;
+
+    let mut next_root = [0u8; 32];
+    let mut chain_key = [0u8; 32];
+    next_root.copy_from_slice(&output[..32]);
+    chain_key.copy_from_slice(&output[32..]);
+    Ok((next_root, chain_key))
+}
+
+fn kdf_chain(chain_key: &[u8; 32]) -> Result<([u8; 32], [u8; 32])> {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(chain_key)
+        .map_err(|_| anyhow!("Invalid chain key"))?;
+    mac.update(&[0x01]);
+    let message_key: [u8; 32] = mac.finalize().into_bytes().into();
+
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(chain_key)
+        .map_err(|_| anyhow!("Invalid chain key"))?;
+    mac.update(&[0x02]);
+    let next_chain: [u8; 32] = mac.finalize().into_bytes().into();
+
+    Ok((next_chain, message_key))
+}
+
+fn message_cipher_params(message_key: &[u8; 32]) -> Result<([u8; 32], [u8; 12])> {
+    let hkdf = Hkdf::<Sha256>::new(None, message_key);
+    let mut output = [0u8; 44];
+    hkdf.expand(MESSAGE_KDF_INFO, &mut output)
+        .map_err(|_| anyhow!("Message key derivation failed"))?;
+
+    let mut aes_key = [0u8; 32];
+    let mut nonce = [0u8; 12];
+    aes_key.copy_from_slice(&output[..32]);
+    nonce.copy_from_slice(&output[32..]);
+    Ok((aes_key, nonce))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::NonMessengerCrypto;
+
+    fn identities() -> (IdentityKeyPair, IdentityKeyPair) {
+        let crypto = NonMessengerCrypto::new();
+        let alice = crypto.generate_identity_key_pair().unwrap();
+        let bob = crypto.generate_identity_key_pair().unwrap();
+        (
+            IdentityKeyPair::from_private_key(&alice.private_key).unwrap(),
+            IdentityKeyPair::from_private_key(&bob.private_key).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_ratchet_round_trip() {
+        let (alice_id, bob_id) = identities();
+        let mut alice = RatchetSession::initiate("bob", &alice_id, &bob_id.public_key()).unwrap();
+
+        let first = alice.encrypt("hello bob").unwrap();
+        let mut bob = RatchetSession::respond("alice", &bob_id, &alice_id.public_key(), &first).unwrap();
+        assert_eq!(bob.decrypt(&first).unwrap(), "hello bob");
+
+        let reply = bob.encrypt("hello alice").unwrap();
+        assert_eq!(alice.decrypt(&reply).unwrap(), "hello alice");
+
+        let second = alice.encrypt("again").unwrap();
+        assert!(RatchetSession::read_header(&second).unwrap().x3dh_ephemeral.is_none());
+        assert_eq!(bob.decrypt(&second).unwrap(), "again");
+    }
+
+    #[test]
+    fn test_ratchet_out_of_order() {
+        let (alice_id, bob_id) = identities();
+        let mut alice = RatchetSession::initiate("bob", &alice_id, &bob_id.public_key()).unwrap();
+
+        let m1 = alice.encrypt("one").unwrap();
+        let m2 = alice.encrypt("two").unwrap();
+        let m3 = alice.encrypt("three").unwrap();
+
+        let mut bob = RatchetSession::respond("alice", &bob_id, &alice_id.public_key(), &m3).unwrap();
+        assert_eq!(bob.decrypt(&m3).unwrap(), "three");
+        assert_eq!(bob.decrypt(&m1).unwrap(), "one");
+        assert_eq!(bob.decrypt(&m2).unwrap(), "two");
+
+        // Replayed message keys are consumed
+        assert!(bob.decrypt(&m1).is_err());
+    }
+
+    #[test]
+    fn test_ratchet_rejects_tampering_without_losing_state() {
+        let (alice_id, bob_id) = identities();
+        let mut alice = RatchetSession::initiate("bob", &alice_id, &bob_id.public_key()).unwrap();
+
+        let message = alice.encrypt("intact").unwrap();
+        let mut bob = RatchetSession::respond("alice", &bob_id, &alice_id.public_key(), &message).unwrap();
+
+        let mut tampered = message.clone();
+        tampered.auth_tag = general_purpose::STANDARD.encode([0u8; 16]);
+        assert!(bob.decrypt(&tampered).is_err());
+        assert_eq!(bob.decrypt(&message).unwrap(), "intact");
+    }
+
+    #[test]
+    fn test_failed_decrypt_never_replaces_session() {
+        let (alice_id, bob_id) = identities();
+        let mut alice = RatchetSession::initiate("bob", &alice_id, &bob_id.public_key()).unwrap();
+
+        let first = alice.encrypt("hello bob").unwrap();
+        let (_, bob) = RatchetSession::open(None, "alice", &bob_id, &alice_id.public_key(), &first).unwrap();
+        let mut bob = bob.unwrap();
+        assert_eq!(alice.decrypt(&bob.encrypt("hello alice").unwrap()).unwrap(), "hello alice");
+
+        // A duplicate of the first message still carries the X3DH key
+        let replay = RatchetSession::open(Some(bob.clone()), "alice", &bob_id, &alice_id.public_key(), &first);
+        assert!(replay.is_err());
+        assert_eq!(bob.decrypt(&alice.encrypt("still here").unwrap()).unwrap(), "still here");
+    }
+
+    #[test]
+    fn test_reinstalled_peer_starts_a_new_session() {
+        let (alice_id, bob_id) = identities();
+        let mut alice = RatchetSession::initiate("bob", &alice_id, &bob_id.public_key()).unwrap();
+
+        let first = alice.encrypt("hello bob").unwrap();
+        let (_, bob) = RatchetSession::open(None, "alice", &bob_id, &alice_id.public_key(), &first).unwrap();
+        let mut bob = bob.unwrap();
+        assert_eq!(alice.decrypt(&bob.encrypt("hello alice").unwrap()).unwrap(), "hello alice");
+
+        // Alice lost her session and starts over with a fresh ephemeral
+        let mut reinstalled = RatchetSession::initiate("bob", &alice_id, &bob_id.public_key()).unwrap();
+        let restart = reinstalled.encrypt("new phone").unwrap();
+        let (text, bob) = RatchetSession::open(Some(bob), "alice", &bob_id, &alice_id.public_key(), &restart).unwrap();
+        assert_eq!(text, "new phone");
+        let mut bob = bob.unwrap();
+        assert_eq!(reinstalled.decrypt(&bob.encrypt("welcome back").unwrap()).unwrap(), "welcome back");
+
+        // Neither first message can start a session again, even after a round trip through storage
+        let stored: RatchetSession = serde_json::from_str(&serde_json::to_string(&bob).unwrap()).unwrap();
+        for replayed in [&first, &restart] {
+            assert!(RatchetSession::open(Some(stored.clone()), "alice", &bob_id, &alice_id.public_key(), replayed).is_err());
+        }
+    }
+
+    #[test]
+    fn test_simultaneous_start_settles_on_one_session() {
+        let (alice_id, bob_id) = identities();
+        let mut alice = RatchetSession::initiate("bob", &alice_id, &bob_id.public_key()).unwrap();
+        let mut bob = RatchetSession::initiate("alice", &bob_id, &alice_id.public_key()).unwrap();
+
+        let to_bob = alice.encrypt("from alice").unwrap();
+        let to_alice = bob.encrypt("from bob").unwrap();
+
+        let (text, alice_next) = RatchetSession::open(Some(alice.clone()), "bob", &alice_id, &bob_id.public_key(), &to_alice).unwrap();
+        assert_eq!(text, "from bob");
+        let (text, bob_next) = RatchetSession::open(Some(bob.clone()), "alice", &bob_id, &alice_id.public_key(), &to_bob).unwrap();
+        assert_eq!(text, "from alice");
+        let mut alice = alice_next.unwrap();
+        let mut bob = bob_next.unwrap();
+        // Exactly one side adopted the other's session and stopped sending X3DH data
+        assert!(alice.pending_x3dh_ephemeral.is_some() != bob.pending_x3dh_ephemeral.is_some());
+        assert_eq!(bob.decrypt(&alice.encrypt("after").unwrap()).unwrap(), "after");
+        assert_eq!(alice.decrypt(&bob.encrypt("settled").unwrap()).unwrap(), "settled");
+    }
+}
@@ -0,0 +1,173 @@
+use crate::crypto::NonMessengerCrypto;
+use crate::database::Database;
+use crate::dispatcher::seal_message;
+use crate::models::*;
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+/// Envelope `message_type`s, matching `shared/protocols/awareness.js`
+pub const RECEIPT_DELIVERY_STATUS: &str = "delivery_status";
+pub const RECEIPT_READ: &str = "read_receipt";
+
+/// Settings key for the read receipt opt-out; receipts are on unless set to "false"
+pub const SETTING_READ_RECEIPTS: &str = "read_receipts_enabled";
+
+const PROTOCOL_VERSION: &str = "1.0";
+
+/// How long receipt envelope ids are remembered to drop duplicates. Well past
+/// the server's pool TTL, after which no copy can still be delivered.
+pub const SEEN_RECEIPT_RETENTION_SECS: i64 = 7 * 24 * 60 * 60;
+
+/// Plaintext of a receipt envelope, in the shape of awareness.js
+/// `createDeliveryStatus` / `createReadReceipt`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Receipt {
+    #[serde(rename = "type")]
+    pub receipt_type: String,
+    pub message_id: String,
+    /// Further messages covered by the same receipt, so a batch costs one envelope
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub message_ids: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+    pub user_id: String,
+    #[serde(default)]
+    pub timestamp: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub read_timestamp: Option<i64>,
+    pub version: String,
+}
+
+impl Receipt {
+    pub fn new(receipt_type: &str, message_ids: &[String], user_id: &str, now: i64) -> Result<Self> {
+        let (message_id, rest) = message_ids.split_first()
+            .ok_or_else(|| anyhow!("Receipt needs at least one message id"))?;
+        let is_read = receipt_type == RECEIPT_READ;
+
+        Ok(Self {
+            receipt_type: receipt_type.to_string(),
+            message_id: message_id.clone(),
+            message_ids: rest.to_vec(),
+            status: (!is_read).then(|| DeliveryStatus::Delivered.as_str().to_string()),
+            user_id: user_id.to_string(),
+            timestamp: now,
+            read_timestamp: is_read.then_some(now),
+            version: PROTOCOL_VERSION.to_string(),
+        })
+    }
+
+    /// Status this receipt moves our messages to. Only delivered and read are
+    /// accepted from a peer; anything else would let them rewrite our state.
+    pub fn delivery_status(&self) -> Option<DeliveryStatus> {
+        match self.receipt_type.as_str() {
+            RECEIPT_READ => Some(DeliveryStatus::Read),
+            RECEIPT_DELIVERY_STATUS => self.status.as_deref()
+                .and_then(DeliveryStatus::parse)
+                .filter(|status| matches!(status, DeliveryStatus::Delivered | DeliveryStatus::Read)),
+            _ => None,
+        }
+    }
+
+    pub fn all_message_ids(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.message_id.as_str()).chain(self.message_ids.iter().map(String::as_str))
+    }
+}
+
+pub fn is_receipt_type(message_type: &str) -> bool {
+    matches!(message_type, RECEIPT_DELIVERY_STATUS | RECEIPT_READ)
+}
+
+pub async fn read_receipts_enabled(db: &Database) -> Result<bool> {
+    Ok(db.get_setting(SETTING_READ_RECEIPTS).await?.as_deref() != Some("false"))
+}
+
+/// Encrypt a receipt for `contact` and queue it in the outbox
+pub async fn queue_receipt(
+    crypto: &NonMessengerCrypto,
+    db: &Database,
+    outbox: &Notify,
+    contact: &Contact,
+    receipt_type: &str,
+    message_ids: &[String],
+) -> Result<()> {
+    let profile = db.get_user_profile().await?
+        .ok_or_else(|| anyhow!("No user profile found"))?;
+    let now = chrono::Utc::now().timestamp();
+
+    let receipt = Receipt::new(receipt_type, message_ids, &profile.contact_code.join(" "), now)?;
+    let encrypted = seal_message(crypto, db, contact, &serde_json::to_string(&receipt)?).await?;
+
+    let entry = OutboxEntry {
+        message_id: uuid::Uuid::new_v4().to_string(),
+        contact_id: contact.id.clone(),
+        recipient_contact_code: contact.contact_code.join(" "),
+        message_type: receipt_type.to_string(),
+        encrypted_message: encrypted,
+        timestamp: now,
+        status: "pending".to_string(),
+        attempts: 0,
+        next_attempt_at: now,
+        last_error: None,
+        created_at: now,
+    };
+    crate::outbox::enqueue(db, outbox, &entry).await
+}
+
+/// Apply a receipt from `contact` to the messages we sent them.
+/// Returns the ids whose status moved, with the status they moved to.
+pub async fn apply_receipt(db: &Database, contact: &Contact, receipt: &Receipt) -> Result<Vec<(String, DeliveryStatus)>> {
+    let status = receipt.delivery_status()
+        .ok_or_else(|| anyhow!("Unsupported receipt {} ({:?})", receipt.receipt_type, receipt.status))?;
+
+    let mut advanced = Vec::new();
+    for message_id in receipt.all_message_ids() {
+        if db.advance_message_status(&contact.id, message_id, status).await? {
+            advanced.push((message_id.to_string(), status));
+        }
+    }
+
+    Ok(advanced)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{TempDatabase, test_contact};
+
+    #[tokio::test]
+    async fn test_receipts_only_advance_status() {
+        let db = TempDatabase::open().await;
+
+        let contact = test_contact("alice");
+        db.insert_contact(&contact).await.unwrap();
+        db.insert_message(&Message {
+            id: "m1".to_string(),
+            contact_id: contact.id.clone(),
+            content: "hello".to_string(),
+            is_from_me: true,
+            timestamp: 1,
+            message_type: "text".to_string(),
+            delivery_status: "sent".to_string(),
+            encrypted_content: String::new(),
+            created_at: 1,
+        }).await.unwrap();
+
+        let ids = vec!["m1".to_string()];
+        let read = Receipt::new(RECEIPT_READ, &ids, "alice", 2).unwrap();
+        let delivered = Receipt::new(RECEIPT_DELIVERY_STATUS, &ids, "alice", 3).unwrap();
+
+        assert_eq!(apply_receipt(&db, &contact, &read).await.unwrap(), vec![("m1".to_string(), DeliveryStatus::Read)]);
+        // A delivery receipt arriving late must not move read back to delivered
+        assert!(apply_receipt(&db, &contact, &delivered).await.unwrap().is_empty());
+
+        let messages = db.get_messages_for_contact(&contact.id).await.unwrap();
+        assert_eq!(messages[0].delivery_status, "read");
+
+        // Receipts from someone else cannot touch the message
+        let mallory = test_contact("mallory");
+        db.update_message_status("m1", "sent").await.unwrap();
+        assert!(apply_receipt(&db, &mallory, &read).await.unwrap().is_empty());
+    }
+}
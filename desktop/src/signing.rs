@@ -0,0 +1,192 @@
+use crate::crypto::{IdentityKeyPair, IdentityPublicKey};
+use crate::models::*;
+use anyhow::{Result, anyhow};
+use serde::Serialize;
+use serde_json::Value;
+
+/// Envelopes that carry a detached Ed25519 signature from the sender's identity key.
+///
+/// The signature covers the canonical JSON of the envelope with the
+/// `signature` field removed: object keys sorted, no insignificant whitespace.
+pub trait SignedEnvelope: Serialize {
+    fn signature(&self) -> Option<&str>;
+    fn set_signature(&mut self, signature: Option<String>);
+
+    /// Public identity key the envelope claims to be signed with
+    fn signer_key(&self) -> Option<&str>;
+
+    fn canonical_bytes(&self) -> Result<Vec<u8>> {
+        let mut value = serde_json::to_value(self)?;
+        if let Some(object) = value.as_object_mut() {
+            object.remove("signature");
+        }
+
+        let mut canonical = String::new();
+        write_canonical_json(&value, &mut canonical)?;
+        Ok(canonical.into_bytes())
+    }
+}
+
+pub fn sign_envelope<T: SignedEnvelope>(envelope: &mut T, private_key: &str) -> Result<()> {
+    let identity = IdentityKeyPair::from_private_key(private_key)?;
+    envelope.set_signature(None);
+    let signature = identity.sign(&envelope.canonical_bytes()?);
+    envelope.set_signature(Some(signature));
+    Ok(())
+}
+
+/// Verify the envelope against its own `signer_key`
+pub fn verify_envelope<T: SignedEnvelope>(envelope: &T) -> Result<()> {
+    let signer_key = envelope.signer_key()
+        .ok_or_else(|| anyhow!("Envelope does not name a signing key"))?;
+    verify_envelope_with_key(envelope, signer_key)
+}
+
+pub fn verify_envelope_with_key<T: SignedEnvelope>(envelope: &T, public_key: &str) -> Result<()> {
+    let signature = envelope.signature()
+        .ok_or_else(|| anyhow!("Envelope is not signed"))?;
+    let public_key = IdentityPublicKey::from_public_key(public_key)?;
+    public_key.verify(&envelope.canonical_bytes()?, signature)
+}
+
+fn write_canonical_json(value: &Value, out: &mut String) -> Result<()> {
+    match value {
+        Value::Object(map) => {
+            let mut keys: Vec<&String> = map.keys().collect();
+            keys.sort();
+
+            out.push('{');
+            for (i, key) in keys.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&serde_json::to_string(key)?);
+                out.push(':');
+                write_canonical_json(&map[key.as_str()], out)?;
+            }
+            out.push('}');
+        }
+        Value::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_canonical_json(item, out)?;
+            }
+            out.push(']');
+        }
+        other => out.push_str(&serde_json::to_string(other)?),
+    }
+
+    Ok(())
+}
+
+impl SignedEnvelope for MessageEnvelope {
+    fn signature(&self) -> Option<&str> {
+        self.signature.as_deref()
+    }
+
+    fn set_signature(&mut self, signature: Option<String>) {
+        self.signature = signature;
+    }
+
+    fn signer_key(&self) -> Option<&str> {
+        self.sender_key.as_deref()
+    }
+}
+
+impl SignedEnvelope for ContactRequestMessage {
+    fn signature(&self) -> Option<&str> {
+        self.signature.as_deref()
+    }
+
+    fn set_signature(&mut self, signature: Option<String>) {
+        self.signature = signature;
+    }
+
+    fn signer_key(&self) -> Option<&str> {
+        Some(&self.sender_public_key)
+    }
+}
+
+impl SignedEnvelope for ContactResponseMessage {
+    fn signature(&self) -> Option<&str> {
+        self.signature.as_deref()
+    }
+
+    fn set_signature(&mut self, signature: Option<String>) {
+        self.signature = signature;
+    }
+
+    fn signer_key(&self) -> Option<&str> {
+        self.recipient_public_key.as_deref()
+    }
+}
+
+impl SignedEnvelope for VoiceCallMessage {
+    fn signature(&self) -> Option<&str> {
+        self.signature.as_deref()
+    }
+
+    fn set_signature(&mut self, signature: Option<String>) {
+        self.signature = signature;
+    }
+
+    fn signer_key(&self) -> Option<&str> {
+        self.sender_key.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::NonMessengerCrypto;
+
+    fn call_message(sender_key: &str) -> VoiceCallMessage {
+        VoiceCallMessage {
+            r#type: "VOICE_CALL_INIT".to_string(),
+            id: "message-id".to_string(),
+            timestamp: 1_700_000_000,
+            call_id: "call-id".to_string(),
+            caller_id: Some("caller".to_string()),
+            recipient_id: Some("recipient".to_string()),
+            version: "1.0".to_string(),
+            encrypted_key: None,
+            key_share: None,
+            sender_key: Some(sender_key.to_string()),
+            signature: None,
+        }
+    }
+
+    #[test]
+    fn test_sign_and_verify_envelope() {
+        let crypto = NonMessengerCrypto::new();
+        let key_pair = crypto.generate_identity_key_pair().unwrap();
+
+        let mut message = call_message(&key_pair.public_key);
+        sign_envelope(&mut message, &key_pair.private_key).unwrap();
+        assert!(verify_envelope(&message).is_ok());
+
+        // Round-tripping through JSON must not change the canonical form
+        let json = serde_json::to_string(&message).unwrap();
+        let parsed: VoiceCallMessage = serde_json::from_str(&json).unwrap();
+        assert!(verify_envelope(&parsed).is_ok());
+    }
+
+    #[test]
+    fn test_tampered_or_unsigned_envelope_rejected() {
+        let crypto = NonMessengerCrypto::new();
+        let key_pair = crypto.generate_identity_key_pair().unwrap();
+        let other = crypto.generate_identity_key_pair().unwrap();
+
+        let mut message = call_message(&key_pair.public_key);
+        assert!(verify_envelope(&message).is_err());
+
+        sign_envelope(&mut message, &key_pair.private_key).unwrap();
+        assert!(verify_envelope_with_key(&message, &other.public_key).is_err());
+
+        message.call_id = "other-call".to_string();
+        assert!(verify_envelope(&message).is_err());
+    }
+}
@@ -0,0 +1,262 @@
+use crate::database::Database;
+use crate::discovery;
+use crate::models::*;
+use crate::network::MessagePoolClient;
+use crate::AppState;
+use anyhow::{Result, anyhow};
+use reqwest::Client;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, Notify};
+
+#[derive(Debug, Clone)]
+pub struct SupervisorConfig {
+    pub ping_interval: Duration,
+    pub ping_timeout: Duration,
+    pub reconnect_base_delay: Duration,
+    pub reconnect_max_delay: Duration,
+}
+
+impl Default for SupervisorConfig {
+    fn default() -> Self {
+        Self {
+            ping_interval: Duration::from_secs(30),
+            ping_timeout: Duration::from_secs(5),
+            reconnect_base_delay: Duration::from_secs(1),
+            reconnect_max_delay: Duration::from_secs(60),
+        }
+    }
+}
+
+impl SupervisorConfig {
+    /// Exponential backoff with jitter in [50%, 100%] of the capped delay
+    pub fn reconnect_delay(&self, failures: u32, jitter: f64) -> Duration {
+        let exponent = failures.saturating_sub(1).min(16);
+        let delay = self.reconnect_base_delay
+            .saturating_mul(1u32 << exponent)
+            .min(self.reconnect_max_delay);
+        delay.mul_f64(0.5 + jitter.clamp(0.0, 1.0) / 2.0)
+    }
+}
+
+/// Shared switch between the connect/disconnect commands and the supervisor
+pub struct SupervisorControl {
+    enabled: AtomicBool,
+    wake: Notify,
+}
+
+impl SupervisorControl {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled: AtomicBool::new(enabled),
+            wake: Notify::new(),
+        }
+    }
+
+    pub fn enable(&self) {
+        self.enabled.store(true, Ordering::SeqCst);
+        self.wake.notify_one();
+    }
+
+    /// Stop reconnecting, e.g. after the user disconnects on purpose
+    pub fn disable(&self) {
+        self.enabled.store(false, Ordering::SeqCst);
+        self.wake.notify_one();
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::SeqCst)
+    }
+}
+
+/// Connect to `server_url` and bring the session up: identity, trusted
+/// contacts and `register_user` so the server routes real-time messages to us
+pub async fn establish_session(
+    database: &Mutex<Database>,
+    network: &Mutex<MessagePoolClient>,
+    server_url: &str,
+) -> Result<()> {
+    let (profile, contacts) = {
+        let db = database.lock().await;
+        (db.get_user_profile().await?, db.get_all_contacts().await?)
+    };
+
+    let mut network = network.lock().await;
+    if network.is_connected().await {
+        network.disconnect().await?;
+    }
+
+    if let Some(profile) = &profile {
+        // Legacy RSA profiles cannot sign; they can still connect but sends will fail
+        if let Err(e) = network.set_identity(&profile.private_key).await {
+            log::warn!("Profile key cannot sign envelopes: {}", e);
+        }
+    }
+    network.set_trusted_contacts(&contacts).await;
+
+    network.connect(server_url).await?;
+    if let Some(profile) = &profile {
+        network.register_user(&profile.contact_code.join(" ")).await?;
+    }
+
+    Ok(())
+}
+
+/// Keeps the client connected to the best reachable node in `server_nodes`.
+///
+/// Nodes are pinged and ranked by priority, then latency. A dropped socket or
+/// a failed ping of the current node triggers failover with jittered backoff.
+pub struct ConnectionSupervisor {
+    database: Arc<Mutex<Database>>,
+    network: Arc<Mutex<MessagePoolClient>>,
+    control: Arc<SupervisorControl>,
+    client: Client,
+    config: SupervisorConfig,
+}
+
+impl ConnectionSupervisor {
+    pub fn new(state: &AppState, config: SupervisorConfig) -> Self {
+        Self {
+            database: Arc::clone(&state.database),
+            network: Arc::clone(&state.network),
+            control: Arc::clone(&state.supervisor),
+            client: Client::new(),
+            config,
+        }
+    }
+
+    pub async fn run(self) {
+        let mut connection = self.network.lock().await.subscribe_connection();
+        let mut failures = 0u32;
+
+        loop {
+            if !self.control.is_enabled() {
+                self.control.wake.notified().await;
+                continue;
+            }
+
+            if *connection.borrow_and_update() {
+                tokio::select! {
+                    _ = connection.changed() => {}
+                    _ = self.control.wake.notified() => {}
+                    _ = tokio::time::sleep(self.config.ping_interval) => self.check_current_node().await,
+                }
+                continue;
+            }
+
+            match self.connect_best_node().await {
+                Ok(url) => {
+                    failures = 0;
+                    log::info!("Connected to node {}", url);
+                    if let Err(e) = discovery::sync_nodes(&self.database, &self.network).await {
+                        log::warn!("Node discovery via {} failed: {}", url, e);
+                    }
+                }
+                Err(e) => {
+                    failures += 1;
+                    let delay = self.config.reconnect_delay(failures, rand::random::<f64>());
+                    log::warn!("Reconnect attempt {} failed: {}; retrying in {:?}", failures, e, delay);
+                    tokio::select! {
+                        _ = tokio::time::sleep(delay) => {}
+                        _ = self.control.wake.notified() => {}
+                    }
+                }
+            }
+        }
+    }
+
+    /// Ping the active nodes and connect to the first healthy one in rank order
+    async fn connect_best_node(&self) -> Result<String> {
+        // connect_to_server may have won the race while we were waking up
+        {
+            let network = self.network.lock().await;
+            if network.is_connected().await {
+                return network.server_url().await.ok_or_else(|| anyhow!("Connected without a server URL"));
+            }
+        }
+
+        let nodes = self.database.lock().await.get_active_nodes().await?;
+        if nodes.is_empty() {
+            return Err(anyhow!("No active server nodes"));
+        }
+
+        let mut candidates = Vec::new();
+        for mut node in nodes {
+            match self.ping(&node.url).await {
+                Ok(response_time) => {
+                    node.last_ping = chrono::Utc::now().timestamp();
+                    node.response_time = response_time;
+                    self.database.lock().await
+                        .update_node_ping(&node.url, node.last_ping, response_time).await?;
+                    candidates.push(node);
+                }
+                Err(e) => log::debug!("Node {} unreachable: {}", node.url, e),
+            }
+        }
+
+        candidates.retain(ServerNode::is_healthy);
+        candidates.sort_by_key(|node| (node.priority, node.response_time));
+
+        for node in candidates {
+            match establish_session(&self.database, &self.network, &node.url).await {
+                Ok(()) => return Ok(node.url),
+                Err(e) => log::warn!("Failed to connect to {}: {}", node.url, e),
+            }
+        }
+
+        Err(anyhow!("No reachable server node"))
+    }
+
+    async fn check_current_node(&self) {
+        let server_url = match self.network.lock().await.server_url().await {
+            Some(url) => url,
+            None => return,
+        };
+
+        match self.ping(&server_url).await {
+            Ok(response_time) => {
+                let db = self.database.lock().await;
+                if let Err(e) = db.update_node_ping(&server_url, chrono::Utc::now().timestamp(), response_time).await {
+                    log::warn!("Failed to record ping for {}: {}", server_url, e);
+                }
+            }
+            Err(e) => {
+                log::warn!("Node {} stopped answering pings ({}), failing over", server_url, e);
+                if let Err(e) = self.network.lock().await.disconnect().await {
+                    log::warn!("Failed to drop connection to {}: {}", server_url, e);
+                }
+            }
+        }
+    }
+
+    /// Round-trip time of the node health check in milliseconds
+    async fn ping(&self, server_url: &str) -> Result<i64> {
+        let start_time = Instant::now();
+        let response = self.client
+            .get(&format!("{}/health", server_url))
+            .timeout(self.config.ping_timeout)
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(anyhow!("Health check returned {}", response.status()));
+        }
+        Ok(start_time.elapsed().as_millis() as i64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reconnect_delay_is_capped_and_jittered() {
+        let config = SupervisorConfig::default();
+
+        assert_eq!(config.reconnect_delay(1, 1.0), Duration::from_secs(1));
+        assert_eq!(config.reconnect_delay(3, 1.0), Duration::from_secs(4));
+        assert_eq!(config.reconnect_delay(3, 0.0), Duration::from_secs(2));
+        assert_eq!(config.reconnect_delay(50, 1.0), config.reconnect_max_delay);
+    }
+}
@@ -0,0 +1,65 @@
+use crate::database::Database;
+use crate::keystore::{KeyStore, MemoryKeyStore};
+use crate::models::Contact;
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// A database in a fresh temp file, deleted on drop even when an assert fails
+pub struct TempDatabase {
+    db: Option<Database>,
+    path: PathBuf,
+}
+
+impl TempDatabase {
+    pub async fn open() -> Self {
+        Self::with_key_store(Arc::new(MemoryKeyStore::new())).await
+    }
+
+    pub async fn with_key_store(key_store: Arc<dyn KeyStore>) -> Self {
+        let path = std::env::temp_dir().join(format!("nonmessenger-test-{}.db", uuid::Uuid::new_v4()));
+        let db = Database::open(&path, key_store).await.unwrap();
+        Self { db: Some(db), path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Deref for TempDatabase {
+    type Target = Database;
+
+    fn deref(&self) -> &Database {
+        self.db.as_ref().expect("database is open until drop")
+    }
+}
+
+impl DerefMut for TempDatabase {
+    fn deref_mut(&mut self) -> &mut Database {
+        self.db.as_mut().expect("database is open until drop")
+    }
+}
+
+impl Drop for TempDatabase {
+    fn drop(&mut self) {
+        // Close the connection before removing its file
+        self.db.take();
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// An unverified contact whose name and key derive from `id`
+pub fn test_contact(id: &str) -> Contact {
+    Contact {
+        id: id.to_string(),
+        name: id.to_string(),
+        contact_code: vec![],
+        public_key: format!("{}-key", id),
+        status: "offline".to_string(),
+        last_seen: 0,
+        is_verified: false,
+        device_id: "device".to_string(),
+        created_at: 0,
+    }
+}
@@ -103,8 +103,9 @@ impl Validator {
     }
 
     pub fn validate_public_key(key: &str) -> bool {
-        key.starts_with("-----BEGIN PUBLIC KEY-----") && 
-        key.ends_with("-----END PUBLIC KEY-----")
+        (key.starts_with("-----BEGIN PUBLIC KEY-----") && 
+        key.ends_with("-----END PUBLIC KEY-----")) ||
+        crate::crypto::IdentityPublicKey::from_public_key(key).is_ok()
     }
 
     pub fn validate_private_key(key: &str) -> bool {
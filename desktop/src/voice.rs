@@ -1,10 +1,78 @@
+use crate::audio_format::{AudioFormat, FormatAdapter};
+use crate::jitter_buffer::{JitterBuffer, PlayoutFrame};
 use crate::models::*;
+use crate::network::WebSocketSender;
+use crate::voice_codec::{self, CodecConfig, VoiceDecoder, VoiceEncoder};
+use crate::voice_crypto::{CallKey, ReplayWindow};
+use crate::AppState;
+use crate::database::Database;
 use anyhow::{Result, anyhow};
+use base64::{Engine as _, engine::general_purpose};
 use cpal::{Device, Host, Stream, StreamConfig, SupportedStreamConfig};
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use ringbuf::{HeapConsumer, HeapProducer, HeapRb};
 use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
+use std::time::Duration;
+use tauri::{AppHandle, Manager};
 use tokio::sync::{Mutex, mpsc};
-use std::collections::VecDeque;
+use tokio::task::JoinHandle;
+use tokio::time::MissedTickBehavior;
+
+/// Decoded audio kept queued ahead of the output device
+const PLAYOUT_QUEUE_MS: u32 = 60;
+/// Room in the ring between the decoder and the output device
+const PLAYOUT_RING_MS: u32 = 200;
+/// How often the playout task tops the queue up
+const PLAYOUT_TICK_MS: u64 = 10;
+/// Room in the ring between the input device and the encoder
+const CAPTURE_RING_MS: u32 = 200;
+/// How often the encoder task drains the capture ring
+const CAPTURE_TICK_MS: u64 = 10;
+
+/// Settings keys for the chosen devices; unset or empty means the system default
+pub const SETTING_INPUT_DEVICE: &str = "audio_input_device";
+pub const SETTING_OUTPUT_DEVICE: &str = "audio_output_device";
+
+pub fn device_setting_key(direction: AudioDirection) -> &'static str {
+    match direction {
+        AudioDirection::Input => SETTING_INPUT_DEVICE,
+        AudioDirection::Output => SETTING_OUTPUT_DEVICE,
+    }
+}
+
+/// Emitted when a call falls back to another device, e.g. after an unplug
+pub const EVENT_AUDIO_DEVICE_CHANGED: &str = "audio-device-changed";
+
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AudioDeviceChangedEvent {
+    pub direction: AudioDirection,
+    pub device: Option<String>,
+}
+
+/// One input stream's samples, in the device format they are recorded in.
+/// Each new stream, such as after a device switch mid-call, sends its own.
+struct CapturedAudio {
+    format: AudioFormat,
+    samples: HeapConsumer<f32>,
+}
+
+/// An open stream's device stopped being available
+#[derive(Debug, Clone)]
+pub struct DeviceLost {
+    direction: AudioDirection,
+    device_name: String,
+}
+
+/// One encrypted 20 ms Opus frame on its way to the network
+#[derive(Debug, Clone)]
+pub struct VoicePacket {
+    /// Contact code of the peer the frame is relayed to
+    pub recipient_contact_code: String,
+    pub call_id: String,
+    pub sequence_number: i32,
+    pub data: Vec<u8>,
+}
 
 pub struct VoiceCallManager {
     host: Host,
@@ -16,9 +84,83 @@ pub struct VoiceCallManager {
     is_playing: Arc<AtomicBool>,
     current_call: Arc<Mutex<Option<VoiceCall>>>,
     call_state: Arc<Mutex<CallState>>,
-    audio_sender: Arc<Mutex<Option<mpsc::UnboundedSender<Vec<f32>>>>>,
-    audio_receiver: Arc<Mutex<Option<mpsc::UnboundedReceiver<Vec<f32>>>>>,
-    playback_buffer: Arc<Mutex<VecDeque<f32>>>,
+    /// Chosen device names; None means the system default
+    preferred_input: Option<String>,
+    preferred_output: Option<String>,
+    device_loss_tx: Option<mpsc::UnboundedSender<DeviceLost>>,
+    /// Hands each new input stream's capture ring to the current call's encoder task
+    audio_sender: Arc<Mutex<Option<mpsc::UnboundedSender<CapturedAudio>>>>,
+    codec_config: CodecConfig,
+    playout: Arc<Mutex<Option<Playout>>>,
+    /// Decodes ahead of the output device for the current call
+    playout_task: Option<JoinHandle<()>>,
+    replay_window: Arc<Mutex<ReplayWindow>>,
+    packet_tx: Option<mpsc::UnboundedSender<VoicePacket>>,
+}
+
+/// Receive side of a call's audio. Frames are decoded on the async side into
+/// a lock-free ring, so the output callback only copies samples out of it.
+struct Playout {
+    jitter_buffer: JitterBuffer,
+    decoder: VoiceDecoder,
+    device_format: AudioFormat,
+    adapter: FormatAdapter,
+    /// Decoded audio in the device format; None until an output stream is open
+    samples: Option<HeapProducer<f32>>,
+}
+
+impl Playout {
+    fn new() -> Result<Self> {
+        Ok(Self {
+            jitter_buffer: JitterBuffer::new(),
+            decoder: VoiceDecoder::new()?,
+            device_format: AudioFormat::CALL,
+            adapter: FormatAdapter::new(AudioFormat::CALL, AudioFormat::CALL),
+            samples: None,
+        })
+    }
+
+    /// Feed a new output stream, converting to its device's format; the
+    /// jitter buffer carries on
+    fn set_output(&mut self, device_format: AudioFormat, samples: HeapProducer<f32>) {
+        if device_format != self.device_format {
+            self.device_format = device_format;
+            self.adapter = FormatAdapter::new(AudioFormat::CALL, device_format);
+        }
+        self.samples = Some(samples);
+    }
+
+    /// Decode frames until `PLAYOUT_QUEUE_MS` of audio waits for the device.
+    /// Frames leave the jitter buffer as fast as the device plays them.
+    fn fill(&mut self) {
+        let target = samples_for(self.device_format, PLAYOUT_QUEUE_MS);
+        while self.samples.as_ref().map_or(false, |samples| samples.len() < target) {
+            let frame = self.decode_next_frame();
+            if let Some(samples) = self.samples.as_mut() {
+                samples.push_slice(&frame);
+            }
+        }
+    }
+
+    fn decode_next_frame(&mut self) -> Vec<f32> {
+        let decoded = match self.jitter_buffer.pop() {
+            PlayoutFrame::Packet(packet) => self.decoder.decode(&packet),
+            PlayoutFrame::Recover(next_packet) => self.decoder.recover(&next_packet),
+            PlayoutFrame::Conceal => self.decoder.conceal(),
+            PlayoutFrame::Silence => Ok(vec![0.0; voice_codec::FRAME_SAMPLES]),
+        };
+
+        let frame = decoded.unwrap_or_else(|e| {
+            log::debug!("Voice frame decoding failed: {}", e);
+            vec![0.0; voice_codec::FRAME_SAMPLES]
+        });
+        self.adapter.process(&frame)
+    }
+}
+
+/// Interleaved samples in `duration_ms` of audio
+fn samples_for(format: AudioFormat, duration_ms: u32) -> usize {
+    (format.sample_rate as usize * format.channels as usize * duration_ms as usize) / 1000
 }
 
 #[derive(Debug, Clone)]
@@ -27,14 +169,14 @@ pub struct VoiceCall {
     pub contact: Contact,
     pub is_incoming: bool,
     pub start_time: i64,
-    pub encryption_key: Option<Vec<u8>>,
+    /// Media key exchanged in the call offer
+    pub encryption_key: CallKey,
 }
 
 impl VoiceCallManager {
     pub fn new() -> Self {
         let host = cpal::default_host();
-        let (audio_sender, audio_receiver) = mpsc::unbounded_channel();
-        
+
         Self {
             host,
             input_device: None,
@@ -45,31 +187,180 @@ impl VoiceCallManager {
             is_playing: Arc::new(AtomicBool::new(false)),
             current_call: Arc::new(Mutex::new(None)),
             call_state: Arc::new(Mutex::new(CallState::Idle)),
-            audio_sender: Arc::new(Mutex::new(Some(audio_sender))),
-            audio_receiver: Arc::new(Mutex::new(Some(audio_receiver))),
-            playback_buffer: Arc::new(Mutex::new(VecDeque::new())),
+            preferred_input: None,
+            preferred_output: None,
+            device_loss_tx: None,
+            audio_sender: Arc::new(Mutex::new(None)),
+            codec_config: CodecConfig::default(),
+            playout: Arc::new(Mutex::new(None)),
+            playout_task: None,
+            replay_window: Arc::new(Mutex::new(ReplayWindow::default())),
+            packet_tx: None,
         }
     }
 
+    /// Receive the encoded frames of every call. Must be called before a call starts.
+    pub fn subscribe_packets(&mut self) -> mpsc::UnboundedReceiver<VoicePacket> {
+        let (packet_tx, packet_rx) = mpsc::unbounded_channel();
+        self.packet_tx = Some(packet_tx);
+        packet_rx
+    }
+
+    pub fn codec_config(&self) -> &CodecConfig {
+        &self.codec_config
+    }
+
+    /// Applies from the next call on
+    pub fn set_codec_config(&mut self, config: CodecConfig) -> Result<()> {
+        config.validate()?;
+        self.codec_config = config;
+        Ok(())
+    }
+
+    /// Receive notice when the device behind an open stream goes away.
+    /// Must be called before a call starts.
+    pub fn subscribe_device_loss(&mut self) -> mpsc::UnboundedReceiver<DeviceLost> {
+        let (device_loss_tx, device_loss_rx) = mpsc::unbounded_channel();
+        self.device_loss_tx = Some(device_loss_tx);
+        device_loss_rx
+    }
+
+    /// Resolve both devices. A call can go on with only one of them, e.g.
+    /// listen-only without a microphone, so only having neither is an error.
     pub async fn initialize_audio_devices(&mut self) -> Result<()> {
-        // Get default input device (microphone)
-        self.input_device = self.host.default_input_device();
-        if self.input_device.is_none() {
-            return Err(anyhow!("No input device available"));
+        // Preferred devices, or the defaults if those aren't plugged in
+        self.input_device = self.resolve_device(AudioDirection::Input);
+        self.output_device = self.resolve_device(AudioDirection::Output);
+
+        match (&self.input_device, &self.output_device) {
+            (None, None) => return Err(anyhow!("No audio devices available")),
+            (None, Some(_)) => log::warn!("No input device available; calls are listen-only"),
+            (Some(_), None) => log::warn!("No output device available; calls cannot be heard"),
+            (Some(_), Some(_)) => log::info!("Audio devices initialized successfully"),
         }
+        Ok(())
+    }
 
-        // Get default output device (speakers/headphones)
-        self.output_device = self.host.default_output_device();
-        if self.output_device.is_none() {
-            return Err(anyhow!("No output device available"));
+    fn preferred_device(&self, direction: AudioDirection) -> Option<&str> {
+        match direction {
+            AudioDirection::Input => self.preferred_input.as_deref(),
+            AudioDirection::Output => self.preferred_output.as_deref(),
         }
+    }
+
+    fn active_device(&self, direction: AudioDirection) -> Option<&Device> {
+        match direction {
+            AudioDirection::Input => self.input_device.as_ref(),
+            AudioDirection::Output => self.output_device.as_ref(),
+        }
+    }
+
+    fn find_device(&self, direction: AudioDirection, name: Option<&str>) -> Option<Device> {
+        find_device(&self.host, direction, name)
+    }
 
-        log::info!("Audio devices initialized successfully");
+    fn resolve_device(&self, direction: AudioDirection) -> Option<Device> {
+        if let Some(name) = self.preferred_device(direction) {
+            match self.find_device(direction, Some(name)) {
+                Some(device) => return Some(device),
+                None => log::warn!("{} device {} is not available; using the default", direction.as_str(), name),
+            }
+        }
+        self.find_device(direction, None)
+    }
+
+    /// Restore a saved choice without touching open streams
+    pub fn set_preferred_device(&mut self, direction: AudioDirection, name: Option<String>) {
+        match direction {
+            AudioDirection::Input => self.preferred_input = name,
+            AudioDirection::Output => self.preferred_output = name,
+        }
+    }
+
+    /// Use the device called `name`, or the system default for None, from now
+    /// on. A running call switches over without being interrupted.
+    pub async fn select_device(&mut self, direction: AudioDirection, name: Option<String>) -> Result<()> {
+        let device = self.find_device(direction, name.as_deref())
+            .ok_or_else(|| anyhow!("{} device {} not found", direction.as_str(), name.as_deref().unwrap_or("default")))?;
+        self.set_preferred_device(direction, name);
+        self.switch_device(direction, Some(device)).await
+    }
+
+    async fn switch_device(&mut self, direction: AudioDirection, device: Option<Device>) -> Result<()> {
+        match direction {
+            AudioDirection::Input => {
+                self.input_device = device;
+                if self.input_stream.lock().await.is_some() {
+                    self.rebuild_stream(direction).await?;
+                }
+            }
+            AudioDirection::Output => {
+                self.output_device = device;
+                if self.output_stream.lock().await.is_some() {
+                    self.rebuild_stream(direction).await?;
+                }
+            }
+        }
         Ok(())
     }
 
+    async fn rebuild_stream(&self, direction: AudioDirection) -> Result<()> {
+        let stream = match direction {
+            AudioDirection::Input => &self.input_stream,
+            AudioDirection::Output => &self.output_stream,
+        };
+
+        if self.active_device(direction).is_none() {
+            // Nothing left to switch to; the call goes on without this side
+            stream.lock().await.take();
+            return Ok(());
+        }
+
+        let result = match direction {
+            AudioDirection::Input => self.open_input_stream().await,
+            AudioDirection::Output => self.open_output_stream().await,
+        };
+        if result.is_err() {
+            stream.lock().await.take();
+        }
+        result
+    }
+
+    /// Move a stream whose device disappeared over to the system default.
+    /// Returns None if the loss was already handled.
+    pub async fn handle_device_lost(&mut self, lost: &DeviceLost) -> Result<Option<AudioDeviceChangedEvent>> {
+        let active_name = self.active_device(lost.direction).and_then(|device| device.name().ok());
+        if active_name.as_deref() != Some(lost.device_name.as_str()) {
+            return Ok(None);
+        }
+
+        let fallback = self.find_device(lost.direction, None)
+            .filter(|device| device.name().map_or(true, |name| name != lost.device_name));
+        let fallback_name = fallback.as_ref().and_then(|device| device.name().ok());
+        log::warn!(
+            "{} device {} was lost; switching to {}",
+            lost.direction.as_str(), lost.device_name, fallback_name.as_deref().unwrap_or("no device"),
+        );
+
+        self.switch_device(lost.direction, fallback).await?;
+        Ok(Some(AudioDeviceChangedEvent { direction: lost.direction, device: fallback_name }))
+    }
+
+    /// Chosen and in-use device names, for `list_devices` to enumerate
+    /// against without holding the manager
+    pub fn device_selection(&self) -> DeviceSelection {
+        let active_name = |direction| self.active_device(direction).and_then(|device| device.name().ok());
+        DeviceSelection {
+            preferred_input: self.preferred_input.clone(),
+            preferred_output: self.preferred_output.clone(),
+            active_input: active_name(AudioDirection::Input),
+            active_output: active_name(AudioDirection::Output),
+        }
+    }
+
     pub async fn initiate_call(&mut self, contact: &Contact) -> Result<String> {
-        if !matches!(*self.call_state.lock().await, CallState::Idle) {
+        // Ended stays visible to the UI until the next call, like in `receive_call`
+        if !matches!(*self.call_state.lock().await, CallState::Idle | CallState::Ended) {
             return Err(anyhow!("Already in a call"));
         }
 
@@ -83,7 +374,7 @@ impl VoiceCallManager {
             contact: contact.clone(),
             is_incoming: false,
             start_time: chrono::Utc::now().timestamp(),
-            encryption_key: None,
+            encryption_key: CallKey::generate(),
         };
 
         {
@@ -105,7 +396,61 @@ impl VoiceCallManager {
         Ok(call_id)
     }
 
-    pub async fn accept_call(&mut self, call_id: &str) -> Result<()> {
+    /// Register a call offered by a contact; the UI then accepts or rejects it
+    pub async fn receive_call(&mut self, call_id: &str, contact: &Contact, encryption_key: CallKey) -> Result<()> {
+        if !matches!(*self.call_state.lock().await, CallState::Idle | CallState::Ended) {
+            return Err(anyhow!("Already in a call"));
+        }
+
+        let call = VoiceCall {
+            call_id: call_id.to_string(),
+            contact: contact.clone(),
+            is_incoming: true,
+            start_time: chrono::Utc::now().timestamp(),
+            encryption_key,
+        };
+
+        {
+            let mut current_call = self.current_call.lock().await;
+            *current_call = Some(call);
+        }
+
+        {
+            let mut state = self.call_state.lock().await;
+            *state = CallState::Ringing;
+        }
+
+        log::info!("Incoming voice call: {}", call_id);
+        Ok(())
+    }
+
+    pub async fn current_call_id(&self) -> Option<String> {
+        self.current_call.lock().await.as_ref().map(|call| call.call_id.clone())
+    }
+
+    /// Contact on the other end of `call_id`, who its signaling goes to
+    pub async fn call_contact(&self, call_id: &str) -> Result<Contact> {
+        match self.current_call.lock().await.as_ref() {
+            Some(call) if call.call_id == call_id => Ok(call.contact.clone()),
+            _ => Err(anyhow!("No matching call"))
+        }
+    }
+
+    /// Media key of `call_id`, for sealing into the call offer
+    pub async fn call_key(&self, call_id: &str) -> Result<CallKey> {
+        match self.current_call.lock().await.as_ref() {
+            Some(call) if call.call_id == call_id => Ok(call.encryption_key.clone()),
+            _ => Err(anyhow!("No matching call"))
+        }
+    }
+
+    /// Start media for `call_id`, under the offered key mixed with the
+    /// callee's `key_share`, whichever side we are on
+    pub async fn accept_call(&mut self, call_id: &str, key_share: &str) -> Result<()> {
+        if !matches!(*self.call_state.lock().await, CallState::Calling | CallState::Ringing) {
+            return Err(anyhow!("Call {} is not waiting to be accepted", call_id));
+        }
+
         let current_call = {
             let call = self.current_call.lock().await;
             call.clone()
@@ -113,6 +458,11 @@ impl VoiceCallManager {
 
         match current_call {
             Some(call) if call.call_id == call_id => {
+                let encryption_key = call.encryption_key.with_key_share(key_share)?;
+                if let Some(call) = self.current_call.lock().await.as_mut() {
+                    call.encryption_key = encryption_key;
+                }
+
                 {
                     let mut state = self.call_state.lock().await;
                     *state = CallState::Connected;
@@ -159,6 +509,7 @@ impl VoiceCallManager {
             *state = CallState::Ended;
         }
 
+        // Dropping the call wipes its media key
         {
             let mut current_call = self.current_call.lock().await;
             *current_call = None;
@@ -179,6 +530,9 @@ impl VoiceCallManager {
             call.clone()
         };
 
+        let audio = self.playout.lock().await.as_ref()
+            .map(|playout| playout.jitter_buffer.stats());
+
         let status = match current_call {
             Some(call) => CallStatus {
                 state: format!("{:?}", state),
@@ -187,6 +541,7 @@ impl VoiceCallManager {
                 start_time: Some(call.start_time),
                 duration: Some(chrono::Utc::now().timestamp() - call.start_time),
                 is_incoming: call.is_incoming,
+                audio,
             },
             None => CallStatus {
                 state: format!("{:?}", state),
@@ -195,6 +550,7 @@ impl VoiceCallManager {
                 start_time: None,
                 duration: None,
                 is_incoming: false,
+                audio: None,
             }
         };
 
@@ -202,8 +558,14 @@ impl VoiceCallManager {
     }
 
     async fn start_audio_streaming(&mut self) -> Result<()> {
-        self.start_recording().await?;
-        self.start_playback().await?;
+        // Pick up a preferred device plugged in since the last call
+        self.initialize_audio_devices().await?;
+        if self.input_device.is_some() {
+            self.start_recording().await?;
+        }
+        if self.output_device.is_some() {
+            self.start_playback().await?;
+        }
         log::info!("Audio streaming started");
         Ok(())
     }
@@ -212,6 +574,14 @@ impl VoiceCallManager {
         self.is_recording.store(false, Ordering::Relaxed);
         self.is_playing.store(false, Ordering::Relaxed);
 
+        // Dropping the sender ends the encoder task
+        self.audio_sender.lock().await.take();
+        if let Some(playout_task) = self.playout_task.take() {
+            playout_task.abort();
+        }
+        self.playout.lock().await.take();
+        *self.replay_window.lock().await = ReplayWindow::default();
+
         {
             let mut input_stream = self.input_stream.lock().await;
             if let Some(stream) = input_stream.take() {
@@ -231,17 +601,41 @@ impl VoiceCallManager {
     }
 
     async fn start_recording(&mut self) -> Result<()> {
+        let call = self.current_call.lock().await.clone()
+            .ok_or_else(|| anyhow!("No active call"))?;
+        let packet_tx = self.packet_tx.clone()
+            .ok_or_else(|| anyhow!("No voice packet subscriber"))?;
+        let encoder = VoiceEncoder::new(&self.codec_config)?;
+        let (captured_tx, captured_rx) = mpsc::unbounded_channel();
+        *self.audio_sender.lock().await = Some(captured_tx);
+        tokio::spawn(run_encoder(captured_rx, encoder, call, packet_tx));
+
+        self.open_input_stream().await?;
+        self.is_recording.store(true, Ordering::Relaxed);
+
+        log::info!("Recording started");
+        Ok(())
+    }
+
+    /// Capture from the current input device into the call's encoder task,
+    /// replacing any stream already open
+    async fn open_input_stream(&self) -> Result<()> {
         let input_device = self.input_device.as_ref()
             .ok_or_else(|| anyhow!("No input device available"))?;
 
         let config = input_device.default_input_config()?;
-        let sample_rate = config.sample_rate().0;
-        let channels = config.channels();
+        let format = AudioFormat { sample_rate: config.sample_rate().0, channels: config.channels() };
+
+        log::info!("Recording config: {} Hz, {} channels", format.sample_rate, format.channels);
 
-        log::info!("Recording config: {} Hz, {} channels", sample_rate, channels);
+        // The callback only copies into the ring; the encoder task drains it
+        let (mut producer, consumer) = HeapRb::<f32>::new(samples_for(format, CAPTURE_RING_MS)).split();
+        if let Some(audio_sender) = self.audio_sender.lock().await.as_ref() {
+            let _ = audio_sender.send(CapturedAudio { format, samples: consumer });
+        }
 
         let is_recording = Arc::clone(&self.is_recording);
-        let audio_sender = Arc::clone(&self.audio_sender);
+        let on_error = stream_error_handler(AudioDirection::Input, input_device, self.device_loss_tx.clone());
 
         let stream = match config.sample_format() {
             cpal::SampleFormat::F32 => {
@@ -249,13 +643,10 @@ impl VoiceCallManager {
                     &config.into(),
                     move |data: &[f32], _: &cpal::InputCallbackInfo| {
                         if is_recording.load(Ordering::Relaxed) {
-                            let sender = audio_sender.blocking_lock();
-                            if let Some(ref sender) = *sender {
-                                let _ = sender.send(data.to_vec());
-                            }
+                            producer.push_slice(data);
                         }
                     },
-                    |err| log::error!("Audio input error: {}", err),
+                    on_error,
                     None,
                 )?
             }
@@ -264,17 +655,11 @@ impl VoiceCallManager {
                     &config.into(),
                     move |data: &[i16], _: &cpal::InputCallbackInfo| {
                         if is_recording.load(Ordering::Relaxed) {
-                            let float_data: Vec<f32> = data.iter()
-                                .map(|&sample| sample as f32 / i16::MAX as f32)
-                                .collect();
-                            
-                            let sender = audio_sender.blocking_lock();
-                            if let Some(ref sender) = *sender {
-                                let _ = sender.send(float_data);
-                            }
+                            producer.push_iter(&mut data.iter()
+                                .map(|&sample| sample as f32 / i16::MAX as f32));
                         }
                     },
-                    |err| log::error!("Audio input error: {}", err),
+                    on_error,
                     None,
                 )?
             }
@@ -283,46 +668,56 @@ impl VoiceCallManager {
                     &config.into(),
                     move |data: &[u16], _: &cpal::InputCallbackInfo| {
                         if is_recording.load(Ordering::Relaxed) {
-                            let float_data: Vec<f32> = data.iter()
-                                .map(|&sample| (sample as f32 - u16::MAX as f32 / 2.0) / (u16::MAX as f32 / 2.0))
-                                .collect();
-                            
-                            let sender = audio_sender.blocking_lock();
-                            if let Some(ref sender) = *sender {
-                                let _ = sender.send(float_data);
-                            }
+                            producer.push_iter(&mut data.iter()
+                                .map(|&sample| (sample as f32 - u16::MAX as f32 / 2.0) / (u16::MAX as f32 / 2.0)));
                         }
                     },
-                    |err| log::error!("Audio input error: {}", err),
+                    on_error,
                     None,
                 )?
             }
         };
 
         stream.play()?;
-        self.is_recording.store(true, Ordering::Relaxed);
 
         {
             let mut input_stream = self.input_stream.lock().await;
             *input_stream = Some(stream);
         }
 
-        log::info!("Recording started");
         Ok(())
     }
 
     async fn start_playback(&mut self) -> Result<()> {
+        *self.playout.lock().await = Some(Playout::new()?);
+        if let Some(playout_task) = self.playout_task.replace(tokio::spawn(run_playout(Arc::clone(&self.playout)))) {
+            playout_task.abort();
+        }
+
+        self.open_output_stream().await?;
+        self.is_playing.store(true, Ordering::Relaxed);
+
+        log::info!("Playback started");
+        Ok(())
+    }
+
+    /// Play the call's audio on the current output device, replacing any
+    /// stream already open
+    async fn open_output_stream(&self) -> Result<()> {
         let output_device = self.output_device.as_ref()
             .ok_or_else(|| anyhow!("No output device available"))?;
 
         let config = output_device.default_output_config()?;
-        let sample_rate = config.sample_rate().0;
-        let channels = config.channels();
+        let format = AudioFormat { sample_rate: config.sample_rate().0, channels: config.channels() };
 
-        log::info!("Playback config: {} Hz, {} channels", sample_rate, channels);
+        log::info!("Playback config: {} Hz, {} channels", format.sample_rate, format.channels);
+        let (producer, mut consumer) = HeapRb::<f32>::new(samples_for(format, PLAYOUT_RING_MS)).split();
+        if let Some(playout) = self.playout.lock().await.as_mut() {
+            playout.set_output(format, producer);
+        }
 
         let is_playing = Arc::clone(&self.is_playing);
-        let playback_buffer = Arc::clone(&self.playback_buffer);
+        let on_error = stream_error_handler(AudioDirection::Output, output_device, self.device_loss_tx.clone());
 
         let stream = match config.sample_format() {
             cpal::SampleFormat::F32 => {
@@ -330,9 +725,8 @@ impl VoiceCallManager {
                     &config.into(),
                     move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
                         if is_playing.load(Ordering::Relaxed) {
-                            let mut buffer = playback_buffer.blocking_lock();
                             for sample in data.iter_mut() {
-                                *sample = buffer.pop_front().unwrap_or(0.0);
+                                *sample = next_playout_sample(&mut consumer);
                             }
                         } else {
                             for sample in data.iter_mut() {
@@ -340,7 +734,7 @@ impl VoiceCallManager {
                             }
                         }
                     },
-                    |err| log::error!("Audio output error: {}", err),
+                    on_error,
                     None,
                 )?
             }
@@ -349,9 +743,8 @@ impl VoiceCallManager {
                     &config.into(),
                     move |data: &mut [i16], _: &cpal::OutputCallbackInfo| {
                         if is_playing.load(Ordering::Relaxed) {
-                            let mut buffer = playback_buffer.blocking_lock();
                             for sample in data.iter_mut() {
-                                let float_sample = buffer.pop_front().unwrap_or(0.0);
+                                let float_sample = next_playout_sample(&mut consumer);
                                 *sample = (float_sample * i16::MAX as f32) as i16;
                             }
                         } else {
@@ -360,7 +753,7 @@ impl VoiceCallManager {
                             }
                         }
                     },
-                    |err| log::error!("Audio output error: {}", err),
+                    on_error,
                     None,
                 )?
             }
@@ -369,9 +762,8 @@ impl VoiceCallManager {
                     &config.into(),
                     move |data: &mut [u16], _: &cpal::OutputCallbackInfo| {
                         if is_playing.load(Ordering::Relaxed) {
-                            let mut buffer = playback_buffer.blocking_lock();
                             for sample in data.iter_mut() {
-                                let float_sample = buffer.pop_front().unwrap_or(0.0);
+                                let float_sample = next_playout_sample(&mut consumer);
                                 *sample = ((float_sample + 1.0) * u16::MAX as f32 / 2.0) as u16;
                             }
                         } else {
@@ -380,33 +772,45 @@ impl VoiceCallManager {
                             }
                         }
                     },
-                    |err| log::error!("Audio output error: {}", err),
+                    on_error,
                     None,
                 )?
             }
         };
 
         stream.play()?;
-        self.is_playing.store(true, Ordering::Relaxed);
 
         {
             let mut output_stream = self.output_stream.lock().await;
             *output_stream = Some(stream);
         }
 
-        log::info!("Playback started");
         Ok(())
     }
 
-    pub async fn add_audio_data(&self, audio_data: Vec<f32>) -> Result<()> {
-        let mut buffer = self.playback_buffer.lock().await;
-        buffer.extend(audio_data);
-        
-        // Limit buffer size to prevent memory issues
-        while buffer.len() > 48000 { // ~1 second at 48kHz
-            buffer.pop_front();
+    /// Authenticate and decrypt a received frame of the current call and
+    /// queue it for playout
+    pub async fn receive_voice_data(&self, message: &VoiceDataMessage) -> Result<()> {
+        let call = match self.current_call.lock().await.as_ref() {
+            Some(call) if call.call_id == message.call_id => call.clone(),
+            _ => return Err(anyhow!("Voice data for unknown call {}", message.call_id)),
+        };
+
+        let sealed = general_purpose::STANDARD.decode(&message.encrypted_audio_data)?;
+        let packet = {
+            let mut replay_window = self.replay_window.lock().await;
+            replay_window.check(message.sequence_number)?;
+            // Frames from the other side: the caller's if we were called
+            let packet = call.encryption_key.open_frame(&call.call_id, call.is_incoming, message.sequence_number, &sealed)?;
+            replay_window.accept(message.sequence_number);
+            packet
+        };
+
+        let mut playout = self.playout.lock().await;
+        let playout = playout.as_mut().ok_or_else(|| anyhow!("Call audio is not running"))?;
+        if !playout.jitter_buffer.push(message.sequence_number, packet, chrono::Utc::now().timestamp_millis()) {
+            log::debug!("Discarded late or duplicate voice frame {}", message.sequence_number);
         }
-        
         Ok(())
     }
 
@@ -422,3 +826,224 @@ impl VoiceCallManager {
         Ok((input_devices, output_devices))
     }
 }
+
+/// Frame captured audio into encrypted Opus packets until the call's capture
+/// channel closes, draining the current input stream's ring on every tick
+async fn run_encoder(
+    mut captured: mpsc::UnboundedReceiver<CapturedAudio>,
+    mut encoder: VoiceEncoder,
+    call: VoiceCall,
+    packets: mpsc::UnboundedSender<VoicePacket>,
+) {
+    let from_caller = !call.is_incoming;
+    let recipient_contact_code = call.contact.contact_code.join(" ");
+    // Never wraps: 2^31 frames of 20 ms is over a year
+    let mut sequence_number: i32 = 0;
+    let mut input: Option<(HeapConsumer<f32>, FormatAdapter)> = None;
+    let mut drained = Vec::new();
+    let mut ticks = tokio::time::interval(Duration::from_millis(CAPTURE_TICK_MS));
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    loop {
+        tokio::select! {
+            next = captured.recv() => match next {
+                // A switched input device may record in a different format
+                Some(CapturedAudio { format, samples }) => {
+                    input = Some((samples, FormatAdapter::new(format, AudioFormat::CALL)));
+                    continue;
+                }
+                None => return,
+            },
+            _ = ticks.tick() => {}
+        }
+
+        let (samples, adapter) = match input.as_mut() {
+            Some(input) => input,
+            None => continue,
+        };
+        drained.clear();
+        drained.extend(samples.pop_iter());
+        if drained.is_empty() {
+            continue;
+        }
+        let samples = adapter.process(&drained);
+
+        let frames = match encoder.encode(&samples) {
+            Ok(frames) => frames,
+            Err(e) => {
+                log::warn!("Voice encoding failed: {}", e);
+                continue;
+            }
+        };
+
+        for frame in frames {
+            let data = match call.encryption_key.seal_frame(&call.call_id, from_caller, sequence_number, &frame) {
+                Ok(data) => data,
+                Err(e) => {
+                    log::warn!("Voice frame encryption failed: {}", e);
+                    return;
+                }
+            };
+            let packet = VoicePacket {
+                recipient_contact_code: recipient_contact_code.clone(),
+                call_id: call.call_id.clone(),
+                sequence_number,
+                data,
+            };
+            if packets.send(packet).is_err() {
+                return;
+            }
+            sequence_number += 1;
+        }
+    }
+}
+
+/// Device names `list_devices` marks as selected and active
+#[derive(Debug, Clone, Default)]
+pub struct DeviceSelection {
+    pub preferred_input: Option<String>,
+    pub preferred_output: Option<String>,
+    pub active_input: Option<String>,
+    pub active_output: Option<String>,
+}
+
+fn devices(host: &Host, direction: AudioDirection) -> Result<Vec<Device>> {
+    Ok(match direction {
+        AudioDirection::Input => host.input_devices()?.collect(),
+        AudioDirection::Output => host.output_devices()?.collect(),
+    })
+}
+
+/// The device called `name`, or the system default for None
+fn find_device(host: &Host, direction: AudioDirection, name: Option<&str>) -> Option<Device> {
+    match (name, direction) {
+        (Some(name), _) => devices(host, direction).ok()?
+            .into_iter()
+            .find(|device| device.name().map_or(false, |device_name| device_name == name)),
+        (None, AudioDirection::Input) => host.default_input_device(),
+        (None, AudioDirection::Output) => host.default_output_device(),
+    }
+}
+
+/// Every device on the system, marked against `selection`. Enumerating can
+/// be slow, so this runs without the voice manager.
+pub fn list_devices(selection: &DeviceSelection) -> Result<AudioDevices> {
+    let host = cpal::default_host();
+    Ok(AudioDevices {
+        inputs: device_infos(&host, AudioDirection::Input, selection.preferred_input.as_deref(), selection.active_input.as_deref())?,
+        outputs: device_infos(&host, AudioDirection::Output, selection.preferred_output.as_deref(), selection.active_output.as_deref())?,
+    })
+}
+
+fn device_infos(host: &Host, direction: AudioDirection, preferred_name: Option<&str>, active_name: Option<&str>) -> Result<Vec<AudioDeviceInfo>> {
+    let default_name = find_device(host, direction, None).and_then(|device| device.name().ok());
+    let selected_name = preferred_name.map(str::to_string).or_else(|| default_name.clone());
+
+    Ok(devices(host, direction)?
+        .iter()
+        .filter_map(|device| device.name().ok())
+        .map(|name| AudioDeviceInfo {
+            is_default: default_name.as_deref() == Some(name.as_str()),
+            is_selected: selected_name.as_deref() == Some(name.as_str()),
+            is_active: active_name == Some(name.as_str()),
+            name,
+        })
+        .collect())
+}
+
+/// Log stream errors and report the stream's device if it went away
+fn stream_error_handler(
+    direction: AudioDirection,
+    device: &Device,
+    device_loss_tx: Option<mpsc::UnboundedSender<DeviceLost>>,
+) -> impl FnMut(cpal::StreamError) + Send + 'static {
+    let device_name = device.name().unwrap_or_default();
+    move |err| {
+        log::error!("Audio {} error: {}", direction.as_str(), err);
+        if let (cpal::StreamError::DeviceNotAvailable, Some(device_loss_tx)) = (&err, &device_loss_tx) {
+            let _ = device_loss_tx.send(DeviceLost { direction, device_name: device_name.clone() });
+        }
+    }
+}
+
+/// Next decoded sample, or silence if the decoder has fallen behind
+fn next_playout_sample(samples: &mut HeapConsumer<f32>) -> f32 {
+    samples.pop().unwrap_or(0.0)
+}
+
+/// Keep the current call's playout ring topped up until the task is aborted
+async fn run_playout(playout: Arc<Mutex<Option<Playout>>>) {
+    let mut ticks = tokio::time::interval(Duration::from_millis(PLAYOUT_TICK_MS));
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    loop {
+        ticks.tick().await;
+        if let Some(playout) = playout.lock().await.as_mut() {
+            playout.fill();
+        }
+    }
+}
+
+/// Relay encoded frames to the peer as `VOICE_DATA` messages; runs for the app's lifetime
+pub async fn send_voice_packets(sender: WebSocketSender, mut packets: mpsc::UnboundedReceiver<VoicePacket>) {
+    while let Some(packet) = packets.recv().await {
+        let data = general_purpose::STANDARD.encode(&packet.data);
+        if let Err(e) = sender.send_voice_data(&packet.recipient_contact_code, &packet.call_id, &data, packet.sequence_number).await {
+            log::debug!("Dropped voice frame {}: {}", packet.sequence_number, e);
+        }
+    }
+}
+
+/// Applies saved device choices at startup and moves calls over to the
+/// default device when the one in use is unplugged
+pub struct DeviceWatcher {
+    app: AppHandle,
+    database: Arc<Mutex<Database>>,
+    voice: Arc<Mutex<VoiceCallManager>>,
+    device_loss: mpsc::UnboundedReceiver<DeviceLost>,
+}
+
+impl DeviceWatcher {
+    pub fn new(app: AppHandle, state: &AppState, device_loss: mpsc::UnboundedReceiver<DeviceLost>) -> Self {
+        Self {
+            app,
+            database: Arc::clone(&state.database),
+            voice: Arc::clone(&state.voice),
+            device_loss,
+        }
+    }
+
+    pub async fn run(mut self) {
+        if let Err(e) = self.restore_preferences().await {
+            log::warn!("Failed to load audio device preferences: {}", e);
+        }
+
+        while let Some(lost) = self.device_loss.recv().await {
+            let mut voice = self.voice.lock().await;
+            match voice.handle_device_lost(&lost).await {
+                Ok(Some(event)) => {
+                    if let Err(e) = self.app.emit_all(EVENT_AUDIO_DEVICE_CHANGED, event) {
+                        log::warn!("Failed to emit {}: {}", EVENT_AUDIO_DEVICE_CHANGED, e);
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => log::error!("Failed to recover from losing {}: {}", lost.device_name, e),
+            }
+        }
+    }
+
+    async fn restore_preferences(&self) -> Result<()> {
+        let (input, output) = {
+            let db = self.database.lock().await;
+            (
+                db.get_setting(SETTING_INPUT_DEVICE).await?,
+                db.get_setting(SETTING_OUTPUT_DEVICE).await?,
+            )
+        };
+
+        let mut voice = self.voice.lock().await;
+        voice.set_preferred_device(AudioDirection::Input, input.filter(|name| !name.is_empty()));
+        voice.set_preferred_device(AudioDirection::Output, output.filter(|name| !name.is_empty()));
+        Ok(())
+    }
+}
@@ -0,0 +1,205 @@
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
+use std::os::raw::c_int;
+
+/// Canonical call format: 48 kHz mono, 20 ms Opus frames
+pub const SAMPLE_RATE: u32 = 48_000;
+pub const CHANNELS: u16 = 1;
+pub const FRAME_DURATION_MS: u32 = 20;
+pub const FRAME_SAMPLES: usize = (SAMPLE_RATE / 1000 * FRAME_DURATION_MS) as usize;
+
+/// Largest packet Opus produces for one frame, well under the server's 4 KB limit
+pub const MAX_PACKET_BYTES: usize = 1275;
+
+/// Longest frame a packet may decode to (120 ms)
+const MAX_DECODED_SAMPLES: usize = FRAME_SAMPLES * 6;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CodecConfig {
+    /// Target bitrate in bits per second
+    pub bitrate: i32,
+    /// Encoder effort, 0 (fastest) to 10 (best quality)
+    pub complexity: i32,
+    /// In-band forward error correction, so a lost frame can be rebuilt from the next
+    pub fec: bool,
+    /// Expected packet loss; FEC spends more bits as this grows
+    pub expected_loss_percent: i32,
+}
+
+impl Default for CodecConfig {
+    fn default() -> Self {
+        Self {
+            bitrate: 24_000,
+            complexity: 5,
+            fec: true,
+            expected_loss_percent: 10,
+        }
+    }
+}
+
+impl CodecConfig {
+    pub fn validate(&self) -> Result<()> {
+        if !(6_000..=510_000).contains(&self.bitrate) {
+            return Err(anyhow!("Bitrate must be between 6000 and 510000 bps"));
+        }
+        if !(0..=10).contains(&self.complexity) {
+            return Err(anyhow!("Complexity must be between 0 and 10"));
+        }
+        if !(0..=100).contains(&self.expected_loss_percent) {
+            return Err(anyhow!("Expected loss must be a percentage"));
+        }
+        Ok(())
+    }
+}
+
+/// Opus encoder that cuts arbitrary capture chunks into 20 ms packets.
+///
+/// Built on the raw libopus API because the `opus` crate does not expose the
+/// complexity setting.
+pub struct VoiceEncoder {
+    encoder: *mut audiopus_sys::OpusEncoder,
+    pending: Vec<f32>,
+}
+
+// The encoder state is only touched through `&mut self`
+unsafe impl Send for VoiceEncoder {}
+
+impl VoiceEncoder {
+    pub fn new(config: &CodecConfig) -> Result<Self> {
+        config.validate()?;
+
+        let mut error: c_int = 0;
+        let encoder = unsafe {
+            audiopus_sys::opus_encoder_create(
+                SAMPLE_RATE as i32,
+                CHANNELS as c_int,
+                audiopus_sys::OPUS_APPLICATION_VOIP as c_int,
+                &mut error,
+            )
+        };
+        if encoder.is_null() || error != audiopus_sys::OPUS_OK as c_int {
+            return Err(anyhow!("Failed to create Opus encoder (error {})", error));
+        }
+
+        let mut voice_encoder = Self { encoder, pending: Vec::with_capacity(FRAME_SAMPLES * 2) };
+        voice_encoder.configure(config)?;
+        Ok(voice_encoder)
+    }
+
+    pub fn configure(&mut self, config: &CodecConfig) -> Result<()> {
+        config.validate()?;
+        self.ctl(audiopus_sys::OPUS_SET_BITRATE_REQUEST as c_int, config.bitrate)?;
+        self.ctl(audiopus_sys::OPUS_SET_COMPLEXITY_REQUEST as c_int, config.complexity)?;
+        self.ctl(audiopus_sys::OPUS_SET_INBAND_FEC_REQUEST as c_int, config.fec as i32)?;
+        self.ctl(audiopus_sys::OPUS_SET_PACKET_LOSS_PERC_REQUEST as c_int, config.expected_loss_percent)?;
+        Ok(())
+    }
+
+    fn ctl(&mut self, request: c_int, value: i32) -> Result<()> {
+        let result = unsafe { audiopus_sys::opus_encoder_ctl(self.encoder, request, value as c_int) };
+        if result != audiopus_sys::OPUS_OK as c_int {
+            return Err(anyhow!("Opus encoder rejected setting {} = {} (error {})", request, value, result));
+        }
+        Ok(())
+    }
+
+    /// Buffer 48 kHz mono samples and encode every complete frame.
+    /// Leftover samples wait for the next call.
+    pub fn encode(&mut self, samples: &[f32]) -> Result<Vec<Vec<u8>>> {
+        self.pending.extend_from_slice(samples);
+
+        let mut packets = Vec::new();
+        let mut offset = 0;
+        while self.pending.len() - offset >= FRAME_SAMPLES {
+            let frame = &self.pending[offset..offset + FRAME_SAMPLES];
+            let mut packet = vec![0u8; MAX_PACKET_BYTES];
+            let written = unsafe {
+                audiopus_sys::opus_encode_float(
+                    self.encoder,
+                    frame.as_ptr(),
+                    FRAME_SAMPLES as c_int,
+                    packet.as_mut_ptr(),
+                    MAX_PACKET_BYTES as i32,
+                )
+            };
+            if written < 0 {
+                return Err(anyhow!("Opus encoding failed (error {})", written));
+            }
+            packet.truncate(written as usize);
+            packets.push(packet);
+            offset += FRAME_SAMPLES;
+        }
+
+        self.pending.drain(..offset);
+        Ok(packets)
+    }
+}
+
+impl Drop for VoiceEncoder {
+    fn drop(&mut self) {
+        unsafe { audiopus_sys::opus_encoder_destroy(self.encoder) };
+    }
+}
+
+pub struct VoiceDecoder {
+    decoder: opus::Decoder,
+}
+
+impl VoiceDecoder {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            decoder: opus::Decoder::new(SAMPLE_RATE, opus::Channels::Mono)?,
+        })
+    }
+
+    /// Decode one packet into 48 kHz mono samples
+    pub fn decode(&mut self, packet: &[u8]) -> Result<Vec<f32>> {
+        let mut samples = vec![0.0f32; MAX_DECODED_SAMPLES];
+        let decoded = self.decoder.decode_float(packet, &mut samples, false)?;
+        samples.truncate(decoded);
+        Ok(samples)
+    }
+
+    /// Rebuild a lost frame from the FEC data carried by the packet after it
+    pub fn recover(&mut self, next_packet: &[u8]) -> Result<Vec<f32>> {
+        let mut samples = vec![0.0f32; FRAME_SAMPLES];
+        let decoded = self.decoder.decode_float(next_packet, &mut samples, true)?;
+        samples.truncate(decoded);
+        Ok(samples)
+    }
+
+    /// Packet loss concealment: extrapolate a frame from the audio before it
+    pub fn conceal(&mut self) -> Result<Vec<f32>> {
+        let mut samples = vec![0.0f32; FRAME_SAMPLES];
+        let decoded = self.decoder.decode_float(&[], &mut samples, false)?;
+        samples.truncate(decoded);
+        Ok(samples)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frames_round_trip_through_opus() {
+        let mut encoder = VoiceEncoder::new(&CodecConfig::default()).unwrap();
+        let mut decoder = VoiceDecoder::new().unwrap();
+
+        // 50 ms of a 440 Hz tone: two full frames, 10 ms left pending
+        let tone: Vec<f32> = (0..FRAME_SAMPLES * 5 / 2)
+            .map(|i| (i as f32 * 440.0 * std::f32::consts::TAU / SAMPLE_RATE as f32).sin() * 0.5)
+            .collect();
+        let packets = encoder.encode(&tone).unwrap();
+        assert_eq!(packets.len(), 2);
+        assert!(packets.iter().all(|p| !p.is_empty() && p.len() <= MAX_PACKET_BYTES));
+
+        assert_eq!(encoder.encode(&tone[..FRAME_SAMPLES / 2]).unwrap().len(), 1);
+
+        for packet in &packets {
+            assert_eq!(decoder.decode(packet).unwrap().len(), FRAME_SAMPLES);
+        }
+
+        assert!(VoiceEncoder::new(&CodecConfig { complexity: 11, ..Default::default() }).is_err());
+    }
+}
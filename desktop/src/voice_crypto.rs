@@ -0,0 +1,195 @@
+use aes_gcm::{Aes256Gcm, Key, Nonce, aead::{Aead, NewAead, Payload}};
+use anyhow::{Result, anyhow};
+use base64::{Engine as _, engine::general_purpose};
+use hkdf::Hkdf;
+use rand::{RngCore, rngs::OsRng};
+use sha2::Sha256;
+use std::fmt;
+use zeroize::Zeroizing;
+
+pub const CALL_KEY_SIZE: usize = 32;
+pub const KEY_SHARE_SIZE: usize = 32;
+
+const MEDIA_KEY_INFO: &[u8] = b"nonmessenger-call-media";
+
+/// How far behind the newest frame a reordered frame may still arrive
+const REPLAY_WINDOW: u32 = 64;
+
+/// Per-call AES-256-GCM key for voice frames, wiped from memory when dropped.
+///
+/// Frames are never sent with their nonce: it is derived from the sender's
+/// role and the frame's sequence number, so both directions can share the key
+/// without ever reusing a nonce. Frames are only sealed with the key returned
+/// by `with_key_share`, so a replayed offer can never bring back the key, and
+/// with it the nonces, of an earlier call.
+#[derive(Clone)]
+pub struct CallKey {
+    key: Zeroizing<[u8; CALL_KEY_SIZE]>,
+}
+
+impl fmt::Debug for CallKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CallKey(..)")
+    }
+}
+
+impl CallKey {
+    pub fn generate() -> Self {
+        let mut key = Zeroizing::new([0u8; CALL_KEY_SIZE]);
+        OsRng.fill_bytes(key.as_mut());
+        Self { key }
+    }
+
+    pub fn from_base64(encoded: &str) -> Result<Self> {
+        let bytes = Zeroizing::new(general_purpose::STANDARD.decode(encoded)?);
+        if bytes.len() != CALL_KEY_SIZE {
+            return Err(anyhow!("Call key must be {} bytes", CALL_KEY_SIZE));
+        }
+
+        let mut key = Zeroizing::new([0u8; CALL_KEY_SIZE]);
+        key.copy_from_slice(&bytes);
+        Ok(Self { key })
+    }
+
+    /// Encoded for the key exchange, which seals it to the contact
+    pub fn to_base64(&self) -> Zeroizing<String> {
+        Zeroizing::new(general_purpose::STANDARD.encode(self.key.as_ref()))
+    }
+
+    /// The media key: the offered key mixed with the callee's fresh key share
+    pub fn with_key_share(&self, key_share: &str) -> Result<Self> {
+        let share = general_purpose::STANDARD.decode(key_share)?;
+        if share.len() != KEY_SHARE_SIZE {
+            return Err(anyhow!("Call key share must be {} bytes", KEY_SHARE_SIZE));
+        }
+
+        let hkdf = Hkdf::<Sha256>::new(Some(&share), self.key.as_ref());
+        let mut key = Zeroizing::new([0u8; CALL_KEY_SIZE]);
+        hkdf.expand(MEDIA_KEY_INFO, key.as_mut())
+            .map_err(|_| anyhow!("Call key derivation failed"))?;
+        Ok(Self { key })
+    }
+
+    /// Encrypt one Opus frame. The call id is authenticated so frames can't be
+    /// moved between calls.
+    pub fn seal_frame(&self, call_id: &str, from_caller: bool, sequence_number: i32, frame: &[u8]) -> Result<Vec<u8>> {
+        let cipher = Aes256Gcm::new(Key::from_slice(self.key.as_ref()));
+        let nonce = frame_nonce(from_caller, sequence_number);
+        cipher.encrypt(Nonce::from_slice(&nonce), Payload { msg: frame, aad: call_id.as_bytes() })
+            .map_err(|e| anyhow!("Voice frame encryption failed: {}", e))
+    }
+
+    pub fn open_frame(&self, call_id: &str, from_caller: bool, sequence_number: i32, sealed: &[u8]) -> Result<Vec<u8>> {
+        let cipher = Aes256Gcm::new(Key::from_slice(self.key.as_ref()));
+        let nonce = frame_nonce(from_caller, sequence_number);
+        cipher.decrypt(Nonce::from_slice(&nonce), Payload { msg: sealed, aad: call_id.as_bytes() })
+            .map_err(|_| anyhow!("Voice frame {} failed authentication", sequence_number))
+    }
+}
+
+/// Random value the callee contributes to the media key, encoded for the accept
+pub fn generate_key_share() -> String {
+    let mut share = [0u8; KEY_SHARE_SIZE];
+    OsRng.fill_bytes(&mut share);
+    general_purpose::STANDARD.encode(share)
+}
+
+/// Direction byte, then the sequence number big-endian. Sequence numbers
+/// are unique per direction for the life of a call.
+fn frame_nonce(from_caller: bool, sequence_number: i32) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[0] = if from_caller { 1 } else { 2 };
+    nonce[8..].copy_from_slice(&(sequence_number as u32).to_be_bytes());
+    nonce
+}
+
+/// Sliding window of recently accepted sequence numbers. Frames may arrive
+/// out of order, but each one is only played once.
+#[derive(Debug, Default)]
+pub struct ReplayWindow {
+    highest: Option<u32>,
+    /// Bit `n` is set when frame `highest - n` has been accepted
+    seen: u64,
+}
+
+impl ReplayWindow {
+    /// Reject frames already accepted or too old to tell
+    pub fn check(&self, sequence_number: i32) -> Result<()> {
+        let sequence = sequence_number as u32;
+        let highest = match self.highest {
+            Some(highest) if sequence <= highest => highest,
+            _ => return Ok(()),
+        };
+
+        let age = highest - sequence;
+        if age >= REPLAY_WINDOW {
+            return Err(anyhow!("Voice frame {} is outside the replay window", sequence_number));
+        }
+        if self.seen & (1 << age) != 0 {
+            return Err(anyhow!("Replayed voice frame {}", sequence_number));
+        }
+        Ok(())
+    }
+
+    /// Record a frame that passed `check` and authenticated
+    pub fn accept(&mut self, sequence_number: i32) {
+        let sequence = sequence_number as u32;
+        match self.highest {
+            Some(highest) if sequence <= highest => {
+                self.seen |= 1 << (highest - sequence);
+            }
+            Some(highest) => {
+                let shift = sequence - highest;
+                self.seen = if shift >= REPLAY_WINDOW { 1 } else { (self.seen << shift) | 1 };
+                self.highest = Some(sequence);
+            }
+            None => {
+                self.seen = 1;
+                self.highest = Some(sequence);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frames_are_bound_to_call_direction_and_sequence() {
+        let key = CallKey::generate();
+        let peer_key = CallKey::from_base64(&key.to_base64()).unwrap();
+
+        let sealed = key.seal_frame("call_1", true, 7, b"opus").unwrap();
+        assert_eq!(peer_key.open_frame("call_1", true, 7, &sealed).unwrap(), b"opus");
+        assert!(peer_key.open_frame("call_1", false, 7, &sealed).is_err());
+        assert!(peer_key.open_frame("call_1", true, 8, &sealed).is_err());
+        assert!(peer_key.open_frame("call_2", true, 7, &sealed).is_err());
+
+        let mut window = ReplayWindow::default();
+        for sequence in [5, 7, 6] {
+            window.check(sequence).unwrap();
+            window.accept(sequence);
+        }
+        assert!(window.check(6).is_err());
+        window.accept(100);
+        assert!(window.check(7).is_err());
+        assert!(window.check(99).is_ok());
+    }
+
+    #[test]
+    fn test_key_share_gives_each_call_a_fresh_key() {
+        let offered = CallKey::generate();
+        let share = generate_key_share();
+        let media = offered.with_key_share(&share).unwrap();
+        let peer_media = CallKey::from_base64(&offered.to_base64()).unwrap().with_key_share(&share).unwrap();
+
+        let sealed = media.seal_frame("call_1", true, 0, b"opus").unwrap();
+        assert_eq!(peer_media.open_frame("call_1", true, 0, &sealed).unwrap(), b"opus");
+        // A replayed offer meets a new share, so frame 0 is never sealed under the old key again
+        assert!(offered.open_frame("call_1", true, 0, &sealed).is_err());
+        let replayed = offered.with_key_share(&generate_key_share()).unwrap();
+        assert!(replayed.open_frame("call_1", true, 0, &sealed).is_err());
+        assert!(offered.with_key_share("c2hvcnQ=").is_err());
+    }
+}
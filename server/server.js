@@ -278,6 +278,7 @@ class NonMessengerServer {
     getNodes(req, res) {
         const nodes = Array.from(this.serverNodes).map(node => ({
             nodeUrl: node.nodeUrl,
+            publicKey: node.publicKey,
             lastSeen: node.lastSeen
         }));
         